@@ -0,0 +1,713 @@
+use std::collections::HashMap;
+use std::path;
+use comrak::nodes::{AstNode, NodeValue};
+use hayagriva::archive::{self, ArchivedStyle};
+use hayagriva::citationberg::taxonomy::Locator;
+use hayagriva::citationberg::{IndependentStyle, LocaleCode, Style};
+use hayagriva::{
+    BibliographyDriver, BibliographyRequest, BufWriteFormat, CitationItem, CitationRequest, ElemChild,
+    ElemChildren, Formatted, Library, LocatorPayload, SpecificLocator
+};
+use serde_json::{json, Map, Value};
+
+use crate::diagnostics::{Diagnostic, Diagnostics};
+use crate::filesystem::FileSystem;
+use crate::html_generation;
+use crate::source_map::SourceMap;
+
+// `[@key]`, `[@key, p. 12]` or `[@a; @b, cap. 2]` in the markdown. Like `@ref`,
+// the preprocessor turns it into a single inline tag.
+pub static CITE_START: &str = "[@";
+pub static CITE_END: &str = "]";
+static CITE_TAG_START: &str = "<thener-cite data-cite='";
+
+// Placed alone in a line, marks where the list of references goes.
+pub static BIBLIOGRAPHY_DIRECTIVE: &str = "@bibliography";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CitationStyle {
+    #[default]
+    Apa,
+    Ieee,
+    Chicago
+}
+
+impl CitationStyle {
+    fn archived(&self) -> ArchivedStyle {
+        match self {
+            CitationStyle::Apa => ArchivedStyle::AmericanPsychologicalAssociation,
+            CitationStyle::Ieee => ArchivedStyle::InstituteOfElectricalAndElectronicsEngineers,
+            CitationStyle::Chicago => ArchivedStyle::ChicagoAuthorDate
+        }
+    }
+}
+
+/* The `bibliography` section of `project.thn`:
+{
+    "bibliography": {
+        "files": ["./referencias.bib", "./otras.json"],
+        "style": "apa",
+        "title": "Referencias",
+        "locale": "es-ES"
+    }
+}
+
+`files` are BibTeX/BibLaTeX (`.bib`) or CSL-JSON (`.json`) files, relative to
+the project. `style` is one of "apa", "ieee" or "chicago" (author-date), "apa"
+by default. The list of references is placed where the `@bibliography`
+directive is, or at the end of the document. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct BibliographyConfig {
+    files: Vec<String>,
+    style: Option<CitationStyle>,
+    title: Option<String>,
+    locale: Option<String>
+}
+
+impl BibliographyConfig {
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+}
+
+// The entries of every bibliography file, ready to be cited.
+pub struct Bibliography {
+    library: Library,
+    style: IndependentStyle,
+    locale: LocaleCode,
+    title: String
+}
+
+// 1-based line and column of a byte offset.
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |line| line.chars().count()) + 1;
+    (line, column)
+}
+
+fn load_biblatex(text: &str, file: &path::Path) -> Result<Library, Diagnostics> {
+    hayagriva::io::from_biblatex_str(text).map_err(|errors| {
+        let mut diagnostics = Diagnostics::new();
+        for error in errors {
+            let (message, span) = match error {
+                hayagriva::io::BibLaTeXError::Parse(err) => (err.kind.to_string(), err.span),
+                hayagriva::io::BibLaTeXError::Type(err) => (err.kind.to_string(), err.span)
+            };
+            let (line, column) = line_column(text, span.start);
+            diagnostics.push(
+                Diagnostic::error(format!("error en la bibliografía: {message}"))
+                    .in_file(file)
+                    .at(line, column)
+            );
+        }
+        diagnostics
+    })
+}
+
+// "Knuth, Donald E." for the CSL-JSON `{ "family": "Knuth", "given": "Donald E." }`.
+fn csl_names(value: &Value) -> Option<Value> {
+    let names = value.as_array()?.iter()
+        .filter_map(|name| {
+            if let Some(literal) = name.get("literal").and_then(Value::as_str) {
+                return Some(literal.to_string());
+            }
+            let family = name.get("family").and_then(Value::as_str)?;
+            match name.get("given").and_then(Value::as_str) {
+                Some(given) => Some(format!("{family}, {given}")),
+                None => Some(family.to_string())
+            }
+        })
+        .map(Value::String)
+        .collect::<Vec<_>>();
+
+    (!names.is_empty()).then_some(Value::Array(names))
+}
+
+// "2020-05-01" for `{ "date-parts": [[2020, 5, 1]] }`, or just the year.
+fn csl_date(value: &Value) -> Option<Value> {
+    let parts = value.get("date-parts")?.get(0)?.as_array()?;
+    let parts = parts.iter()
+        .map(|part| part.as_i64().or_else(|| part.as_str()?.parse().ok()))
+        .collect::<Option<Vec<i64>>>()?;
+
+    match parts.as_slice() {
+        [year] => Some(json!(year)),
+        [year, month] => Some(json!(format!("{year:04}-{month:02}"))),
+        [year, month, day, ..] => Some(json!(format!("{year:04}-{month:02}-{day:02}"))),
+        [] => None
+    }
+}
+
+// CSL-JSON values may be strings or numbers.
+fn csl_text(item: &Value, field: &str) -> Option<Value> {
+    match item.get(field)? {
+        Value::String(text) => Some(Value::String(text.clone())),
+        Value::Number(number) => Some(Value::String(number.to_string())),
+        _ => None
+    }
+}
+
+// Translates a CSL-JSON item into hayagriva's format, so that it can be mixed
+// with BibTeX entries. Returns the key and the entry.
+fn csl_to_entry(item: &Value) -> Option<(String, Value)> {
+    let key = csl_text(item, "id")?.as_str()?.to_string();
+    let csl_type = item.get("type").and_then(Value::as_str).unwrap_or("");
+
+    let (entry_type, parent_type) = match csl_type {
+        "article-journal" | "article-magazine" | "article-newspaper" | "article" => ("article", Some("periodical")),
+        "paper-conference" => ("article", Some("proceedings")),
+        "chapter" | "entry-encyclopedia" | "entry-dictionary" => ("chapter", Some("book")),
+        "book" => ("book", None),
+        "thesis" => ("thesis", None),
+        "report" => ("report", None),
+        "webpage" | "post-weblog" | "post" => ("web", None),
+        _ => ("misc", None)
+    };
+
+    let mut entry = Map::new();
+    let mut parent = Map::new();
+    entry.insert("type".to_string(), json!(entry_type));
+
+    let fields = [
+        ("title", "title"),
+        ("page", "page-range"),
+        ("URL", "url"),
+        ("edition", "edition"),
+        ("genre", "genre"),
+        ("note", "note")
+    ];
+    for (csl_field, field) in fields {
+        if let Some(value) = csl_text(item, csl_field) {
+            entry.insert(field.to_string(), value);
+        }
+    }
+
+    // Volume and issue belong to the journal, not the article.
+    let numbering = if parent_type.is_some() { &mut parent } else { &mut entry };
+    for field in ["volume", "issue"] {
+        if let Some(value) = csl_text(item, field) {
+            numbering.insert(field.to_string(), value);
+        }
+    }
+
+    if let Some(authors) = item.get("author").and_then(csl_names) {
+        entry.insert("author".to_string(), authors);
+    }
+    if let Some(editors) = item.get("editor").and_then(csl_names) {
+        let holder = if parent_type == Some("book") { &mut parent } else { &mut entry };
+        holder.insert("editor".to_string(), editors);
+    }
+    if let Some(date) = item.get("issued").and_then(csl_date) {
+        entry.insert("date".to_string(), date);
+    }
+
+    if let Some(publisher) = csl_text(item, "publisher") {
+        let mut value = Map::new();
+        value.insert("name".to_string(), publisher);
+        if let Some(place) = csl_text(item, "publisher-place") {
+            value.insert("location".to_string(), place);
+        }
+        let holder = if parent_type.is_some() { &mut parent } else { &mut entry };
+        holder.insert("publisher".to_string(), Value::Object(value));
+    }
+
+    let mut serial = Map::new();
+    for (csl_field, field) in [("DOI", "doi"), ("ISBN", "isbn"), ("ISSN", "issn")] {
+        if let Some(value) = csl_text(item, csl_field) {
+            serial.insert(field.to_string(), value);
+        }
+    }
+    if !serial.is_empty() {
+        entry.insert("serial-number".to_string(), Value::Object(serial));
+    }
+
+    if let Some(parent_type) = parent_type {
+        parent.insert("type".to_string(), json!(parent_type));
+        if let Some(title) = csl_text(item, "container-title") {
+            parent.insert("title".to_string(), title);
+        }
+        entry.insert("parent".to_string(), Value::Object(parent));
+    }
+
+    Some((key, Value::Object(entry)))
+}
+
+fn load_csl_json(text: &str, file: &path::Path) -> Result<Library, Diagnostics> {
+    let items: Vec<Value> = serde_json::from_str(text).map_err(|err| {
+        Diagnostic::error(format!("CSL-JSON inválido: {err}"))
+            .in_file(file)
+            .at(err.line(), err.column())
+    })?;
+
+    let mut diagnostics = Diagnostics::new();
+    let mut entries = Map::new();
+    for (index, item) in items.iter().enumerate() {
+        match csl_to_entry(item) {
+            Some((key, entry)) => {
+                entries.insert(key, entry);
+            },
+            None => diagnostics.push(
+                Diagnostic::error(format!("la entrada número {} no tiene `id`", index + 1)).in_file(file)
+            )
+        }
+    }
+    if diagnostics.has_errors() {
+        return Err(diagnostics);
+    }
+
+    serde_json::from_value(Value::Object(entries)).map_err(|err| {
+        Diagnostic::error(format!("no se pudo leer la bibliografía: {err}")).in_file(file).into()
+    })
+}
+
+impl Bibliography {
+    // Reads every file of the bibliography. Entries with the same key in
+    // different files are reported as errors.
+    pub fn load(filesystem: &dyn FileSystem, config: &BibliographyConfig, files: &[path::PathBuf]) -> Result<Bibliography, Box<dyn std::error::Error>> {
+        let mut diagnostics = Diagnostics::new();
+        let mut library = Library::new();
+        let mut origins: HashMap<String, &path::Path> = HashMap::new();
+
+        for file in files {
+            let text = match filesystem.read_to_string(file) {
+                Ok(text) => text,
+                Err(err) => {
+                    diagnostics.push(Diagnostic::error(format!("No se pudo leer la bibliografía: {err}")).in_file(file));
+                    continue;
+                }
+            };
+
+            let loaded = match file.extension().and_then(|extension| extension.to_str()) {
+                Some("bib") => load_biblatex(&text, file),
+                Some("json") => load_csl_json(&text, file),
+                _ => Err(Diagnostic::error("formato de bibliografía no soportado, se esperaba `.bib` o `.json`").in_file(file).into())
+            };
+
+            match loaded {
+                Ok(loaded) => {
+                    for entry in loaded.iter() {
+                        if let Some(first) = origins.insert(entry.key().to_string(), file) {
+                            diagnostics.push(Diagnostic::error(format!(
+                                "la referencia `{}` ya fue definida en {}", entry.key(), first.display()
+                            )).in_file(file));
+                        }
+                        library.push(entry);
+                    }
+                },
+                Err(errors) => {
+                    for diagnostic in errors.iter() {
+                        diagnostics.push(diagnostic.clone());
+                    }
+                }
+            }
+        }
+
+        if diagnostics.has_errors() {
+            return Err(Box::new(diagnostics));
+        }
+
+        let style = match config.style.unwrap_or_default().archived().get() {
+            Style::Independent(style) => style,
+            Style::Dependent(_) => return Err("El estilo de citas no es independiente".into())
+        };
+
+        Ok(Bibliography {
+            library,
+            style,
+            locale: LocaleCode(config.locale.clone().unwrap_or("es-ES".to_string())),
+            title: config.title.clone().unwrap_or("Referencias".to_string())
+        })
+    }
+}
+
+// One of the works cited in a `[@...]`, with where in it, e.g. "p. 12".
+#[derive(Clone, Debug, PartialEq)]
+struct Cited {
+    key: String,
+    locator: Option<String>
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_alphanumeric() || "-_:./+".contains(c)
+}
+
+// Parses what is between `[@` and `]`, e.g. `knuth1984, p. 12; @lamport`.
+// None if it doesn't look like a citation.
+fn parse_citation(text: &str) -> Option<Vec<Cited>> {
+    text.split(';')
+        .map(|part| {
+            let part = part.trim().strip_prefix('@')?;
+            // Keys may contain dots and colons, but not end with them.
+            let key_len = part.find(|c| !is_key_char(c)).unwrap_or(part.len());
+            let key = part[..key_len].trim_end_matches(['.', ':']);
+            if key.is_empty() {
+                return None;
+            }
+
+            let rest = part[key.len()..].trim();
+            let locator = match rest.strip_prefix(',') {
+                Some(locator) if !locator.trim().is_empty() => Some(locator.trim().to_string()),
+                Some(_) => None,
+                None if rest.is_empty() => None,
+                None => return None
+            };
+
+            Some(Cited { key: key.to_string(), locator })
+        })
+        .collect()
+}
+
+fn cite_tag(cited: &[Cited]) -> String {
+    // Keys can't contain `|` or `;`, so they are safe as separators.
+    let encoded = cited.iter()
+        .map(|cited| match &cited.locator {
+            Some(locator) => format!("{}|{locator}", cited.key),
+            None => cited.key.clone()
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+
+    format!("{CITE_TAG_START}{}'/>", html_generation::escape_attribute(&encoded))
+}
+
+fn decode_cite_tag(literal: &str) -> Option<Vec<Cited>> {
+    let encoded = literal.strip_prefix(CITE_TAG_START)?.split('\'').next()?;
+    let encoded = encoded.replace("&#39;", "'")
+        .replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&");
+
+    Some(encoded.split(';')
+        .map(|item| match item.split_once('|') {
+            Some((key, locator)) => Cited { key: key.to_string(), locator: Some(locator.to_string()) },
+            None => Cited { key: item.to_string(), locator: None }
+        })
+        .collect())
+}
+
+// Turns every `[@key, locator]` of a line into a tag that `resolve_citations`
+// renders once the whole document is known. Brackets that don't hold a citation,
+// or that are the text of a link, are left as they are.
+pub fn resolve_citation_syntax(line: &str) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(CITE_START) {
+        let after = &rest[start + CITE_START.len()..];
+        let Some(end) = after.find(CITE_END) else {
+            break;
+        };
+
+        let is_link = after[end + CITE_END.len()..].starts_with('(');
+        match parse_citation(&format!("@{}", &after[..end])).filter(|_| !is_link) {
+            Some(cited) => {
+                result.push_str(&rest[..start]);
+                result.push_str(&cite_tag(&cited));
+            },
+            None => result.push_str(&rest[..start + CITE_START.len() + end + CITE_END.len()])
+        }
+        rest = &after[end + CITE_END.len()..];
+    }
+    result.push_str(rest);
+
+    result
+}
+
+// "p. 12" is a page, "cap. 3" a chapter and so on. A bare number is a page.
+fn parse_locator(locator: &str) -> SpecificLocator<'_> {
+    static LABELS: &[(&str, Locator)] = &[
+        ("pp.", Locator::Page),
+        ("p.", Locator::Page),
+        ("págs.", Locator::Page),
+        ("pág.", Locator::Page),
+        ("cap.", Locator::Chapter),
+        ("chap.", Locator::Chapter),
+        ("sec.", Locator::Section),
+        ("§", Locator::Section),
+        ("fig.", Locator::Figure),
+        ("vol.", Locator::Volume),
+        ("párr.", Locator::Paragraph),
+        ("para.", Locator::Paragraph)
+    ];
+
+    for (label, kind) in LABELS {
+        if let Some(value) = locator.strip_prefix(label) {
+            return SpecificLocator(*kind, LocatorPayload::Str(value.trim()));
+        }
+    }
+
+    if locator.chars().all(|c| c.is_ascii_digit() || c == '-' || c == '–') {
+        SpecificLocator(Locator::Page, LocatorPayload::Str(locator))
+    } else {
+        SpecificLocator(Locator::Custom, LocatorPayload::Str(locator))
+    }
+}
+
+// Writes rendered citations as HTML, escaping their text.
+fn write_html(children: &ElemChildren, output: &mut String) {
+    for child in &children.0 {
+        let escaped = match child {
+            ElemChild::Text(text) => ElemChild::Text(Formatted {
+                text: html_generation::escape_text(&text.text),
+                formatting: text.formatting
+            }),
+            ElemChild::Link { text, url } => ElemChild::Link {
+                text: Formatted { text: html_generation::escape_text(&text.text), formatting: text.formatting },
+                url: html_generation::escape_attribute(url)
+            },
+            ElemChild::Markup(markup) => ElemChild::Markup(html_generation::escape_text(markup)),
+            ElemChild::Elem(elem) => {
+                write_html(&elem.children, output);
+                continue;
+            },
+            ElemChild::Transparent { .. } => continue
+        };
+        escaped.write_buf(output, BufWriteFormat::Html).unwrap_or(());
+    }
+}
+
+fn entry_id(key: &str) -> String {
+    format!("ref-{key}")
+}
+
+fn located(diagnostic: Diagnostic, line: usize, source_map: &SourceMap) -> Diagnostic {
+    match source_map.lookup(line) {
+        Some(source) => diagnostic.in_file(source.file.as_ref()).at(source.line, 1),
+        None => diagnostic
+    }
+}
+
+/* Renders every citation in place and returns the list of the works cited, to
+be placed by the caller:
+
+<section class='bibliography'>
+    <h1 class='bibliography-title'>Referencias</h1>
+    <div class='bibliography-entry' id='ref-knuth1984'><span class='bibliography-label'>[1]</span> ...</div>
+</section>
+
+Citations link to their entry. Citing an unknown key, or citing anything
+without a bibliography, is an error. Returns None when nothing is cited. */
+pub fn resolve_citations<'a>(root: &'a AstNode<'a>, bibliography: Option<&Bibliography>, source_map: &SourceMap, diagnostics: &mut Diagnostics) -> Option<String> {
+    let citations = root.descendants()
+        .filter_map(|node| {
+            let cited = match node.data.borrow().value {
+                NodeValue::HtmlInline(ref literal) => decode_cite_tag(literal),
+                _ => None
+            };
+            cited.map(|cited| (node, cited))
+        })
+        .collect::<Vec<_>>();
+
+    if citations.is_empty() {
+        return None;
+    }
+
+    let node_line = |node: &'a AstNode<'a>| node.ancestors()
+        .map(|ancestor| ancestor.data.borrow().sourcepos.start.line)
+        .find(|line| *line > 0)
+        .unwrap_or(0);
+
+    let Some(bibliography) = bibliography else {
+        for (node, _) in &citations {
+            diagnostics.push(located(
+                Diagnostic::error("hay citas pero el proyecto no tiene una sección `bibliography`"),
+                node_line(node),
+                source_map
+            ));
+        }
+        return None;
+    };
+
+    let locales = archive::locales();
+    let mut driver = BibliographyDriver::new();
+    let mut rendered_nodes = vec![];
+    for (node, cited) in &citations {
+        let mut items = vec![];
+        for cited in cited {
+            match bibliography.library.get(&cited.key) {
+                Some(entry) => items.push(CitationItem::with_locator(entry, cited.locator.as_deref().map(parse_locator))),
+                None => diagnostics.push(located(
+                    Diagnostic::error(format!("cita a una referencia desconocida: `{}`", cited.key)),
+                    node_line(node),
+                    source_map
+                ))
+            }
+        }
+
+        if items.is_empty() {
+            node.data.borrow_mut().value = NodeValue::HtmlInline("<span class='citation'>??</span>".to_string());
+            continue;
+        }
+
+        let mut request = CitationRequest::from_items(items, &bibliography.style, &locales);
+        request.locale = Some(bibliography.locale.clone());
+        driver.citation(request);
+        rendered_nodes.push((node, cited[0].key.clone()));
+    }
+
+    let rendered = driver.finish(BibliographyRequest::new(&bibliography.style, Some(bibliography.locale.clone()), &locales));
+
+    for ((node, first_key), citation) in rendered_nodes.iter().zip(&rendered.citations) {
+        let mut html = String::new();
+        write_html(&citation.citation, &mut html);
+        node.data.borrow_mut().value = NodeValue::HtmlInline(format!(
+            "<a class='citation' href='#{}'>{html}</a>",
+            html_generation::escape_attribute(&entry_id(first_key))
+        ));
+    }
+
+    let mut html = format!(
+        "<section class='bibliography'>\n<h1 class='bibliography-title'>{}</h1>\n",
+        html_generation::escape_text(&bibliography.title)
+    );
+    for item in rendered.bibliography.map(|bibliography| bibliography.items).unwrap_or_default() {
+        html.push_str(&format!("<div class='bibliography-entry' id='{}'>", html_generation::escape_attribute(&entry_id(&item.key))));
+        if let Some(first_field) = item.first_field {
+            html.push_str("<span class='bibliography-label'>");
+            write_html(&ElemChildren(vec![first_field]), &mut html);
+            html.push_str("</span> ");
+        }
+        write_html(&item.content, &mut html);
+        html.push_str("</div>\n");
+    }
+    html.push_str("</section>\n");
+
+    Some(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use comrak::{parse_document, Arena, Options};
+    use crate::filesystem::MemoryFs;
+    use crate::source_map::SourceLine;
+
+    fn cited(key: &str, locator: Option<&str>) -> Cited {
+        Cited { key: key.to_string(), locator: locator.map(str::to_string) }
+    }
+
+    fn bibliography() -> Bibliography {
+        let files = MemoryFs::new()
+            .with_file("/doc/refs.bib", "@book{knuth1984, title={The TeXbook}, author={Knuth, Donald}, year={1984}, publisher={Addison-Wesley}}");
+        let config = BibliographyConfig { files: vec!["refs.bib".to_string()], ..Default::default() };
+        Bibliography::load(&files, &config, &[path::PathBuf::from("/doc/refs.bib")]).ok().unwrap()
+    }
+
+    // Renders the citations of a one-line document read from /doc/start.md.
+    fn render(markdown: &str, bibliography: Option<&Bibliography>) -> (Option<String>, Vec<Diagnostic>) {
+        let arena = Arena::new();
+        let root = parse_document(&arena, &resolve_citation_syntax(markdown), &Options::default());
+        let mut source_map = SourceMap::new("/doc");
+        source_map.push(SourceLine { file: Rc::new(path::PathBuf::from("/doc/start.md")), line: 1 });
+
+        let mut diagnostics = Diagnostics::new();
+        let html = resolve_citations(root, bibliography, &source_map, &mut diagnostics);
+        (html, diagnostics.iter().cloned().collect())
+    }
+
+    #[test]
+    fn citations_with_locators_and_several_keys() {
+        assert_eq!(parse_citation("@knuth1984"), Some(vec![cited("knuth1984", None)]));
+        assert_eq!(parse_citation("@knuth1984, p. 12"), Some(vec![cited("knuth1984", Some("p. 12"))]));
+        assert_eq!(parse_citation("@knuth1984,"), Some(vec![cited("knuth1984", None)]));
+        assert_eq!(
+            parse_citation("@a; @doi:10.1/b.c, cap. 2"),
+            Some(vec![cited("a", None), cited("doi:10.1/b.c", Some("cap. 2"))])
+        );
+    }
+
+    #[test]
+    fn text_that_is_not_a_citation() {
+        for text in ["knuth1984", "@", "@a; b", "@a texto", "@a.", "@a; "] {
+            assert_eq!(parse_citation(text), None, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn citation_syntax_becomes_a_tag() {
+        let line = resolve_citation_syntax("Ver [@knuth1984, p. 3] y [@a; @b].");
+        assert_eq!(line, format!(
+            "Ver {} y {}.",
+            cite_tag(&[cited("knuth1984", Some("p. 3"))]),
+            cite_tag(&[cited("a", None), cited("b", None)])
+        ));
+
+        for line in ["[@knuth1984](https://example.com)", "[@ no es una cita]", "sin cerrar [@knuth1984", "correo@example.com"] {
+            assert_eq!(resolve_citation_syntax(line), line);
+        }
+    }
+
+    #[test]
+    fn cite_tags_are_decoded_back() {
+        let cited = vec![cited("a", Some("p. 'uno' & <dos>")), cited("b", None)];
+        assert_eq!(decode_cite_tag(&cite_tag(&cited)), Some(cited));
+        assert_eq!(decode_cite_tag("<span class='x'>"), None);
+    }
+
+    #[test]
+    fn csl_json_articles_belong_to_their_journal() {
+        let item = json!({
+            "id": "ana2020",
+            "type": "article-journal",
+            "title": "Un artículo",
+            "author": [{ "family": "Pérez", "given": "Ana" }, { "literal": "Grupo X" }],
+            "issued": { "date-parts": [[2020, 5]] },
+            "container-title": "Revista",
+            "volume": 3,
+            "page": "10-20",
+            "DOI": "10.1/x"
+        });
+
+        assert_eq!(csl_to_entry(&item), Some(("ana2020".to_string(), json!({
+            "type": "article",
+            "title": "Un artículo",
+            "page-range": "10-20",
+            "author": ["Pérez, Ana", "Grupo X"],
+            "date": "2020-05",
+            "serial-number": { "doi": "10.1/x" },
+            "parent": { "type": "periodical", "title": "Revista", "volume": "3" }
+        }))));
+    }
+
+    #[test]
+    fn csl_json_books_and_missing_ids() {
+        let item = json!({ "id": 7, "type": "book", "issued": { "date-parts": [["1984"]] }, "publisher": "Addison-Wesley", "publisher-place": "Reading" });
+        assert_eq!(csl_to_entry(&item), Some(("7".to_string(), json!({
+            "type": "book",
+            "date": 1984,
+            "publisher": { "name": "Addison-Wesley", "location": "Reading" }
+        }))));
+
+        assert_eq!(csl_to_entry(&json!({ "type": "book", "title": "Sin id" })), None);
+    }
+
+    #[test]
+    fn citations_link_to_their_entry() {
+        let (html, diagnostics) = render("Texto [@knuth1984, p. 3].", Some(&bibliography()));
+
+        assert!(diagnostics.is_empty());
+        assert!(html.unwrap().contains("<div class='bibliography-entry' id='ref-knuth1984'>"));
+    }
+
+    #[test]
+    fn unknown_keys_are_errors_where_cited() {
+        let (html, diagnostics) = render("Texto [@nadie].", Some(&bibliography()));
+
+        assert_eq!(html.as_deref(), Some("<section class='bibliography'>\n<h1 class='bibliography-title'>Referencias</h1>\n</section>\n"));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "cita a una referencia desconocida: `nadie`");
+        assert_eq!(diagnostics[0].file.as_deref(), Some(path::Path::new("/doc/start.md")));
+        assert_eq!(diagnostics[0].line, Some(1));
+    }
+
+    #[test]
+    fn citations_without_bibliography_are_errors() {
+        let (html, diagnostics) = render("Texto [@knuth1984].", None);
+
+        assert_eq!(html, None);
+        assert_eq!(diagnostics[0].message, "hay citas pero el proyecto no tiene una sección `bibliography`");
+    }
+}
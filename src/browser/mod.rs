@@ -0,0 +1,115 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use std::{ffi, path};
+use anyhow::{Context, Result};
+use headless_chrome::{Browser, LaunchOptions, Tab};
+
+/* The `browser` section of `project.thn`, all of it optional:
+{
+    "browser": {
+        "executable": "/usr/bin/chromium",
+        "args": ["--no-sandbox"],
+        "timeout": 30
+    }
+}
+
+`executable` is the Chrome or Chromium to run, the one installed in the system
+by default. `args` are passed to it besides the usual ones for headless use.
+`timeout` is how many seconds a page has to load and print. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BrowserConfig {
+    executable: Option<String>,
+    args: Option<Vec<String>>,
+    timeout: Option<u64>
+}
+
+impl BrowserConfig {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(30))
+    }
+}
+
+// Chrome drops its connection after this long without a request. Watch mode can
+// be idle for a while between builds; a browser that was dropped is started again.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// Browsers that are running, so that they can be closed when the process is
+// interrupted.
+static RUNNING: Mutex<Vec<Weak<Mutex<Option<Browser>>>>> = Mutex::new(vec![]);
+
+// A headless Chrome shared by every render of a build, and by every rebuild of a
+// `watch` or `serve` session. It is started the first time a page is opened and
+// closed when the session is dropped.
+pub struct BrowserSession {
+    config: BrowserConfig,
+    browser: Arc<Mutex<Option<Browser>>>
+}
+
+impl BrowserSession {
+    pub fn new(config: &BrowserConfig) -> BrowserSession {
+        let browser = Arc::new(Mutex::new(None));
+        let mut running = RUNNING.lock().unwrap();
+        running.retain(|browser| browser.strong_count() > 0);
+        running.push(Arc::downgrade(&browser));
+
+        BrowserSession { config: config.clone(), browser }
+    }
+
+    fn launch(&self) -> Result<Browser> {
+        println!("[INFO] Starting Chrome");
+        let executable = match &self.config.executable {
+            Some(executable) if path::Path::new(executable).is_file() => path::PathBuf::from(executable),
+            Some(executable) => anyhow::bail!("No existe el ejecutable de Chrome `{executable}` indicado en `browser.executable`"),
+            None => headless_chrome::browser::default_executable()
+                .map_err(|err| anyhow::anyhow!("No se encontró Chrome ({err}), se puede indicar con `browser.executable`"))?
+        };
+        let args: Vec<&ffi::OsStr> = self.config.args.iter().flatten().map(ffi::OsStr::new).collect();
+
+        let options = LaunchOptions::default_builder()
+            .path(Some(executable.clone()))
+            .args(args)
+            .idle_browser_timeout(IDLE_TIMEOUT)
+            .build()?;
+
+        Browser::new(options).with_context(|| format!("No se pudo iniciar Chrome ({})", executable.display()))
+    }
+
+    // A new tab, starting the browser if it isn't running or if it stopped.
+    pub fn new_tab(&self) -> Result<Arc<Tab>> {
+        let mut browser = self.browser.lock().unwrap();
+        let tab = match browser.as_ref().map(|browser| browser.new_tab()) {
+            Some(Ok(tab)) => tab,
+            _ => {
+                let started = browser.insert(self.launch()?);
+                started.new_tab()?
+            }
+        };
+        tab.set_default_timeout(self.config.timeout());
+
+        Ok(tab)
+    }
+}
+
+impl Drop for BrowserSession {
+    fn drop(&mut self) {
+        // Dropping the browser kills Chrome and removes its profile.
+        self.browser.lock().unwrap().take();
+    }
+}
+
+// Closes every browser before exiting on Ctrl-C (or a SIGTERM), which would
+// otherwise leave Chrome running with its temporary profile behind.
+pub fn close_on_interrupt() -> Result<()> {
+    ctrlc::set_handler(|| {
+        for browser in RUNNING.lock().unwrap().iter().filter_map(Weak::upgrade) {
+            // A browser that is busy starting is left to the OS.
+            if let Ok(mut browser) = browser.try_lock() {
+                browser.take();
+            }
+        }
+        std::process::exit(130);
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,566 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use comrak::Arena;
+use comrak::nodes::{Ast, AstNode, LineColumn, NodeHtmlBlock, NodeValue};
+
+use crate::diagnostics::{Diagnostic, Diagnostics};
+use crate::{html_generation, math, md_compiler};
+use crate::source_map::SourceMap;
+use crate::toc::Heading;
+
+// `@ref(name)` in the markdown. The preprocessor turns it into a single inline
+// tag so that comrak keeps it in one node.
+pub static REF_START: &str = "@ref(";
+pub static REF_END: &str = ")";
+static REF_TAG_START: &str = "<thener-ref id='";
+
+// What `@#name` spans look like once preprocessed.
+static ANCHOR_TAG_START: &str = "<span id='";
+
+pub fn ref_tag(name: &str) -> String {
+    format!("{REF_TAG_START}{}'/>", html_generation::escape_attribute(name))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetKind {
+    Section,
+    Figure,
+    Table,
+    Equation
+}
+
+// How a reference to each kind of target is written, e.g. "Figura 4", and the
+// titles of the lists of figures and tables.
+pub struct Labels {
+    pub section: &'static str,
+    pub figure: &'static str,
+    pub table: &'static str,
+    pub equation: &'static str,
+    pub figures_title: &'static str,
+    pub tables_title: &'static str
+}
+
+impl Default for Labels {
+    fn default() -> Labels {
+        Labels {
+            section: "Sección",
+            figure: "Figura",
+            table: "Tabla",
+            equation: "Ecuación",
+            figures_title: "Índice de figuras",
+            tables_title: "Índice de tablas"
+        }
+    }
+}
+
+impl Labels {
+    // The labels for the language of the document ("en", "pt-BR"...), in
+    // Spanish for languages without their own.
+    pub fn for_language(language: Option<&str>) -> Labels {
+        let language = language.unwrap_or("es").split(['-', '_']).next().unwrap_or("").to_lowercase();
+        match language.as_str() {
+            "en" => Labels {
+                section: "Section",
+                figure: "Figure",
+                table: "Table",
+                equation: "Equation",
+                figures_title: "List of Figures",
+                tables_title: "List of Tables"
+            },
+            "pt" => Labels {
+                section: "Seção",
+                figure: "Figura",
+                table: "Tabela",
+                equation: "Equação",
+                figures_title: "Lista de figuras",
+                tables_title: "Lista de tabelas"
+            },
+            _ => Labels::default()
+        }
+    }
+
+    // "Figura 3.", in front of a caption.
+    pub fn caption_label(&self, kind: TargetKind, number: &str) -> String {
+        format!("{} {number}.", self.for_kind(kind))
+    }
+
+    fn for_kind(&self, kind: TargetKind) -> &'static str {
+        match kind {
+            TargetKind::Section => self.section,
+            TargetKind::Figure => self.figure,
+            TargetKind::Table => self.table,
+            TargetKind::Equation => self.equation
+        }
+    }
+}
+
+// Something a reference can point to, with its number ("2.3", "4").
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub kind: TargetKind,
+    pub number: String
+}
+
+// Splits a `<span id='name'>` or `<thener-ref id='name'/>` tag, returning the name.
+fn tag_id<'a>(literal: &'a str, tag_start: &str) -> Option<&'a str> {
+    literal.strip_prefix(tag_start)?.split('\'').next()
+}
+
+// Line of the preprocessed markdown where a node starts. Inline nodes don't always
+// know, so the closest block that does is used.
+fn node_line<'a>(node: &'a AstNode<'a>) -> usize {
+    node.ancestors()
+        .map(|ancestor| ancestor.data.borrow().sourcepos.start.line)
+        .find(|line| *line > 0)
+        .unwrap_or(0)
+}
+
+fn located(diagnostic: Diagnostic, node_line: usize, source_map: &SourceMap) -> Diagnostic {
+    match source_map.lookup(node_line) {
+        Some(source) => diagnostic.in_file(source.file.as_ref()).at(source.line, 1),
+        None => diagnostic
+    }
+}
+
+fn is_anchor<'a>(node: &'a AstNode<'a>) -> bool {
+    matches!(node.data.borrow().value, NodeValue::HtmlInline(ref literal) if literal.starts_with(ANCHOR_TAG_START) || literal == "</span>")
+}
+
+fn is_blank<'a>(node: &'a AstNode<'a>) -> bool {
+    match node.data.borrow().value {
+        NodeValue::Text(ref text) => text.trim().is_empty(),
+        NodeValue::SoftBreak | NodeValue::LineBreak => true,
+        _ => false
+    }
+}
+
+// The kind of numbered element a block is, if any. A paragraph made only of
+// images (and anchors) is a figure, as is a rendered diagram, and one with just a
+// display equation is an equation.
+pub fn element_kind<'a>(node: &'a AstNode<'a>) -> Option<TargetKind> {
+    match node.data.borrow().value {
+        NodeValue::Heading(_) => return Some(TargetKind::Section),
+        NodeValue::Table(_) => return Some(TargetKind::Table),
+        NodeValue::HtmlInline(ref literal) | NodeValue::HtmlBlock(comrak::nodes::NodeHtmlBlock { ref literal, .. })
+            if literal.starts_with("<figure") => return Some(TargetKind::Figure),
+        NodeValue::Paragraph => (),
+        _ => return None
+    }
+
+    let mut kind = None;
+    for child in node.children() {
+        let child_kind = match child.data.borrow().value {
+            NodeValue::Image(_) => Some(TargetKind::Figure),
+            NodeValue::HtmlInline(ref literal) if literal.starts_with(math::EQUATION_TAG_START) => Some(TargetKind::Equation),
+            _ => None
+        };
+
+        match child_kind {
+            // Several images make one figure, but several equations are not one.
+            Some(TargetKind::Figure) if kind.is_none() || kind == Some(TargetKind::Figure) => kind = child_kind,
+            Some(TargetKind::Equation) if kind.is_none() => kind = child_kind,
+            None if is_anchor(child) || is_blank(child) => (),
+            _ => return None
+        }
+    }
+
+    kind
+}
+
+// A paragraph holding nothing but anchors, which then apply to the next block.
+fn is_anchor_only<'a>(node: &'a AstNode<'a>) -> bool {
+    matches!(node.data.borrow().value, NodeValue::Paragraph)
+        && node.children().all(|child| is_anchor(child) || is_blank(child))
+}
+
+// Every heading, figure, table and equation in document order, with its number.
+// Headings are numbered as in `headings`, the rest counting from 1.
+fn number_elements<'a>(root: &'a AstNode<'a>, headings: &[Heading]) -> Vec<(&'a AstNode<'a>, Target)> {
+    let mut numbered = vec![];
+    let mut heading_index = 0;
+    let mut figures = 0;
+    let mut tables = 0;
+    let mut equations = 0;
+    for node in root.descendants() {
+        let Some(kind) = element_kind(node) else {
+            continue;
+        };
+
+        let number = match kind {
+            TargetKind::Section => {
+                let Some(heading) = headings.get(heading_index) else {
+                    continue;
+                };
+                heading_index += 1;
+                heading.number.clone()
+            },
+            TargetKind::Figure => {
+                figures += 1;
+                figures.to_string()
+            },
+            TargetKind::Table => {
+                tables += 1;
+                tables.to_string()
+            },
+            TargetKind::Equation => {
+                equations += 1;
+                equations.to_string()
+            }
+        };
+
+        numbered.push((node, Target { kind, number }));
+    }
+
+    numbered
+}
+
+// A paragraph starting with `:` right after an image or a table is its caption.
+fn caption_of<'a>(node: &'a AstNode<'a>) -> Option<&'a AstNode<'a>> {
+    let is_captionable = match node.data.borrow().value {
+        NodeValue::Table(_) => true,
+        NodeValue::Paragraph => element_kind(node) == Some(TargetKind::Figure),
+        _ => false
+    };
+    let next = node.next_sibling().filter(|_| is_captionable)?;
+    if !matches!(next.data.borrow().value, NodeValue::Paragraph) {
+        return None;
+    }
+
+    let first = next.first_child()?;
+    let is_caption = matches!(first.data.borrow().value, NodeValue::Text(ref text) if text.starts_with(':'));
+    is_caption.then_some(next)
+}
+
+// Numbers every heading, figure, table and equation in document order and
+// resolves what each `@#name` anchor points to. Headings are numbered
+// hierarchically starting at the shallowest level used; their ids are targets
+// too. Duplicate anchors are reported as errors.
+pub fn collect_targets<'a>(root: &'a AstNode<'a>, headings: &[Heading], source_map: &SourceMap, diagnostics: &mut Diagnostics) -> HashMap<String, Target> {
+    let mut numbers: HashMap<*const AstNode<'a>, Target> = HashMap::new();
+    let mut targets: HashMap<String, Target> = HashMap::new();
+
+    let mut sections = headings.iter();
+    for (node, target) in number_elements(root, headings) {
+        if target.kind == TargetKind::Section {
+            if let Some(heading) = sections.next() {
+                targets.insert(heading.id.clone(), target.clone());
+            }
+        }
+        // Anchors in a caption belong to its figure or table.
+        if let Some(caption) = caption_of(node) {
+            numbers.insert(caption as *const _, target.clone());
+        }
+
+        numbers.insert(node as *const _, target);
+    }
+
+    // Explicit anchors, by the element they are attached to: the heading, table,
+    // figure or equation they are in, the block after them when they stand
+    // alone, or else the section they are written in.
+    let mut explicit: HashMap<String, usize> = HashMap::new();
+    let mut current_section: Option<Target> = None;
+    for node in root.descendants() {
+        if let Some(target) = numbers.get(&(node as *const _)).filter(|target| target.kind == TargetKind::Section) {
+            current_section = Some(target.clone());
+        }
+
+        let id = match node.data.borrow().value {
+            NodeValue::HtmlInline(ref literal) => tag_id(literal, ANCHOR_TAG_START).map(str::to_string),
+            _ => None
+        };
+        let Some(id) = id else {
+            continue;
+        };
+
+        let line = node_line(node);
+        if let Some(first_line) = explicit.insert(id.clone(), line) {
+            let first = source_map.display(first_line).unwrap_or_else(|| format!("línea {first_line}"));
+            diagnostics.push(located(
+                Diagnostic::error(format!("el id `{id}` ya fue definido en {first}")),
+                line,
+                source_map
+            ));
+            continue;
+        }
+
+        let attached = node.ancestors()
+            .find_map(|ancestor| {
+                if is_anchor_only(ancestor) {
+                    ancestor.next_sibling().and_then(|next| numbers.get(&(next as *const _)))
+                } else {
+                    numbers.get(&(ancestor as *const _))
+                }
+            })
+            .cloned()
+            .or_else(|| current_section.clone());
+
+        match attached {
+            Some(target) => {
+                targets.insert(id, target);
+            },
+            None => diagnostics.push(located(
+                Diagnostic::warning(format!("el id `{id}` no está dentro de ninguna sección ni figura, no se puede referenciar")),
+                line,
+                source_map
+            ))
+        }
+    }
+
+    targets
+}
+
+// Replaces every `@ref(name)` with a link to its target, labelled with its kind
+// and number. Unknown names are reported as errors.
+pub fn resolve_references<'a>(root: &'a AstNode<'a>, targets: &HashMap<String, Target>, labels: &Labels, source_map: &SourceMap, diagnostics: &mut Diagnostics) {
+    for node in root.descendants() {
+        let name = match node.data.borrow().value {
+            NodeValue::HtmlInline(ref literal) => tag_id(literal, REF_TAG_START).map(str::to_string),
+            _ => None
+        };
+        let Some(name) = name else {
+            continue;
+        };
+
+        let text = match targets.get(&name) {
+            Some(target) => format!("{} {}", labels.for_kind(target.kind), target.number),
+            None => {
+                diagnostics.push(located(
+                    Diagnostic::error(format!("referencia a un id desconocido: `{name}`")),
+                    node_line(node),
+                    source_map
+                ));
+                "??".to_string()
+            }
+        };
+
+        node.data.borrow_mut().value = NodeValue::HtmlInline(format!(
+            "<a class='ref' href='#{}'>{}</a>",
+            html_generation::escape_attribute(&name),
+            html_generation::escape_text(&text)
+        ));
+    }
+}
+
+// A captioned figure or table, for `@listoffigures` and `@listoftables`.
+#[derive(Clone, Debug)]
+pub struct Captioned {
+    pub kind: TargetKind,
+    pub number: String,
+    pub id: String,
+    pub text: String
+}
+
+fn html_block<'a>(arena: &'a Arena<AstNode<'a>>, html: String, line: usize) -> &'a AstNode<'a> {
+    let value = NodeValue::HtmlBlock(NodeHtmlBlock { block_type: 6, literal: html });
+    arena.alloc(AstNode::new(RefCell::new(Ast::new(value, LineColumn { line, column: 1 }))))
+}
+
+// The text of a drawn diagram's `<figcaption>`, if it has one.
+fn diagram_caption(literal: &str) -> Option<String> {
+    let start = literal.find("<figcaption>")? + "<figcaption>".len();
+    let end = start + literal[start..].find("</figcaption>")?;
+    Some(literal[start..end].replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&"))
+}
+
+/* Puts every captioned image and table in a `<figure>` along with its caption,
+labelled with its kind and number, and returns them in document order:
+
+<figure class='image-figure' id='figure-2'>
+    <p><img src='...'></p>
+    <figcaption><p><span class='caption-label'>Figura 2.</span> Arquitectura</p></figcaption>
+</figure>
+
+Tables go in a `table-figure` with the caption above them. Drawn diagrams are
+already a figure with their caption and only get the id and label. Elements
+without a caption are left as they are; they are still numbered for `@ref`. */
+pub fn apply_captions<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>, headings: &[Heading], labels: &Labels) -> Vec<Captioned> {
+    let mut captioned = vec![];
+    for (node, target) in number_elements(root, headings) {
+        let id = match target.kind {
+            TargetKind::Figure => format!("figure-{}", target.number),
+            TargetKind::Table => format!("table-{}", target.number),
+            TargetKind::Section | TargetKind::Equation => continue
+        };
+        let label = format!("<span class='caption-label'>{}</span> ", html_generation::escape_text(&labels.caption_label(target.kind, &target.number)));
+        let line = node.data.borrow().sourcepos.start.line;
+
+        // A drawn diagram, captioned with `caption=`.
+        let diagram = match node.data.borrow().value {
+            NodeValue::HtmlBlock(ref html) => Some(html.literal.clone()),
+            _ => None
+        };
+        if let Some(literal) = diagram {
+            let Some(text) = diagram_caption(&literal) else {
+                continue;
+            };
+            let literal = literal
+                .replacen("<figure ", &format!("<figure id='{id}' "), 1)
+                .replacen("<figcaption>", &format!("<figcaption>{label}"), 1);
+            node.data.borrow_mut().value = NodeValue::HtmlBlock(NodeHtmlBlock { block_type: 6, literal });
+            captioned.push(Captioned { kind: target.kind, number: target.number, id, text });
+            continue;
+        }
+
+        let Some(caption) = caption_of(node) else {
+            continue;
+        };
+        let mut text = String::new();
+        md_compiler::collect_text(caption, &mut text);
+        let text = text.trim_start_matches(':').trim().to_string();
+
+        // The `:` gives way to the label.
+        if let Some(first) = caption.first_child() {
+            if let NodeValue::Text(ref mut literal) = first.data.borrow_mut().value {
+                *literal = literal.trim_start_matches(':').trim_start().to_string();
+            }
+            first.insert_before(arena.alloc(AstNode::new(RefCell::new(Ast::new(
+                NodeValue::HtmlInline(label),
+                LineColumn { line, column: 1 }
+            )))));
+        }
+
+        if target.kind == TargetKind::Table {
+            caption.detach();
+            node.insert_before(html_block(arena, format!("<figure class='table-figure' id='{id}'>\n<figcaption>\n"), line));
+            node.insert_before(caption);
+            node.insert_before(html_block(arena, "</figcaption>\n".to_string(), line));
+            node.insert_after(html_block(arena, "</figure>\n".to_string(), line));
+        } else {
+            node.insert_before(html_block(arena, format!("<figure class='image-figure' id='{id}'>\n"), line));
+            caption.insert_before(html_block(arena, "<figcaption>\n".to_string(), line));
+            caption.insert_after(html_block(arena, "</figcaption>\n</figure>\n".to_string(), line));
+        }
+
+        captioned.push(Captioned { kind: target.kind, number: target.number, id, text });
+    }
+
+    captioned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path;
+    use std::sync::Arc;
+    use crate::filesystem::MemoryFs;
+    use crate::jobs::Jobs;
+    use crate::md_compiler::{markdown_to_html, MarkdownOptions, MarkdownPreprocessor};
+
+    // Renders /doc/start.md, importing the other `files`. Errors are returned as
+    // the diagnostics found.
+    fn render(start: &str, files: &[(&str, &str)], language: Option<&str>) -> Result<String, Vec<Diagnostic>> {
+        let filesystem = files.iter().fold(MemoryFs::new(), |filesystem, (file, code)| filesystem.with_file(file, *code));
+        let preprocessor = MarkdownPreprocessor::new()
+            .with_filesystem(Arc::new(filesystem))
+            .with_base_dir(path::Path::new("/doc"));
+        let (markdown, source_map) = preprocessor.preprocess_markdown("start.md", start).unwrap();
+
+        let options = MarkdownOptions {
+            toc: None,
+            number_headings: true,
+            bibliography: None,
+            highlight: None,
+            mermaid: None,
+            diagrams: None,
+            language,
+            jobs: Jobs::default()
+        };
+        markdown_to_html(&markdown, &source_map, &options)
+            .map(|rendered| rendered.html)
+            .map_err(|err| err.downcast_ref::<Diagnostics>().unwrap().iter().cloned().collect())
+    }
+
+    static DOCUMENT: &str = "# Introducción @#sec:intro
+
+![Arquitectura](arq.png) @#fig:arq
+
+: Arquitectura general
+
+| a | b |
+|---|---|
+| 1 | 2 |
+
+: Datos medidos @#tab:datos
+
+## Detalle
+
+![Otro](otro.png) @#fig:otro
+
+Ver @ref(fig:arq), @ref(fig:otro), @ref(tab:datos), @ref(sec:intro) y @ref(detalle).
+";
+
+    #[test]
+    fn each_kind_is_numbered_apart() {
+        let html = render(DOCUMENT, &[], None).unwrap();
+
+        for reference in [
+            "<a class='ref' href='#fig:arq'>Figura 1</a>",
+            "<a class='ref' href='#fig:otro'>Figura 2</a>",
+            "<a class='ref' href='#tab:datos'>Tabla 1</a>",
+            "<a class='ref' href='#sec:intro'>Sección 1</a>",
+            "<a class='ref' href='#detalle'>Sección 1.1</a>"
+        ] {
+            assert!(html.contains(reference), "{reference}");
+        }
+    }
+
+    #[test]
+    fn references_use_the_labels_of_the_language() {
+        let html = render(DOCUMENT, &[], Some("en-US")).unwrap();
+
+        assert!(html.contains("<a class='ref' href='#fig:arq'>Figure 1</a>"));
+        assert!(html.contains("<a class='ref' href='#tab:datos'>Table 1</a>"));
+    }
+
+    #[test]
+    fn duplicate_ids_are_errors() {
+        let diagnostics = render("# Uno @#repetido\n\nTexto @#repetido\n", &[], None).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "el id `repetido` ya fue definido en start.md:1");
+        assert_eq!(diagnostics[0].file.as_deref(), Some(path::Path::new("/doc/start.md")));
+        assert_eq!(diagnostics[0].line, Some(3));
+    }
+
+    #[test]
+    fn unknown_ids_are_errors_where_referenced() {
+        let start = "# Uno\n\n@import capitulo\n";
+        let diagnostics = render(start, &[("/doc/capitulo.md", "Texto\n\nVer @ref(nada).\n")], None).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "referencia a un id desconocido: `nada`");
+        assert_eq!(diagnostics[0].file.as_deref(), Some(path::Path::new("/doc/capitulo.md")));
+        assert_eq!(diagnostics[0].line, Some(3));
+    }
+
+    #[test]
+    fn captions_are_labelled_with_their_number() {
+        let html = render(DOCUMENT, &[], None).unwrap();
+
+        assert!(html.contains("<figure class='image-figure' id='figure-1'>"));
+        assert!(html.contains("<span class='caption-label'>Figura 1.</span> Arquitectura general"));
+        assert!(html.contains("<figure class='table-figure' id='table-1'>"));
+        assert!(html.contains("<span class='caption-label'>Tabla 1.</span> Datos medidos"));
+        // The second figure has no caption, but is still numbered.
+        assert!(!html.contains("id='figure-2'"));
+    }
+
+    #[test]
+    fn lists_hold_the_captioned_elements() {
+        let html = render(&format!("@listoffigures\n\n@listoftables\n\n{DOCUMENT}"), &[], None).unwrap();
+
+        assert!(html.contains("<nav class='toc list-of-figures'>\n<h1 class='toc-title'>Índice de figuras</h1>"));
+        assert!(html.contains("<li class='toc-entry'><a href='#figure-1'><span class='toc-number'>Figura 1.</span> Arquitectura general</a><span class='toc-page' data-target='figure-1'></span></li>"));
+        assert!(!html.contains("<a href='#figure-2'>"));
+        assert!(html.contains("<nav class='toc list-of-tables'>\n<h1 class='toc-title'>Índice de tablas</h1>"));
+        assert!(html.contains("<a href='#table-1'><span class='toc-number'>Tabla 1.</span> Datos medidos</a>"));
+    }
+
+    #[test]
+    fn lists_use_the_titles_of_the_language() {
+        let html = render(&format!("@listoffigures\n\n{DOCUMENT}"), &[], Some("pt")).unwrap();
+
+        assert!(html.contains("<h1 class='toc-title'>Lista de figuras</h1>"));
+        assert!(html.contains("<span class='toc-number'>Figura 1.</span> Arquitectura general"));
+    }
+}
@@ -0,0 +1,265 @@
+use std::{fmt, path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning")
+        }
+    }
+}
+
+// The line a diagnostic points at, with the columns to underline.
+#[derive(Clone, Debug)]
+pub struct Snippet {
+    pub text: String,
+    pub length: usize
+}
+
+// A problem found while building, with enough context to point at the markdown,
+// template or output file that caused it. Lines and columns are 1-based.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: Option<path::PathBuf>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub snippet: Option<Snippet>
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            file: None,
+            line: None,
+            column: None,
+            snippet: None
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, message)
+    }
+
+    pub fn in_file(mut self, file: impl Into<path::PathBuf>) -> Diagnostic {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn at(mut self, line: usize, column: usize) -> Diagnostic {
+        self.line = Some(line);
+        self.column = Some(column);
+        self
+    }
+
+    // Underlines `length` characters of `text` starting at the diagnostic's column.
+    pub fn with_snippet(mut self, text: &str, length: usize) -> Diagnostic {
+        self.snippet = Some(Snippet { text: text.to_string(), length: length.max(1) });
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+// Paths under the working directory are shown relative to it, like rustc does.
+fn display_path(file: &path::Path) -> String {
+    std::env::current_dir().ok()
+        .and_then(|current_dir| file.strip_prefix(current_dir).ok().map(|relative| relative.to_path_buf()))
+        .unwrap_or_else(|| file.to_path_buf())
+        .display()
+        .to_string()
+}
+
+/* Rendered like rustc's errors:
+
+error: ciclo de importes: start.md -> sections/end.md -> start.md
+ --> sections/end.md:5:1
+  |
+5 | @import ../start
+  | ^^^^^^^^^^^^^^^^
+ */
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)?;
+
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let gutter = " ".repeat(self.line.map(|line| line.to_string().len()).unwrap_or(1));
+        write!(f, "\n{gutter}--> {}", display_path(file))?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}:{}", self.column.unwrap_or(1))?;
+        }
+
+        if let (Some(line), Some(snippet)) = (self.line, &self.snippet) {
+            let padding = " ".repeat(self.column.unwrap_or(1).saturating_sub(1));
+            write!(f, "\n{gutter} |")?;
+            write!(f, "\n{line} | {}", snippet.text)?;
+            write!(f, "\n{gutter} | {padding}{}", "^".repeat(snippet.length))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+// Every problem found by a stage. Stages keep going after an error when they
+// can, so a single build reports as many problems as possible.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics {
+    items: Vec<Diagnostic>
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    // A file imported twice reports its problems twice; only the first is kept.
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        let repeated = self.items.iter().any(|item| {
+            item.message == diagnostic.message && item.file == diagnostic.file && item.line == diagnostic.line
+        });
+
+        if !repeated {
+            self.items.push(diagnostic);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.items.iter()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.items.iter().any(Diagnostic::is_error)
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, diagnostic) in self.items.iter().enumerate() {
+            if index > 0 {
+                write!(f, "\n\n")?;
+            }
+            write!(f, "{diagnostic}")?;
+        }
+
+        let errors = self.items.iter().filter(|diagnostic| diagnostic.is_error()).count();
+        if errors > 1 {
+            write!(f, "\n\nerror: se encontraron {errors} errores")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diagnostic: Diagnostic) -> Diagnostics {
+        Diagnostics { items: vec![diagnostic] }
+    }
+}
+
+// Prints an error returned by a build. Diagnostics are shown in full, anything
+// else as a single `[ERROR]` line.
+pub fn report(err: &(dyn std::error::Error + 'static)) {
+    if let Some(diagnostics) = err.downcast_ref::<Diagnostics>() {
+        eprintln!("{diagnostics}");
+    } else if let Some(diagnostic) = err.downcast_ref::<Diagnostic>() {
+        eprintln!("{diagnostic}");
+    } else {
+        println!("[ERROR] {err}");
+    }
+}
+
+// Prints the warnings of a stage that did not fail.
+pub fn report_warnings(diagnostics: &Diagnostics) {
+    for diagnostic in diagnostics.iter().filter(|diagnostic| !diagnostic.is_error()) {
+        eprintln!("{diagnostic}\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycle() -> Diagnostic {
+        Diagnostic::error("ciclo de importes: start.md -> sections/end.md -> start.md")
+            .in_file("/doc/sections/end.md")
+            .at(5, 1)
+            .with_snippet("@import ../start", 16)
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_line() {
+        assert_eq!(cycle().to_string(), concat!(
+            "error: ciclo de importes: start.md -> sections/end.md -> start.md\n",
+            " --> /doc/sections/end.md:5:1\n",
+            "  |\n",
+            "5 | @import ../start\n",
+            "  | ^^^^^^^^^^^^^^^^"
+        ));
+
+        let underlined = Diagnostic::warning("id desconocido").in_file("/doc/start.md").at(12, 5).with_snippet("Ver @ref(x)", 0);
+        assert!(underlined.to_string().ends_with("\n12 | Ver @ref(x)\n   |     ^"), "{underlined}");
+    }
+
+    #[test]
+    fn diagnostics_without_a_line() {
+        assert_eq!(Diagnostic::error("sin archivo").to_string(), "error: sin archivo");
+        assert_eq!(Diagnostic::warning("sin línea").in_file("/doc/start.md").to_string(), "warning: sin línea\n --> /doc/start.md");
+        assert_eq!(Diagnostic::error("sin columna").in_file("/doc/start.md").at(3, 1).to_string(), "error: sin columna\n --> /doc/start.md:3:1");
+
+        // Relative to the working directory when under it.
+        let inside = std::env::current_dir().unwrap().join("capitulos").join("uno.md");
+        let relative = path::Path::new("capitulos").join("uno.md");
+        assert_eq!(Diagnostic::error("relativa").in_file(inside).to_string(), format!("error: relativa\n --> {}", relative.display()));
+    }
+
+    #[test]
+    fn repeated_diagnostics_are_kept_once() {
+        let mut diagnostics = Diagnostics::new();
+        diagnostics.push(Diagnostic::warning("repetido").in_file("/doc/uno.md").at(2, 1));
+        assert!(!diagnostics.has_errors());
+
+        diagnostics.push(cycle());
+        diagnostics.push(cycle());
+        diagnostics.push(Diagnostic::warning("repetido").in_file("/doc/uno.md").at(2, 4));
+        diagnostics.push(Diagnostic::warning("repetido").in_file("/doc/dos.md").at(2, 1));
+
+        assert!(diagnostics.has_errors());
+        assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.file.as_deref().unwrap().to_str().unwrap()).collect::<Vec<_>>(), [
+            "/doc/uno.md",
+            "/doc/sections/end.md",
+            "/doc/dos.md"
+        ]);
+    }
+
+    #[test]
+    fn several_errors_are_counted() {
+        let mut diagnostics = Diagnostics::from(Diagnostic::error("uno"));
+        assert_eq!(diagnostics.to_string(), "error: uno");
+
+        diagnostics.push(Diagnostic::warning("dos"));
+        diagnostics.push(Diagnostic::error("tres"));
+        assert_eq!(diagnostics.to_string(), "error: uno\n\nwarning: dos\n\nerror: tres\n\nerror: se encontraron 2 errores");
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::{fs, path};
+use anyhow::{Context, Result};
+use path_absolutize::Absolutize;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::filesystem::DiskFs;
+use crate::{highlight, html_generation, manifest};
+
+// The language of the blocks drawn by `mermaid`, configured apart.
+pub static MERMAID: &str = "mermaid";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagramFormat {
+    #[default]
+    Svg,
+    Png
+}
+
+impl DiagramFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            DiagramFormat::Svg => "svg",
+            DiagramFormat::Png => "png"
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DiagramCommand {
+    command: String,
+    format: Option<DiagramFormat>
+}
+
+impl DiagramCommand {
+    // The first word of `command`.
+    fn program(&self) -> Option<String> {
+        highlight::split_info(&self.command).ok()?.into_iter().next()
+    }
+}
+
+/* The `diagrams` section of `project.thn`, the tools that draw code blocks by
+the language in their info string:
+{
+    "diagrams": {
+        "dot": { "command": "dot -Tsvg" },
+        "plantuml": { "command": "plantuml -tsvg -pipe" },
+        "d2": { "command": "d2 --pad 0 {input} {output}" },
+        "ditaa": { "command": "ditaa {input} {output}", "format": "png" }
+    }
+}
+
+The code of the block is given to `command` on its standard input and the image
+is read from its standard output. Tools that need files can use `{input}` and
+`{output}` in the command instead, which are replaced by the paths of temporary
+files; the output file ends in the extension of `format`. Commands run in the
+project directory. `format` is "svg" (the default), placed in the page as is,
+or "png", embedded as an image.
+
+Like `mermaid` blocks, which are set up apart in `mermaid`, a diagram can have
+a caption:
+
+```dot caption="Arquitectura del sistema"
+```
+
+Drawn diagrams are kept in `cache/diagrams` inside the build directory, named by
+a hash of their code, their command and the program it runs when that is a file,
+and only drawn again when any of them changes. */
+pub type DiagramsConfig = BTreeMap<String, DiagramCommand>;
+
+pub fn validate(config: &DiagramsConfig) -> Result<(), String> {
+    for (language, command) in config {
+        if language == MERMAID {
+            return Err("los diagramas `mermaid` se configuran en la sección `mermaid`, no en `diagrams`".to_string());
+        }
+        if language.is_empty() || !language.chars().all(|c| c.is_alphanumeric() || "-_+.".contains(c)) {
+            return Err(format!("`{language}` no es un nombre válido para un lenguaje de `diagrams`"));
+        }
+        match highlight::split_info(&command.command) {
+            Ok(words) if words.is_empty() => return Err(format!("el comando de `diagrams.{language}` está vacío")),
+            Ok(_) => (),
+            Err(err) => return Err(format!("el comando de `diagrams.{language}` es inválido: {err}"))
+        }
+    }
+
+    Ok(())
+}
+
+// The language of a code block, the first word of its info string.
+pub fn language(info: &str) -> &str {
+    info.split_whitespace().next().unwrap_or("")
+}
+
+// What the info string of a diagram block asks for.
+pub struct DiagramInfo {
+    pub language: String,
+    pub caption: Option<String>
+}
+
+pub fn parse_info(info: &str) -> Result<DiagramInfo, String> {
+    let mut words = highlight::split_info(info)?.into_iter();
+    let mut options = DiagramInfo {
+        language: words.next().unwrap_or_default(),
+        caption: None
+    };
+    for word in words {
+        match word.split_once('=') {
+            Some(("caption", caption)) => options.caption = Some(caption.to_string()),
+            _ => return Err(format!("opción desconocida para el diagrama: `{word}`"))
+        }
+    }
+
+    Ok(options)
+}
+
+/* The markup of every drawn diagram:
+
+<figure class='diagram dot-graph' data-source='capitulo.md:12'>
+    <svg>...</svg>
+    <figcaption>Arquitectura del sistema</figcaption>
+</figure> */
+pub fn figure_html(info: &DiagramInfo, source: Option<String>, image: &str) -> String {
+    let source = source
+        .map(|source| format!(" data-source='{}'", html_generation::escape_attribute(&source)))
+        .unwrap_or_default();
+    let caption = info.caption.as_ref()
+        .map(|caption| format!("<figcaption>{}</figcaption>", html_generation::escape_text(caption)))
+        .unwrap_or_default();
+
+    format!("<figure class='diagram {}-graph'{source}>{image}{caption}</figure>\n", html_generation::escape_attribute(&info.language))
+}
+
+// An SVG file as markup for the page, without the XML declaration and doctype
+// that come before the `<svg>` element.
+pub fn inline_svg(svg: &str) -> &str {
+    match svg.find("<svg") {
+        Some(start) => &svg[start..],
+        None => svg
+    }
+}
+
+// The program of a command that runs in `dir`. A relative path like
+// `./bin/tool` is taken from `dir`, a bare name like `dot` is looked up in the
+// `PATH`.
+pub fn program_path(dir: &path::Path, program: &str) -> path::PathBuf {
+    let program_path = path::Path::new(program);
+    if program_path.is_relative() && program_path.components().count() > 1 {
+        dir.join(program_path)
+    } else {
+        program_path.to_path_buf()
+    }
+}
+
+// The file of a program given as a path, like `./bin/tool` or `/opt/tool/bin/tool`.
+// None for a bare name, looked up in the `PATH`.
+pub fn program_file(dir: &path::Path, program: &str) -> Option<path::PathBuf> {
+    if path::Path::new(program).components().count() < 2 {
+        return None;
+    }
+
+    let program_path = program_path(dir, program);
+    Some(program_path.absolutize().map(|file| file.to_path_buf()).unwrap_or(program_path))
+}
+
+// The programs of the commands in `config` that are given as paths.
+pub fn program_files(config: &DiagramsConfig, dir: &path::Path) -> Vec<path::PathBuf> {
+    config.values()
+        .filter_map(DiagramCommand::program)
+        .filter_map(|program| program_file(dir, &program))
+        .collect()
+}
+
+// The name of a cached diagram: the hash of how it is drawn and of its code.
+pub fn cache_name(settings: &str, code: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(settings);
+    hasher.update([0]);
+    hasher.update(code);
+
+    hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// Why a diagram could not be drawn, as told by the tool, and the line of the
+// diagram it points at, if any.
+#[derive(Clone, Debug)]
+pub struct DiagramError {
+    pub message: String,
+    pub line: Option<usize>
+}
+
+impl DiagramError {
+    // Keeps what the tool says about the diagram, without JavaScript stack traces
+    // nor mermaid's excerpt of the code.
+    pub fn from_output(output: &str) -> DiagramError {
+        let lines: Vec<&str> = output.lines()
+            .map(str::trim_end)
+            .take_while(|line| !line.trim_start().starts_with("at "))
+            .collect();
+
+        let mut message = vec![];
+        for (index, line) in lines.iter().enumerate() {
+            let is_excerpt = lines.get(index + 1).is_some_and(|next| is_pointer(next));
+            if !line.trim().is_empty() && !is_pointer(line) && !is_excerpt {
+                message.push(line.trim().trim_start_matches("Error: "));
+            }
+        }
+        let message = match message.join(" ") {
+            message if message.is_empty() => "no se dieron más detalles".to_string(),
+            message => message
+        };
+
+        // "Parse error on line 3", "syntax error in line 3 near '->'"...
+        let line = message.match_indices("line ")
+            .filter_map(|(start, _)| {
+                let rest = &message[start + "line ".len()..];
+                rest.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+            })
+            .next();
+
+        DiagramError { message, line }
+    }
+}
+
+// The `-----^` line mermaid puts under its excerpt of the failing code.
+fn is_pointer(line: &str) -> bool {
+    let line = line.trim();
+    line.ends_with('^') && line.trim_end_matches('^').chars().all(|c| c == '-')
+}
+
+// The image of a diagram as markup, or why it couldn't be drawn.
+pub type DiagramResult = Result<String, DiagramError>;
+
+// Draws the code blocks of the languages in `diagrams` with their commands,
+// going to the cache first.
+pub struct Diagrams<'a> {
+    config: &'a DiagramsConfig,
+    // Where the commands run.
+    dir: path::PathBuf,
+    cache_dir: path::PathBuf,
+    // The hash of the program of each language when it is a file, so that
+    // changing the tool draws its diagrams again.
+    programs: BTreeMap<&'a str, String>
+}
+
+impl<'a> Diagrams<'a> {
+    pub fn new(config: &'a DiagramsConfig, dir: &path::Path, cache_dir: &path::Path) -> Diagrams<'a> {
+        let programs = config.iter()
+            .map(|(language, command)| {
+                let program = command.program().and_then(|program| program_file(dir, &program));
+                (language.as_str(), program.and_then(|program| manifest::hash_file(&DiskFs, &program)).unwrap_or_default())
+            })
+            .collect();
+
+        Diagrams { config, dir: dir.to_path_buf(), cache_dir: cache_dir.to_path_buf(), programs }
+    }
+
+    pub fn handles(&self, language: &str) -> bool {
+        self.config.contains_key(language)
+    }
+
+    // Fails as a whole when the command itself can't run.
+    pub fn render(&self, language: &str, code: &str) -> Result<DiagramResult> {
+        let command = self.config.get(language)
+            .with_context(|| format!("No hay un comando para los diagramas `{language}`"))?;
+        let format = command.format.unwrap_or_default();
+
+        let settings = serde_json::json!({
+            "command": command.command,
+            "format": format,
+            "program": self.programs.get(language)
+        }).to_string();
+        let cache_path = self.cache_dir.join(format!("{}.{}", cache_name(&settings, code), format.extension()));
+        let image = match fs::read(&cache_path) {
+            Ok(image) => image,
+            Err(_) => {
+                println!("[INFO] Generating {language} diagram");
+                let image = match self.run(language, command, code)? {
+                    Ok(image) => image,
+                    Err(err) => return Ok(Err(err))
+                };
+
+                fs::create_dir_all(&self.cache_dir).unwrap_or(());
+                fs::write(&cache_path, &image).unwrap_or_else(|_| {
+                    println!("[INFO] No se pudo guardar el diagrama en la caché: {}", cache_path.display())
+                });
+                image
+            }
+        };
+
+        Ok(Ok(match format {
+            DiagramFormat::Svg => inline_svg(&String::from_utf8_lossy(&image)).to_string(),
+            DiagramFormat::Png => format!(
+                "<img src='data:image/png;base64,{}' alt=''>",
+                base64::engine::general_purpose::STANDARD.encode(&image)
+            )
+        }))
+    }
+
+    fn run(&self, language: &str, command: &DiagramCommand, code: &str) -> Result<Result<Vec<u8>, DiagramError>> {
+        let format = command.format.unwrap_or_default();
+        let dir = tempfile::tempdir()?;
+        let input_path = dir.path().join(format!("diagram.{language}"));
+        let output_path = dir.path().join(format!("diagram.{}", format.extension()));
+
+        let words = highlight::split_info(&command.command)
+            .map_err(|err| anyhow::anyhow!("El comando `{}` es inválido: {err}", command.command))?;
+        let uses_input = words.iter().any(|word| word.contains("{input}"));
+        let uses_output = words.iter().any(|word| word.contains("{output}"));
+        let words: Vec<String> = words.iter()
+            .map(|word| word
+                .replace("{input}", &input_path.to_string_lossy())
+                .replace("{output}", &output_path.to_string_lossy()))
+            .collect();
+        let (program, args) = words.split_first()
+            .with_context(|| format!("El comando de los diagramas `{language}` está vacío"))?;
+
+        if uses_input {
+            fs::write(&input_path, code)?;
+        }
+        let mut child = Command::new(program_path(&self.dir, program))
+            .args(args)
+            .current_dir(&self.dir)
+            .stdin(if uses_input { Stdio::null() } else { Stdio::piped() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => anyhow::anyhow!("No se encontró `{program}`, indicado en `diagrams.{language}`"),
+                _ => anyhow::anyhow!("No se pudo ejecutar `{program}`: {err}")
+            })?;
+
+        // Written from another thread, so that a tool that starts writing the image
+        // before reading all the code doesn't block.
+        let writer = child.stdin.take().map(|mut stdin| {
+            let code = code.to_string();
+            std::thread::spawn(move || stdin.write_all(code.as_bytes()))
+        });
+        let output = child.wait_with_output()?;
+        if let Some(writer) = writer {
+            writer.join().unwrap_or(Ok(())).unwrap_or(());
+        }
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let details = if stderr.trim().is_empty() { String::from_utf8_lossy(&output.stdout) } else { stderr };
+            return Ok(Err(DiagramError::from_output(&details)));
+        }
+
+        let image = if uses_output {
+            fs::read(&output_path).with_context(|| format!("`{program}` no generó {}", output_path.display()))?
+        } else {
+            output.stdout
+        };
+        if image.is_empty() {
+            return Ok(Err(DiagramError { message: format!("`{program}` no generó ninguna imagen"), line: None }));
+        }
+
+        Ok(Ok(image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn programs_given_as_paths_are_files() {
+        let dir = path::Path::new("/proyectos/tesis");
+
+        assert_eq!(program_file(dir, "dot"), None);
+        assert_eq!(program_file(dir, "./bin/d2"), Some(path::PathBuf::from("/proyectos/tesis/bin/d2")));
+        assert_eq!(program_file(dir, "../herramientas/plantuml"), Some(path::PathBuf::from("/proyectos/herramientas/plantuml")));
+        assert_eq!(program_file(dir, "/opt/ditaa/bin/ditaa"), Some(path::PathBuf::from("/opt/ditaa/bin/ditaa")));
+        assert_eq!(program_path(dir, "dot"), path::PathBuf::from("dot"));
+    }
+
+    #[test]
+    fn cache_names_hash_the_settings_and_the_code() {
+        let name = cache_name("dot -Tsvg", "digraph { a -> b }");
+
+        assert_eq!(name.len(), 64);
+        assert!(name.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(name, cache_name("dot -Tsvg", "digraph { a -> b }"));
+        assert_ne!(name, cache_name("dot -Tpng", "digraph { a -> b }"));
+        assert_ne!(name, cache_name("dot -Tsvg", "digraph { a -> c }"));
+        // The settings end where the code starts.
+        assert_ne!(cache_name("ab", "c"), cache_name("a", "bc"));
+    }
+
+    #[test]
+    fn errors_keep_what_the_tool_says() {
+        let error = DiagramError::from_output(concat!(
+            "Error: Parse error on line 2:\n",
+            "graph TD    A-->\n",
+            "----------------^\n",
+            "Expecting 'AMP', 'ALPHA', got 'EOF'\n",
+            "    at Parser.parseError (mermaid.js:1:2)\n",
+            "    at Parser.parse (mermaid.js:3:4)\n"
+        ));
+        assert_eq!(error.message, "Parse error on line 2: Expecting 'AMP', 'ALPHA', got 'EOF'");
+        assert_eq!(error.line, Some(2));
+
+        let error = DiagramError::from_output("Error: <stdin>: syntax error in line 3 near '->'\n");
+        assert_eq!(error.message, "<stdin>: syntax error in line 3 near '->'");
+        assert_eq!(error.line, Some(3));
+
+        let error = DiagramError::from_output("no se reconoce el diagrama\n");
+        assert_eq!(error.line, None);
+
+        let error = DiagramError::from_output("\n");
+        assert_eq!(error.message, "no se dieron más detalles");
+        assert_eq!(error.line, None);
+    }
+}
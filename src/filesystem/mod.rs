@@ -0,0 +1,451 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::{fs, io, path};
+use path_absolutize::Absolutize;
+
+// Where a build reads the project from: the markdown, the templates, the
+// bibliography and the assets. The build directory is always on disk.
+pub trait FileSystem: Send + Sync {
+    fn read(&self, file: &path::Path) -> io::Result<Vec<u8>>;
+
+    fn read_to_string(&self, file: &path::Path) -> io::Result<String> {
+        String::from_utf8(self.read(file)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn is_file(&self, file: &path::Path) -> bool;
+
+    // The absolute path of `file` with every `.`, `..` and link resolved, which
+    // tells whether two paths are the same file. Fails if it doesn't exist.
+    fn canonicalize(&self, file: &path::Path) -> io::Result<path::PathBuf>;
+
+    // Every file under `dir`, recursively and sorted.
+    fn files_in(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>>;
+
+    // Size and modification time of `file`, to tell that it hasn't changed
+    // without reading it. None if they aren't known.
+    fn stamp(&self, _file: &path::Path) -> Option<FileStamp> {
+        None
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    // Nanoseconds since the Unix epoch.
+    pub modified: u128
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskFs;
+
+impl FileSystem for DiskFs {
+    fn read(&self, file: &path::Path) -> io::Result<Vec<u8>> {
+        fs::read(file)
+    }
+
+    fn read_to_string(&self, file: &path::Path) -> io::Result<String> {
+        fs::read_to_string(file)
+    }
+
+    fn is_file(&self, file: &path::Path) -> bool {
+        file.is_file()
+    }
+
+    fn canonicalize(&self, file: &path::Path) -> io::Result<path::PathBuf> {
+        fs::canonicalize(file)
+    }
+
+    fn files_in(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry_path = entry?.path();
+            if entry_path.is_dir() {
+                files.extend(self.files_in(&entry_path)?);
+            } else {
+                files.push(entry_path);
+            }
+        }
+        files.sort();
+
+        Ok(files)
+    }
+
+    fn stamp(&self, file: &path::Path) -> Option<FileStamp> {
+        let metadata = fs::metadata(file).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+
+        Some(FileStamp { size: metadata.len(), modified: modified.as_nanos() })
+    }
+}
+
+fn not_found(file: &path::Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} no existe", file.display()))
+}
+
+// Files kept by their absolute path, without links. Directories exist as long as
+// there are files in them.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFs {
+    files: BTreeMap<path::PathBuf, Vec<u8>>
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    // Relative paths are taken from the root, `/`.
+    pub fn with_file(mut self, file: impl AsRef<path::Path>, contents: impl Into<Vec<u8>>) -> MemoryFs {
+        self.insert(file, contents);
+        self
+    }
+
+    pub fn insert(&mut self, file: impl AsRef<path::Path>, contents: impl Into<Vec<u8>>) {
+        self.files.insert(normalize(file.as_ref()), contents.into());
+    }
+
+    /* The files of a `.zip`, `.tar`, `.tar.gz` or `.tgz` archive, placed under
+    `root`: `capitulos/intro.md` in the archive becomes `{root}/capitulos/intro.md`.
+    Entries that would end up outside of `root` are left out. */
+    pub fn from_archive(archive: &path::Path, root: &path::Path) -> io::Result<MemoryFs> {
+        let mut files = MemoryFs::new();
+        let mut add = |name: &path::Path, contents: Vec<u8>| {
+            let inside = name.components().all(|component| matches!(component, path::Component::Normal(_) | path::Component::CurDir));
+            if inside {
+                files.insert(root.join(name), contents);
+            }
+        };
+
+        let reader = io::BufReader::new(fs::File::open(archive)?);
+        match ArchiveKind::of(archive) {
+            Some(ArchiveKind::Zip) => {
+                let mut zip = zip::ZipArchive::new(reader).map_err(io::Error::other)?;
+                for index in 0..zip.len() {
+                    let mut entry = zip.by_index(index).map_err(io::Error::other)?;
+                    let Some(name) = entry.enclosed_name().filter(|_| entry.is_file()) else {
+                        continue;
+                    };
+                    let mut contents = vec![];
+                    entry.read_to_end(&mut contents)?;
+                    add(&name, contents);
+                }
+            },
+            Some(ArchiveKind::Tar) => read_tar(tar::Archive::new(reader), &mut add)?,
+            Some(ArchiveKind::TarGz) => read_tar(tar::Archive::new(flate2::read::GzDecoder::new(reader)), &mut add)?,
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported, format!(
+                "{} no es un archivo comprimido, se esperaba `.zip`, `.tar`, `.tar.gz` o `.tgz`", archive.display()
+            )))
+        }
+
+        Ok(files)
+    }
+}
+
+fn read_tar<R: Read>(mut archive: tar::Archive<R>, add: &mut impl FnMut(&path::Path, Vec<u8>)) -> io::Result<()> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_path_buf();
+        let mut contents = vec![];
+        entry.read_to_end(&mut contents)?;
+        add(&name, contents);
+    }
+
+    Ok(())
+}
+
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz
+}
+
+impl ArchiveKind {
+    fn of(archive: &path::Path) -> Option<ArchiveKind> {
+        let name = archive.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_archive(file: &path::Path) -> bool {
+    ArchiveKind::of(file).is_some()
+}
+
+// The name of an archive without its extension, `tesis` for `tesis.tar.gz`.
+pub fn archive_stem(archive: &path::Path) -> String {
+    let name = archive.file_name().unwrap_or_default().to_string_lossy().to_string();
+    [".tar.gz", ".tgz", ".tar", ".zip"].iter()
+        .find_map(|extension| {
+            let cut = name.len().checked_sub(extension.len())?;
+            name.get(cut..).filter(|end| end.eq_ignore_ascii_case(extension)).map(|_| name[..cut].to_string())
+        })
+        .unwrap_or(name)
+}
+
+fn normalize(file: &path::Path) -> path::PathBuf {
+    file.absolutize_from(path::Path::new("/"))
+        .map(|file| file.to_path_buf())
+        .unwrap_or_else(|_| file.to_path_buf())
+}
+
+impl FileSystem for MemoryFs {
+    fn read(&self, file: &path::Path) -> io::Result<Vec<u8>> {
+        self.files.get(&normalize(file)).cloned().ok_or_else(|| not_found(file))
+    }
+
+    fn is_file(&self, file: &path::Path) -> bool {
+        self.files.contains_key(&normalize(file))
+    }
+
+    fn canonicalize(&self, file: &path::Path) -> io::Result<path::PathBuf> {
+        let file = normalize(file);
+        match self.files.contains_key(&file) {
+            true => Ok(file),
+            false => Err(not_found(&file))
+        }
+    }
+
+    fn files_in(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let dir = normalize(dir);
+        let files: Vec<path::PathBuf> = self.files.keys()
+            .filter(|file| file.starts_with(&dir) && **file != dir)
+            .cloned()
+            .collect();
+
+        match files.is_empty() {
+            true => Err(not_found(&dir)),
+            false => Ok(files)
+        }
+    }
+}
+
+// The files of `upper` over the ones of `lower`: a file in both is read from
+// `upper`. Used to build the unsaved buffers of an editor along with the rest of
+// the project on disk.
+#[derive(Clone)]
+pub struct OverlayFs {
+    upper: Arc<dyn FileSystem>,
+    lower: Arc<dyn FileSystem>
+}
+
+impl OverlayFs {
+    pub fn new(upper: Arc<dyn FileSystem>, lower: Arc<dyn FileSystem>) -> OverlayFs {
+        OverlayFs { upper, lower }
+    }
+}
+
+impl FileSystem for OverlayFs {
+    fn read(&self, file: &path::Path) -> io::Result<Vec<u8>> {
+        match self.upper.is_file(file) {
+            true => self.upper.read(file),
+            false => self.lower.read(file)
+        }
+    }
+
+    fn is_file(&self, file: &path::Path) -> bool {
+        self.upper.is_file(file) || self.lower.is_file(file)
+    }
+
+    // The same file must get the same path from either layer, so links are
+    // resolved by `lower` even for a file only in `upper`: its directory is, and
+    // the name is kept. Without that directory in `lower` it's up to `upper`.
+    fn canonicalize(&self, file: &path::Path) -> io::Result<path::PathBuf> {
+        if !self.upper.is_file(file) {
+            return self.lower.canonicalize(file);
+        }
+        if let Ok(canonical) = self.lower.canonicalize(file) {
+            return Ok(canonical);
+        }
+
+        match (file.parent(), file.file_name()) {
+            (Some(dir), Some(name)) => match self.lower.canonicalize(dir) {
+                Ok(dir) => Ok(dir.join(name)),
+                Err(_) => self.upper.canonicalize(file)
+            },
+            _ => self.upper.canonicalize(file)
+        }
+    }
+
+    fn files_in(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let (upper, lower) = (self.upper.files_in(dir), self.lower.files_in(dir));
+        if let (Err(_), Err(err)) = (&upper, lower.as_ref()) {
+            return Err(io::Error::new(err.kind(), err.to_string()));
+        }
+
+        let mut files: Vec<path::PathBuf> = upper.into_iter().chain(lower).flatten().collect();
+        files.sort();
+        files.dedup();
+
+        Ok(files)
+    }
+
+    fn stamp(&self, file: &path::Path) -> Option<FileStamp> {
+        match self.upper.is_file(file) {
+            true => self.upper.stamp(file),
+            false => self.lower.stamp(file)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Through a link to the project, a file on disk and an unsaved one next to it
+    // are both found in the project itself.
+    #[cfg(unix)]
+    #[test]
+    fn overlay_resolves_links_for_both_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("proyecto");
+        let link = dir.path().join("enlace");
+        fs::create_dir(&project).unwrap();
+        fs::write(project.join("guardado.md"), "en disco").unwrap();
+        fs::write(project.join("editado.md"), "en disco").unwrap();
+        std::os::unix::fs::symlink(&project, &link).unwrap();
+        let project = fs::canonicalize(&project).unwrap();
+
+        let upper = MemoryFs::new()
+            .with_file(link.join("editado.md"), "sin guardar")
+            .with_file(link.join("nuevo.md"), "sin guardar");
+        let overlay = OverlayFs::new(Arc::new(upper), Arc::new(DiskFs));
+
+        for name in ["guardado.md", "editado.md", "nuevo.md"] {
+            assert_eq!(overlay.canonicalize(&link.join(name)).unwrap(), project.join(name));
+        }
+        assert!(overlay.canonicalize(&link.join("falta.md")).is_err());
+    }
+
+    #[test]
+    fn memory_paths_are_normalized() {
+        let memory = MemoryFs::new()
+            .with_file("/doc/start.md", "inicio")
+            .with_file("doc/capitulos/uno.md", "uno");
+
+        assert_eq!(memory.read_to_string(path::Path::new("/doc/./capitulos/../start.md")).unwrap(), "inicio");
+        assert!(memory.is_file(path::Path::new("/doc/capitulos/uno.md")));
+        assert!(!memory.is_file(path::Path::new("/doc/capitulos")));
+        assert_eq!(memory.canonicalize(path::Path::new("/doc/capitulos/../start.md")).unwrap(), path::Path::new("/doc/start.md"));
+        assert!(memory.canonicalize(path::Path::new("/doc/falta.md")).is_err());
+        assert!(memory.read(path::Path::new("/doc/falta.md")).is_err());
+    }
+
+    #[test]
+    fn memory_lists_files_under_a_directory() {
+        let memory = MemoryFs::new()
+            .with_file("/doc/assets/b.png", "b")
+            .with_file("/doc/assets/css/a.css", "a")
+            .with_file("/doc/assets.md", "no");
+
+        assert_eq!(memory.files_in(path::Path::new("/doc/assets")).unwrap(), vec![
+            path::PathBuf::from("/doc/assets/b.png"),
+            path::PathBuf::from("/doc/assets/css/a.css")
+        ]);
+        assert!(memory.files_in(path::Path::new("/doc/imagenes")).is_err());
+    }
+
+    #[test]
+    fn overlay_shadows_lower_files() {
+        let lower = MemoryFs::new()
+            .with_file("/doc/start.md", "guardado")
+            .with_file("/doc/assets/a.css", "a");
+        let upper = MemoryFs::new()
+            .with_file("/doc/start.md", "sin guardar")
+            .with_file("/doc/assets/b.css", "b");
+        let overlay = OverlayFs::new(Arc::new(upper), Arc::new(lower));
+
+        assert_eq!(overlay.read_to_string(path::Path::new("/doc/start.md")).unwrap(), "sin guardar");
+        assert_eq!(overlay.read_to_string(path::Path::new("/doc/assets/a.css")).unwrap(), "a");
+        assert!(overlay.read(path::Path::new("/doc/falta.md")).is_err());
+        assert!(!overlay.is_file(path::Path::new("/doc/falta.md")));
+        assert_eq!(overlay.files_in(path::Path::new("/doc")).unwrap(), vec![
+            path::PathBuf::from("/doc/assets/a.css"),
+            path::PathBuf::from("/doc/assets/b.css"),
+            path::PathBuf::from("/doc/start.md")
+        ]);
+        assert!(overlay.files_in(path::Path::new("/otro")).is_err());
+    }
+
+    // The entries of every archive in these tests, `../fuera.md` aside.
+    fn expected_files(root: &path::Path) -> Vec<path::PathBuf> {
+        vec![root.join("project.thn"), root.join("sections/intro.md")]
+    }
+
+    fn write_tar<W: io::Write>(writer: W) -> W {
+        let mut builder = tar::Builder::new(writer);
+        for (name, contents) in [("project.thn", "{}"), ("sections/intro.md", "# Intro")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, contents.as_bytes()).unwrap();
+        }
+        // `append_data` refuses `..`, so the name is written on its own.
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..11].copy_from_slice(b"../fuera.md");
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, "fuera".as_bytes()).unwrap();
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn reads_zip_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("tesis.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, contents) in [("project.thn", "{}"), ("sections/intro.md", "# Intro"), ("../fuera.md", "fuera")] {
+            zip.start_file(name, options).unwrap();
+            io::Write::write_all(&mut zip, contents.as_bytes()).unwrap();
+        }
+        zip.add_directory("vacio/", options).unwrap();
+        zip.finish().unwrap();
+
+        let root = dir.path().join("tesis");
+        let files = MemoryFs::from_archive(&archive, &root).unwrap();
+
+        assert_eq!(files.files_in(dir.path()).unwrap(), expected_files(&root));
+        assert_eq!(files.read_to_string(&root.join("sections/intro.md")).unwrap(), "# Intro");
+    }
+
+    #[test]
+    fn reads_tar_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar = dir.path().join("tesis.tar");
+        write_tar(fs::File::create(&tar).unwrap());
+        let tar_gz = dir.path().join("tesis.tar.gz");
+        write_tar(flate2::write::GzEncoder::new(fs::File::create(&tar_gz).unwrap(), flate2::Compression::default()))
+            .finish()
+            .unwrap();
+
+        let root = dir.path().join("tesis");
+        for archive in [tar, tar_gz] {
+            let files = MemoryFs::from_archive(&archive, &root).unwrap();
+
+            assert_eq!(files.files_in(dir.path()).unwrap(), expected_files(&root));
+            assert_eq!(files.read_to_string(&root.join("project.thn")).unwrap(), "{}");
+        }
+    }
+
+    #[test]
+    fn archive_names() {
+        assert!(is_archive(path::Path::new("tesis.TGZ")));
+        assert!(!is_archive(path::Path::new("tesis.md")));
+        assert_eq!(archive_stem(path::Path::new("/docs/tesis.tar.gz")), "tesis");
+        assert_eq!(archive_stem(path::Path::new("tesis.ZIP")), "tesis");
+        assert!(MemoryFs::from_archive(path::Path::new("tesis.rar"), path::Path::new("/tesis")).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::path;
+use serde_json::{Map, Value};
+
+use crate::diagnostics::Diagnostic;
+
+// A `---` in the first line of the entry point starts a YAML block that ends with
+// the next `---` line:
+//
+// ---
+// title: Sistemas distribuidos
+// authors: [Ana Pérez, Luis Gómez]
+// ---
+//
+// The block is front matter only when it is empty or its first line, comments
+// aside, is a `key:`. Otherwise the `---` is a thematic break of the markdown,
+// as in a document that starts with one and has no front matter.
+pub static DELIMITER: &str = "---";
+
+pub type Variables = Map<String, Value>;
+
+// Whether what is between the delimiters is meant as front matter.
+fn is_front_matter(block: &[&str]) -> bool {
+    let first = block.iter()
+        .map(|line| line.trim_end())
+        .find(|line| !line.trim_start().is_empty() && !line.trim_start().starts_with('#'));
+    let Some(first) = first else {
+        return block.iter().all(|line| line.trim().is_empty());
+    };
+
+    !first.starts_with(char::is_whitespace)
+        && first.split_once(':').is_some_and(|(key, value)| !key.is_empty() && (value.is_empty() || value.starts_with(' ')))
+}
+
+// Splits the front matter from the markdown. The front matter lines are left
+// blank, so that the lines of the document keep their numbers.
+pub fn split(markdown: &str, file: &path::Path) -> Result<(Variables, String), Diagnostic> {
+    let mut lines = markdown.lines();
+    if lines.next().map(str::trim_end) != Some(DELIMITER) {
+        return Ok((Variables::new(), markdown.to_string()));
+    }
+
+    let block = lines.take_while(|line| line.trim_end() != DELIMITER).collect::<Vec<_>>();
+    let length = block.len();
+    if !is_front_matter(&block) {
+        return Ok((Variables::new(), markdown.to_string()));
+    }
+    if markdown.lines().nth(length + 1).is_none() {
+        return Err(Diagnostic::error("falta el `---` que cierra el front matter").in_file(file).at(1, 1));
+    }
+
+    let yaml = block.join("\n");
+    let value: Value = if yaml.trim().is_empty() {
+        Value::Object(Variables::new())
+    } else {
+        serde_yaml::from_str(&yaml).map_err(|err| {
+            let diagnostic = Diagnostic::error(format!("front matter inválido: {err}")).in_file(file);
+            match err.location() {
+                Some(location) => diagnostic.at(location.line() + 1, location.column()),
+                None => diagnostic
+            }
+        })?
+    };
+
+    let Value::Object(variables) = value else {
+        return Err(Diagnostic::error("el front matter debe ser un mapa de `clave: valor`").in_file(file).at(2, 1));
+    };
+
+    // Both delimiters and everything in between.
+    let blank = "\n".repeat(length + 2);
+    let rest = markdown.lines().skip(length + 2).collect::<Vec<_>>().join("\n");
+
+    Ok((variables, blank + &rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_start(markdown: &str) -> Result<(Variables, String), Diagnostic> {
+        split(markdown, path::Path::new("/doc/start.md"))
+    }
+
+    #[test]
+    fn front_matter_lines_are_left_blank() {
+        let (variables, markdown) = split_start("---\n# Datos\ntitle: Tesis\nautores: [Ana]\n---\n# Inicio\nTexto").unwrap();
+
+        assert_eq!(variables.get("title"), Some(&Value::from("Tesis")));
+        assert_eq!(variables.get("autores"), Some(&serde_json::json!(["Ana"])));
+        assert_eq!(markdown, "\n\n\n\n\n# Inicio\nTexto");
+    }
+
+    #[test]
+    fn empty_front_matter() {
+        let (variables, markdown) = split_start("---\n\n---\nTexto").unwrap();
+
+        assert!(variables.is_empty());
+        assert_eq!(markdown, "\n\n\nTexto");
+    }
+
+    #[test]
+    fn leading_thematic_breaks_are_markdown() {
+        for markdown in [
+            "---\n# Inicio\nTexto",
+            "---\n\n# Inicio\n\n---\n\nMás texto",
+            "---\nUn párrafo, no un mapa\n---",
+            "Texto\n---\ntitle: no es front matter\n---"
+        ] {
+            assert_eq!(split_start(markdown).unwrap(), (Variables::new(), markdown.to_string()));
+        }
+    }
+
+    #[test]
+    fn unclosed_front_matter_is_an_error() {
+        let err = split_start("---\ntitle: Tesis\n# Inicio").unwrap_err();
+
+        assert_eq!(err.message, "falta el `---` que cierra el front matter");
+        assert_eq!(err.line, Some(1));
+    }
+
+    #[test]
+    fn invalid_yaml_is_an_error_where_it_fails() {
+        let err = split_start("---\ntitle: Tesis\nautores: [Ana\n---\nTexto").unwrap_err();
+
+        assert!(err.message.starts_with("front matter inválido"), "{}", err.message);
+        assert_eq!(err.file.as_deref(), Some(path::Path::new("/doc/start.md")));
+        assert!(err.line.is_some_and(|line| line > 1));
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path;
+use std::sync::Arc;
+use minijinja::{Environment, Value};
+
+use crate::diagnostics::Diagnostic;
+use crate::filesystem::FileSystem;
+use crate::html_generation;
+use crate::metadata::Metadata;
+use crate::pdf_exporter::Margins;
+use crate::toc::Heading;
+
+// Placed alone in a line, marks where the main matter starts. The pages before it
+// are the front matter, numbered apart.
+pub static MAINMATTER_DIRECTIVE: &str = "@mainmatter";
+pub static MAINMATTER_ID: &str = "mainmatter";
+
+pub fn mainmatter_html() -> String {
+    format!("<div id='{MAINMATTER_ID}' class='mainmatter'></div>\n")
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrontMatterNumbering {
+    #[default]
+    Roman,
+    Arabic,
+    None
+}
+
+/* The `page_numbers` entry of the `pdf` section, all of it optional:
+{
+    "page_numbers": {
+        "cover_pages": 1,
+        "front_matter": "roman",
+        "offset": 0
+    }
+}
+
+The first `cover_pages` pages (0 by default) have no header nor footer. The
+pages before the `@mainmatter` directive are the front matter: with "roman"
+they are numbered i, ii, iii... counting from the first page, with "none"
+they have no header nor footer either, and with "arabic" they are numbered like
+the rest. The main matter is numbered from 1 where `@mainmatter` is, or from
+the first page without it, plus `offset`. Pages whose number ends up below 1
+are not decorated, so an `offset` of -1 leaves the cover alone. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PageNumbering {
+    cover_pages: Option<u32>,
+    front_matter: Option<FrontMatterNumbering>,
+    offset: Option<i32>
+}
+
+// The number shown on a page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageLabel {
+    pub text: String,
+    pub number: i64,
+    pub front_matter: bool
+}
+
+fn roman(mut number: u32) -> String {
+    static NUMERALS: &[(u32, &str)] = &[
+        (1000, "m"), (900, "cm"), (500, "d"), (400, "cd"), (100, "c"), (90, "xc"),
+        (50, "l"), (40, "xl"), (10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i")
+    ];
+
+    let mut result = String::new();
+    for (value, numeral) in NUMERALS {
+        while number >= *value {
+            result.push_str(numeral);
+            number -= value;
+        }
+    }
+
+    result
+}
+
+impl PageNumbering {
+    // The label of each of the `page_count` pages, `None` for the pages without
+    // header and footer. `mainmatter` is the page where `@mainmatter` landed.
+    pub fn labels(&self, page_count: u32, mainmatter: Option<u32>) -> Vec<Option<PageLabel>> {
+        let cover_pages = self.cover_pages.unwrap_or(0);
+        let offset = self.offset.unwrap_or(0) as i64;
+        let front_matter = self.front_matter.unwrap_or_default();
+
+        (1..=page_count)
+            .map(|page| {
+                if page <= cover_pages {
+                    return None;
+                }
+
+                let in_front_matter = mainmatter.is_some_and(|start| page < start);
+                let number = match (in_front_matter, front_matter, mainmatter) {
+                    (true, FrontMatterNumbering::None, _) => return None,
+                    (true, FrontMatterNumbering::Roman, _) => return Some(PageLabel {
+                        text: roman(page),
+                        number: page as i64,
+                        front_matter: true
+                    }),
+                    (_, FrontMatterNumbering::Arabic, _) | (_, _, None) => page as i64 + offset,
+                    (false, _, Some(start)) => (page - start) as i64 + 1 + offset
+                };
+
+                (number >= 1).then(|| PageLabel { text: number.to_string(), number, front_matter: in_front_matter })
+            })
+            .collect()
+    }
+}
+
+// The label of each page of the PDF. `printed` is the page of the document each
+// of them is, from `pdf_exporter::printed_pages`, and `pages` the pages of the
+// PDF where the headings and `@mainmatter` landed.
+pub fn page_labels(numbering: &PageNumbering, printed: &[u32], pages: &HashMap<String, u32>) -> Vec<Option<PageLabel>> {
+    let document_page = |page: u32| page.checked_sub(1).and_then(|index| printed.get(index as usize)).copied();
+    let mainmatter = pages.get(MAINMATTER_ID).and_then(|page| document_page(*page));
+    let labels = numbering.labels(printed.iter().max().copied().unwrap_or(0), mainmatter);
+
+    printed.iter().map(|page| labels[*page as usize - 1].clone()).collect()
+}
+
+// The label of the page each id in `pages` landed on, for the ones that have one.
+pub fn label_texts(pages: &HashMap<String, u32>, labels: &[Option<PageLabel>]) -> HashMap<String, String> {
+    pages.iter()
+        .filter_map(|(id, page)| {
+            let label = labels.get(page.checked_sub(1)? as usize)?.as_ref()?;
+            Some((id.clone(), label.text.clone()))
+        })
+        .collect()
+}
+
+// A header or footer template, loaded once and rendered for every page.
+pub struct PageTemplate {
+    env: Environment<'static>,
+    name: String,
+    dir: path::PathBuf
+}
+
+impl PageTemplate {
+    pub fn load(filesystem: &Arc<dyn FileSystem>, template_path: &path::Path) -> Result<PageTemplate, Box<dyn std::error::Error>> {
+        if !filesystem.is_file(template_path) {
+            return Err(Box::new(Diagnostic::error("No se pudo leer la plantilla: el archivo no existe").in_file(template_path)));
+        }
+
+        let dir = template_path.parent().unwrap_or(path::Path::new(".")).to_path_buf();
+        let (env, _) = html_generation::template_environment(filesystem.clone(), &dir)?;
+        Ok(PageTemplate {
+            env,
+            name: template_path.file_name().and_then(|name| name.to_str()).unwrap_or("").to_string(),
+            dir
+        })
+    }
+
+    fn render(&self, values: &BTreeMap<&str, Value>) -> Result<String, Diagnostic> {
+        self.env.get_template(&self.name)
+            .and_then(|template| template.render(values))
+            .map_err(|err| html_generation::template_error(&err, &self.dir))
+    }
+}
+
+// The size of the printed pages, in points.
+pub struct PageLayout {
+    pub width: f64,
+    pub height: f64,
+    pub margins: Margins
+}
+
+/* An HTML document with one page per page of the PDF, holding only its header
+and footer, placed in the top and bottom margins. Printed and laid over the
+document by `pdf_exporter::stamp_pages`. The templates see:
+
+- `page`: the page number as shown ("3", "iv").
+- `page_number`: the same as a number, and `front_matter`, true in the front matter.
+- `total_pages`: the number of the last page of the main matter.
+- `chapter` and `chapter_number`: the chapter the page is in, if any.
+- `title` and `metadata`: the document metadata.
+
+`labels` has the label of every page, from `page_labels`, and `pages` the pages
+where the headings landed. */
+pub fn overlay_html(
+    header: Option<&PageTemplate>,
+    footer: Option<&PageTemplate>,
+    labels: &[Option<PageLabel>],
+    layout: &PageLayout,
+    headings: &[Heading],
+    pages: &HashMap<String, u32>,
+    metadata: &Metadata
+) -> Result<String, Box<dyn std::error::Error>> {
+    let total_pages = labels.iter().flatten()
+        .filter(|label| !label.front_matter)
+        .map(|label| label.number)
+        .max()
+        .unwrap_or(0);
+
+    // Chapters by the page they start on.
+    let base_level = headings.iter().map(|heading| heading.level).min().unwrap_or(1);
+    let mut chapters: Vec<(u32, &Heading)> = headings.iter()
+        .filter(|heading| heading.level == base_level)
+        .filter_map(|heading| pages.get(&heading.id).map(|page| (*page, heading)))
+        .collect();
+    chapters.sort_by_key(|(page, _)| *page);
+
+    let (width, height) = (layout.width, layout.height);
+    let margins = &layout.margins;
+    let mut html = format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset='UTF-8'>
+<style>
+@page {{ size: {width}pt {height}pt; margin: 0; }}
+html, body {{ margin: 0; padding: 0; background: transparent; }}
+.page {{ position: relative; width: {width}pt; height: {height}pt; overflow: hidden; }}
+.page + .page {{ break-before: page; }}
+.page-header, .page-footer {{ position: absolute; left: {}in; right: {}in; display: flex; flex-direction: column; justify-content: center; }}
+.page-header {{ top: 0; height: {}in; }}
+.page-footer {{ bottom: 0; height: {}in; }}
+</style>
+</head>
+<body>
+",
+        margins.left.inches(), margins.right.inches(), margins.top.inches(), margins.bottom.inches()
+    );
+
+    for (index, label) in labels.iter().enumerate() {
+        let page = index as u32 + 1;
+        html.push_str("<div class='page'>");
+
+        if let Some(label) = label {
+            let chapter = chapters.iter().rev().find(|(start, _)| *start <= page).map(|(_, heading)| *heading);
+            let values: BTreeMap<&str, Value> = BTreeMap::from([
+                ("page", Value::from(label.text.clone())),
+                ("page_number", Value::from(label.number)),
+                ("front_matter", Value::from(label.front_matter)),
+                ("total_pages", Value::from(total_pages)),
+                ("chapter", Value::from(chapter.map(|heading| heading.text.clone()))),
+                ("chapter_number", Value::from(chapter.map(|heading| heading.number.clone()))),
+                ("title", Value::from(metadata.title.clone())),
+                ("metadata", Value::from_serialize(metadata))
+            ]);
+
+            if let Some(header) = header {
+                html.push_str(&format!("<div class='page-header'>{}</div>", header.render(&values)?));
+            }
+            if let Some(footer) = footer {
+                html.push_str(&format!("<div class='page-footer'>{}</div>", footer.render(&values)?));
+            }
+        }
+
+        html.push_str("</div>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    Ok(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbering() -> PageNumbering {
+        PageNumbering { cover_pages: Some(1), front_matter: Some(FrontMatterNumbering::Roman), offset: None }
+    }
+
+    fn texts(labels: &[Option<PageLabel>]) -> Vec<Option<&str>> {
+        labels.iter().map(|label| label.as_ref().map(|label| label.text.as_str())).collect()
+    }
+
+    #[test]
+    fn labels_follow_the_main_matter() {
+        let pages = HashMap::from([(MAINMATTER_ID.to_string(), 3)]);
+        let labels = page_labels(&numbering(), &[1, 2, 3, 4, 5], &pages);
+
+        assert_eq!(texts(&labels), [None, Some("ii"), Some("1"), Some("2"), Some("3")]);
+    }
+
+    #[test]
+    fn labels_count_the_pages_left_out() {
+        // Only pages 2 to 5 of the document are printed, so the main matter starts
+        // on the second page of the PDF.
+        let pages = HashMap::from([(MAINMATTER_ID.to_string(), 2)]);
+        let labels = page_labels(&numbering(), &[2, 3, 4, 5], &pages);
+
+        assert_eq!(texts(&labels), [Some("ii"), Some("1"), Some("2"), Some("3")]);
+    }
+
+    #[test]
+    fn label_texts_skip_pages_without_label() {
+        let pages = HashMap::from([
+            ("portada".to_string(), 1),
+            ("resumen".to_string(), 2),
+            ("intro".to_string(), 3),
+            (MAINMATTER_ID.to_string(), 3)
+        ]);
+        let labels = page_labels(&numbering(), &[1, 2, 3], &pages);
+        let texts = label_texts(&pages, &labels);
+
+        assert_eq!(texts.get("portada"), None);
+        assert_eq!(texts.get("resumen").map(String::as_str), Some("ii"));
+        assert_eq!(texts.get("intro").map(String::as_str), Some("1"));
+    }
+}
@@ -0,0 +1,290 @@
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::SyntaxSet;
+
+use crate::html_generation;
+
+/* The `highlight` section of `project.thn`, all of it optional:
+{
+    "highlight": {
+        "enabled": true,
+        "theme": "InspiredGitHub",
+        "line_numbers": false
+    }
+}
+
+`theme` is one of the themes bundled with syntect: "InspiredGitHub",
+"Solarized (light)", "Solarized (dark)", "base16-ocean.light",
+"base16-ocean.dark", "base16-eighties.dark" or "base16-mocha.dark".
+`line_numbers` is the default for blocks that don't say otherwise. With
+`enabled` set to false code is not colored, but line numbers, highlighted lines
+and captions still work.
+
+Each fenced block can set its own options after the language:
+
+```rust numbers highlight=2,4-6 caption="Cálculo del total"
+```
+
+`numbers` (or `numbers=false`) shows or hides line numbers, `highlight` marks
+lines and `caption` adds a caption below the block. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct HighlightConfig {
+    enabled: Option<bool>,
+    theme: Option<String>,
+    line_numbers: Option<bool>
+}
+
+impl HighlightConfig {
+    fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    fn theme(&self) -> &str {
+        self.theme.as_deref().unwrap_or("InspiredGitHub")
+    }
+}
+
+// What the info string of a fenced block asks for.
+struct BlockOptions {
+    language: String,
+    line_numbers: bool,
+    highlighted: Vec<RangeInclusive<usize>>,
+    caption: Option<String>
+}
+
+// Splits an info string in words, keeping quoted values together:
+// `rust caption="Un ejemplo"` is `rust` and `caption=Un ejemplo`.
+pub fn split_info(info: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+    for c in info.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            },
+            c => word.push(c)
+        }
+    }
+    if quoted {
+        return Err("falta cerrar las comillas".to_string());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+// "2,4-6" as the lines 2 and 4 to 6.
+fn parse_ranges(ranges: &str) -> Result<Vec<RangeInclusive<usize>>, String> {
+    let parse = |line: &str| line.trim().parse::<usize>().map_err(|_| format!("`{ranges}` no es una lista de líneas válida"));
+
+    ranges.split(',')
+        .map(|range| match range.split_once('-') {
+            Some((start, end)) => Ok(parse(start)?..=parse(end)?),
+            None => parse(range).map(|line| line..=line)
+        })
+        .collect()
+}
+
+fn parse_info(info: &str, config: &HighlightConfig) -> Result<BlockOptions, String> {
+    let mut words = split_info(info)?.into_iter().peekable();
+    let language = match words.peek() {
+        Some(word) if !word.contains('=') && word != "numbers" => words.next().unwrap_or_default(),
+        _ => String::new()
+    };
+
+    let mut options = BlockOptions {
+        language,
+        line_numbers: config.line_numbers.unwrap_or(false),
+        highlighted: vec![],
+        caption: None
+    };
+    for word in words {
+        match word.split_once('=') {
+            None if word == "numbers" => options.line_numbers = true,
+            Some(("numbers", value)) => {
+                options.line_numbers = value.parse().map_err(|_| format!("`numbers` debe ser true o false, no `{value}`"))?;
+            },
+            Some(("highlight", ranges)) => options.highlighted = parse_ranges(ranges)?,
+            Some(("caption", caption)) => options.caption = Some(caption.to_string()),
+            _ => return Err(format!("opción desconocida para el bloque de código: `{word}`"))
+        }
+    }
+
+    Ok(options)
+}
+
+fn css_color(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+// Loading the grammars and the themes takes a moment, so it is done once, the
+// first time a document has code, and shared by every later render.
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_nonewlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+// Highlights fenced code blocks. Created only for documents that have code.
+pub struct Highlighter {
+    config: HighlightConfig,
+    syntaxes: &'static SyntaxSet,
+    theme: &'static Theme
+}
+
+impl Highlighter {
+    pub fn new(config: &HighlightConfig) -> Result<Highlighter, String> {
+        let themes = &themes().themes;
+        let theme = match themes.get(config.theme()) {
+            Some(theme) => theme,
+            None => {
+                let mut available = themes.keys().map(|name| format!("\"{name}\"")).collect::<Vec<_>>();
+                available.sort();
+                return Err(format!("el tema `{}` no existe, los disponibles son {}", config.theme(), available.join(", ")));
+            }
+        };
+
+        Ok(Highlighter {
+            config: config.clone(),
+            syntaxes: syntaxes(),
+            theme
+        })
+    }
+
+    /* Renders a fenced block with the options in its info string. Colors are
+    inline so that they survive printing:
+
+    <pre class='highlight' style='...'><code class='language-rust'>
+        <span class='code-line highlighted' style='...'><span class='line-number' style='...'>1</span>...</span>
+    </code></pre>
+    <div class='code-caption'>Cálculo del total</div> */
+    pub fn code_block_html(&self, info: &str, code: &str) -> Result<String, String> {
+        let options = parse_info(info, &self.config)?;
+
+        let syntax = self.syntaxes.find_syntax_by_token(&options.language)
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text());
+        let mut lines = HighlightLines::new(syntax, self.theme);
+
+        let background = self.theme.settings.background.map(css_color).unwrap_or("#ffffff".to_string());
+        let foreground = self.theme.settings.foreground.map(css_color).unwrap_or("#000000".to_string());
+        let line_highlight = self.theme.settings.line_highlight.map(css_color).unwrap_or("#fff5b1".to_string());
+        let number_width = code.lines().count().to_string().len();
+
+        let language_class = if options.language.is_empty() {
+            String::new()
+        } else {
+            format!(" class='language-{}'", html_generation::escape_attribute(&options.language))
+        };
+        let mut html = format!(
+            "<pre class='highlight' style='background-color: {background}; color: {foreground}; -webkit-print-color-adjust: exact; print-color-adjust: exact;'><code{language_class}>"
+        );
+        for (index, line) in code.lines().enumerate() {
+            let number = index + 1;
+            let highlighted = options.highlighted.iter().any(|range| range.contains(&number));
+            if highlighted {
+                html.push_str(&format!("<span class='code-line highlighted' style='display: inline-block; width: 100%; background-color: {line_highlight};'>"));
+            } else {
+                html.push_str("<span class='code-line' style='display: inline-block; width: 100%;'>");
+            }
+
+            if options.line_numbers {
+                html.push_str(&format!(
+                    "<span class='line-number' style='display: inline-block; min-width: {number_width}ch; margin-right: 1em; text-align: right; opacity: 0.5; user-select: none;'>{number}</span>"
+                ));
+            }
+
+            if self.config.enabled() {
+                let regions = lines.highlight_line(line, self.syntaxes).map_err(|err| err.to_string())?;
+                html.push_str(&styled_line_to_highlighted_html(&regions, IncludeBackground::No).map_err(|err| err.to_string())?);
+            } else {
+                html.push_str(&html_generation::escape_text(line));
+            }
+            html.push_str("</span>\n");
+        }
+        html.push_str("</code></pre>");
+
+        if let Some(caption) = &options.caption {
+            html.push_str(&format!("<div class='code-caption'>{}</div>", html_generation::escape_text(caption)));
+        }
+
+        Ok(html)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlighters_share_the_loaded_sets() {
+        let config = HighlightConfig::default();
+        let (first, second) = (Highlighter::new(&config).unwrap(), Highlighter::new(&config).unwrap());
+
+        assert!(std::ptr::eq(first.syntaxes, second.syntaxes));
+        let html = second.code_block_html("rust numbers", "fn main() {}").unwrap();
+        assert!(html.contains("class='language-rust'") && html.contains(">fn </span>"));
+    }
+
+    #[test]
+    fn unknown_theme_is_an_error() {
+        let config: HighlightConfig = serde_json::from_str(r#"{ "theme": "Nocturno" }"#).unwrap();
+
+        let error = Highlighter::new(&config).err().unwrap();
+        assert!(error.starts_with("el tema `Nocturno` no existe"));
+    }
+
+    #[test]
+    fn info_strings_keep_quoted_values_together() {
+        assert_eq!(split_info(r#"rust  caption="Un ejemplo" numbers"#).unwrap(), ["rust", "caption=Un ejemplo", "numbers"]);
+        assert_eq!(split_info("").unwrap(), Vec::<String>::new());
+        assert_eq!(split_info(r#"rust caption="Sin cerrar"#).unwrap_err(), "falta cerrar las comillas");
+    }
+
+    #[test]
+    fn block_options() {
+        let config = HighlightConfig::default();
+
+        let options = parse_info(r#"python numbers highlight=2,4-6 caption="Cálculo del total""#, &config).unwrap();
+        assert_eq!(options.language, "python");
+        assert!(options.line_numbers);
+        assert_eq!(options.highlighted, [2..=2, 4..=6]);
+        assert_eq!(options.caption.as_deref(), Some("Cálculo del total"));
+
+        // Without a language, and with the numbers of the project turned off.
+        let config: HighlightConfig = serde_json::from_str(r#"{ "line_numbers": true }"#).unwrap();
+        let options = parse_info("numbers=false", &config).unwrap();
+        assert_eq!(options.language, "");
+        assert!(!options.line_numbers);
+
+        assert!(parse_info("rust highlight=2-x", &config).err().unwrap().contains("no es una lista de líneas válida"));
+        assert!(parse_info("rust numbers=si", &config).err().unwrap().contains("debe ser true o false"));
+        assert!(parse_info("rust lineas=2", &config).err().unwrap().contains("opción desconocida"));
+    }
+
+    #[test]
+    fn disabled_highlighting_keeps_the_options() {
+        let config: HighlightConfig = serde_json::from_str(r#"{ "enabled": false }"#).unwrap();
+        let html = Highlighter::new(&config).unwrap()
+            .code_block_html("html numbers highlight=2 caption=Plantilla", "<p>\n  a & b\n</p>")
+            .unwrap();
+
+        assert!(html.contains("&lt;p&gt;") && html.contains("a &amp; b"));
+        assert_eq!(html.matches("class='line-number'").count(), 3);
+        assert_eq!(html.matches("class='code-line highlighted'").count(), 1);
+        assert!(html.find("highlighted").unwrap() > html.find("&lt;p&gt;").unwrap());
+        assert!(html.ends_with("<div class='code-caption'>Plantilla</div>"));
+    }
+}
@@ -1,51 +1,314 @@
-use std::fs;
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::path;
 
-use fs_extra::file;
+use minijinja::syntax::SyntaxConfig;
+use minijinja::{Environment, UndefinedBehavior, Value};
 
-fn resolve_variable(variable_name: &str, content: &str, variable_value: &str) -> String {
-    let result = content.to_string();
+use crate::diagnostics::Diagnostic;
+use crate::filesystem::FileSystem;
+use crate::front_matter::Variables;
+use crate::metadata::Metadata;
+use crate::toc::Heading;
 
-    result.replace(&format!("#{{{variable_name}}}#"), variable_value)
+// Escapes text to be placed inside an HTML element.
+pub fn escape_text(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
 }
 
-pub fn resolve_template(template_path: &str, content_html: &str) -> Result<String, Box<dyn std::error::Error>>{
-    let template = fs::read_to_string(&template_path)?;
+// Escapes text to be placed inside a quoted HTML attribute.
+pub fn escape_attribute(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/* Templates use minijinja (Jinja2) with `#{...}#` for variables, so that the
+original `#{contenido}#` keeps working. Blocks and comments keep the usual
+`{% ... %}` and `{# ... #}`:
+
+{% extends "base.html" %}
+{% block body %}
+    <h1>#{ title }#</h1>
+    {% for author in authors %}<p>#{ author }#</p>{% endfor %}
+    {% include "partials/portada.html" %}
+    #{ contenido }#
+{% endblock %}
+
+Includes and parents are looked up relative to the directory of the template,
+or relative to the template that names them when they start with `./` or `../`.
+Either way they can't be outside that directory.
+Besides the variables of the project and the front matter there are:
 
-    let complete_template = resolve_variable("contenido", &template, content_html);
+- `contenido`: the rendered document.
+- `headings`: every heading, with `level`, `text`, `id` and `number`.
+- `chapters`: the headings of the shallowest level.
+- `metadata`: the document metadata, see `metadata::Metadata`, with `title`,
+  `subtitle`, `authors` (each with `name`, `affiliation` and `email`),
+  `advisor`, `date`, `keywords`, `language` and `abstract`.
 
-    Ok(complete_template)
+Printing or looping over a variable that doesn't exist is an error; use
+`{% if name is defined %}` for optional ones. */
+pub struct TemplateContext<'a> {
+    pub content: &'a str,
+    pub variables: &'a Variables,
+    pub headings: &'a [Heading],
+    pub metadata: &'a Metadata
 }
 
-pub fn mermaid_to_svg(code: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // Have to output to a temp file, read it, and then delete it.
-    // Create a directory inside of `std::env::temp_dir()`.
-    let dir = tempfile::tempdir()?;
+pub fn template_error(err: &minijinja::Error, template_dir: &path::Path) -> Diagnostic {
+    // Errors in included or parent templates are wrapped by the one that includes
+    // them. The innermost one says where the problem actually is.
+    let mut err = err;
+    while let Some(inner) = std::error::Error::source(err).and_then(|source| source.downcast_ref::<minijinja::Error>()) {
+        err = inner;
+    }
 
-    let file_path = dir.path().join("graph.svg");
+    let kind = match err.kind() {
+        minijinja::ErrorKind::UndefinedError => "variable no definida".to_string(),
+        minijinja::ErrorKind::TemplateNotFound => "no se encontró la plantilla".to_string(),
+        minijinja::ErrorKind::SyntaxError => "error de sintaxis".to_string(),
+        kind => kind.to_string()
+    };
+    let message = match err.detail() {
+        Some(detail) => format!("{kind}: {detail}"),
+        None => kind
+    };
 
-    let mut cmd = std::process::Command::new("mmdc");
-    cmd.arg("--input").arg("-")
-        .arg("--backgroundColor").arg("transparent")
-        .arg("--output").arg(file_path.to_str().unwrap());
+    let mut diagnostic = Diagnostic::error(format!("Error en la plantilla: {message}"));
+    if let Some(name) = err.name() {
+        diagnostic = diagnostic.in_file(template_dir.join(name));
+    }
+    let Some(line) = err.line() else {
+        return diagnostic;
+    };
 
-    let mut child = cmd.stdin(std::process::Stdio::piped()).stdout(std::process::Stdio::piped()).spawn().unwrap();
+    // Point at the failing expression when minijinja knows where it is.
+    let source_line = err.template_source().and_then(|source| source.lines().nth(line - 1));
+    match (source_line, err.range(), err.template_source()) {
+        (Some(source_line), Some(range), Some(source)) => {
+            let line_start = source[..range.start].rfind('\n').map_or(0, |start| start + 1);
+            let column = source[line_start..range.start].chars().count() + 1;
+            let length = source[range.clone()].lines().next().unwrap_or("").chars().count().max(1);
+            diagnostic.at(line, column).with_snippet(source_line, length)
+        },
+        _ => diagnostic.at(line, 1)
+    }
+}
 
-    let child_stdin = child.stdin.as_mut().unwrap();
-    write!(child_stdin, "{}", code)?;
+// Files read by a template environment, filled in as it renders.
+pub type UsedTemplates = Arc<Mutex<Vec<path::PathBuf>>>;
 
-    let output = child.wait_with_output()?;
-    if !output.status.success() {
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, String::from_utf8(output.stderr).unwrap())));
+// A template name without `.` and `..` segments, or None when it would leave the
+// template directory. Like `minijinja::path_loader`, hidden files can't be loaded.
+fn normalize_template_name(name: &str) -> Option<String> {
+    let mut segments = vec![];
+    for segment in name.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop()?;
+            },
+            segment if segment.starts_with('.') || segment.contains('\\') => return None,
+            segment => segments.push(segment)
+        }
     }
 
-    let svg = fs::read_to_string(file_path.clone())?;
+    (!segments.is_empty()).then(|| segments.join("/"))
+}
+
+// A template environment with the `#{...}#` syntax that loads templates from
+// `template_dir` in `filesystem`. Also returns the list of files it reads, for
+// the watcher.
+pub fn template_environment(filesystem: Arc<dyn FileSystem>, template_dir: &path::Path) -> Result<(Environment<'static>, UsedTemplates), Box<dyn std::error::Error>> {
+    let mut syntax = SyntaxConfig::builder();
+    syntax.variable_delimiters("#{", "}#");
+    let mut env = Environment::new();
+    env.set_syntax(syntax.build()?);
+    env.set_undefined_behavior(UndefinedBehavior::SemiStrict);
+    env.set_debug(true);
+
+    // `./` and `../` are relative to the template that includes or extends. Names
+    // that leave the directory are kept as written for the loader to reject.
+    env.set_path_join_callback(|name, parent| {
+        if !name.starts_with("./") && !name.starts_with("../") {
+            return name.into();
+        }
+        let joined = match parent.rsplit_once('/') {
+            Some((parent_dir, _)) => format!("{parent_dir}/{name}"),
+            None => name.to_string()
+        };
+        normalize_template_name(&joined).map_or(name.into(), Into::into)
+    });
+
+    let used = Arc::new(Mutex::new(vec![]));
+    let loader_used = used.clone();
+    let loader_dir = template_dir.to_path_buf();
+    env.set_loader(move |name| {
+        let Some(name) = normalize_template_name(name) else {
+            return Ok(None);
+        };
+        let file = loader_dir.join(name);
+        if !filesystem.is_file(&file) {
+            return Ok(None);
+        }
 
-    // Remove the temp file
-    file::remove(file_path.clone())
-    .unwrap_or_else(|_| {
-        println!("[INFO] No se pudo eliminar el archivo temporal: {:?}", file_path)
+        let loaded = filesystem.read_to_string(&file).map_err(|err| {
+            minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, "no se pudo leer la plantilla").with_source(err)
+        })?;
+        loader_used.lock().unwrap().push(file);
+        Ok(Some(loaded))
     });
 
-    Ok(svg)
-}
\ No newline at end of file
+    Ok((env, used))
+}
+
+// Renders the template around the document. Returns the HTML and the path of
+// every template file it used, including parents and includes.
+pub fn resolve_template(filesystem: &Arc<dyn FileSystem>, template_path: &path::Path, context: &TemplateContext) -> Result<(String, Vec<path::PathBuf>), Box<dyn std::error::Error>> {
+    let template_dir = template_path.parent().unwrap_or(path::Path::new(".")).to_path_buf();
+    let template_name = template_path.file_name().and_then(|name| name.to_str()).unwrap_or("").to_string();
+    if !filesystem.is_file(template_path) {
+        return Err(Box::new(Diagnostic::error("No se pudo leer la plantilla: el archivo no existe").in_file(template_path)));
+    }
+
+    let (env, used) = template_environment(filesystem.clone(), &template_dir)?;
+
+    let base_level = context.headings.iter().map(|heading| heading.level).min().unwrap_or(1);
+    let chapters = context.headings.iter().filter(|heading| heading.level == base_level).collect::<Vec<_>>();
+
+    let mut values: BTreeMap<String, Value> = context.variables.iter()
+        .map(|(name, value)| (name.clone(), Value::from_serialize(value)))
+        .collect();
+    values.insert("contenido".to_string(), Value::from_safe_string(context.content.to_string()));
+    values.insert("headings".to_string(), Value::from_serialize(context.headings));
+    values.insert("chapters".to_string(), Value::from_serialize(&chapters));
+    values.insert("metadata".to_string(), Value::from_serialize(context.metadata));
+
+    let html = env.get_template(&template_name)
+        .and_then(|template| template.render(&values))
+        .map_err(|err| template_error(&err, &template_dir))?;
+
+    let used = used.lock().unwrap().clone();
+    Ok((html, used))
+}
+
+// Path polled by the live reload script. Served by `server`, never written to disk.
+pub static LIVE_RELOAD_PATH: &str = "/__thener/version";
+
+// Polls the preview server for the build version and reloads the page when it changes.
+static LIVE_RELOAD_SCRIPT: &str = r#"<script>
+(function () {
+    var version = null;
+    setInterval(function () {
+        fetch("{path}", { cache: "no-store" })
+            .then(function (response) { return response.text(); })
+            .then(function (current) {
+                if (version !== null && current !== version) {
+                    location.reload();
+                }
+                version = current;
+            })
+            .catch(function () {});
+    }, 500);
+})();
+</script>"#;
+
+// Adds the live reload script right before `</body>`, or at the end if the
+// template has no body.
+pub fn inject_live_reload(html: &str) -> String {
+    let script = LIVE_RELOAD_SCRIPT.replace("{path}", LIVE_RELOAD_PATH);
+
+    match html.rfind("</body>") {
+        Some(body_end) => format!("{}{}\n{}", &html[..body_end], script, &html[body_end..]),
+        None => format!("{html}\n{script}")
+    }
+}
+
+// Adds the metadata `<meta>` tags at the end of the `<head>`. Templates without
+// one are left as they are.
+pub fn inject_meta_tags(html: &str, metadata: &Metadata) -> String {
+    let tags = metadata.html_meta_tags();
+    match html.find("</head>") {
+        Some(head_end) if !tags.is_empty() => format!("{}{}\n{}", &html[..head_end], tags, &html[head_end..]),
+        _ => html.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    // Renders /plantillas/main.html with `files` in memory.
+    fn render(files: &[(&str, &str)], variables: &Variables) -> Result<(String, Vec<path::PathBuf>), Diagnostic> {
+        let filesystem: Arc<dyn FileSystem> = Arc::new(files.iter().fold(MemoryFs::new(), |filesystem, (file, code)| filesystem.with_file(file, *code)));
+        let context = TemplateContext { content: "<p>Hola</p>", variables, headings: &[], metadata: &Metadata::default() };
+
+        resolve_template(&filesystem, path::Path::new("/plantillas/main.html"), &context)
+            .map_err(|err| err.downcast_ref::<Diagnostic>().unwrap().clone())
+    }
+
+    #[test]
+    fn template_names_are_normalized() {
+        assert_eq!(normalize_template_name("partes/./a.html").as_deref(), Some("partes/a.html"));
+        assert_eq!(normalize_template_name("partes/../a.html").as_deref(), Some("a.html"));
+        assert_eq!(normalize_template_name("/a.html").as_deref(), Some("a.html"));
+        for name in ["../a.html", "partes/../../a.html", ".oculto.html", "partes/.git/config", "partes\\a.html", ""] {
+            assert_eq!(normalize_template_name(name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn includes_relative_to_the_template_that_names_them() {
+        let (html, used) = render(&[
+            ("/plantillas/main.html", "{% include \"partes/portada.html\" %}#{ contenido }#"),
+            ("/plantillas/partes/portada.html", "{% include \"./titulo.html\" %}{% include \"../pie.html\" %}"),
+            ("/plantillas/partes/titulo.html", "[título]"),
+            ("/plantillas/pie.html", "[pie]")
+        ], &Variables::new()).unwrap();
+
+        assert_eq!(html, "[título][pie]<p>Hola</p>");
+        assert_eq!(used, [
+            path::PathBuf::from("/plantillas/main.html"),
+            path::PathBuf::from("/plantillas/partes/portada.html"),
+            path::PathBuf::from("/plantillas/partes/titulo.html"),
+            path::PathBuf::from("/plantillas/pie.html")
+        ]);
+    }
+
+    #[test]
+    fn templates_outside_the_directory_are_not_found() {
+        let err = render(&[
+            ("/plantillas/main.html", "{% include \"../secreto.html\" %}"),
+            ("/secreto.html", "secreto")
+        ], &Variables::new()).unwrap_err();
+
+        assert!(err.message.starts_with("Error en la plantilla: no se encontró la plantilla"), "{}", err.message);
+        assert_eq!(err.file.as_deref(), Some(path::Path::new("/plantillas/main.html")));
+    }
+
+    #[test]
+    fn undefined_variables_are_errors() {
+        for template in ["<p>#{ falta }#</p>", "{% for autor in falta %}#{ autor }#{% endfor %}"] {
+            let err = render(&[("/plantillas/main.html", template)], &Variables::new()).unwrap_err();
+
+            assert!(err.message.starts_with("Error en la plantilla: variable no definida"), "{}", err.message);
+            assert_eq!(err.file.as_deref(), Some(path::Path::new("/plantillas/main.html")));
+            assert_eq!(err.line, Some(1));
+        }
+    }
+
+    #[test]
+    fn optional_variables_can_be_checked() {
+        let template = "{% if universidad is defined %}#{ universidad }#{% endif %}{# comentario #}";
+        let variables = Variables::from_iter([("universidad".to_string(), "UNAL".into())]);
+
+        assert_eq!(render(&[("/plantillas/main.html", template)], &Variables::new()).unwrap().0, "");
+        assert_eq!(render(&[("/plantillas/main.html", template)], &variables).unwrap().0, "UNAL");
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use rayon::ThreadPool;
+
+// How many files are read, and how many diagram tools run, at the same time.
+// The work of a build is split with rayon, in its own pool when the number of
+// jobs is given (`-j`) and in rayon's global one, with a thread per core,
+// otherwise. Results always come back in order.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    pool: Option<Arc<ThreadPool>>
+}
+
+impl Jobs {
+    // At least one job: `Jobs::new(0)` runs everything in a single thread, like
+    // `Jobs::new(1)`.
+    pub fn new(jobs: usize) -> Jobs {
+        match rayon::ThreadPoolBuilder::new().num_threads(jobs.max(1)).build() {
+            Ok(pool) => Jobs { pool: Some(Arc::new(pool)) },
+            Err(err) => {
+                println!("[ERROR] No se pudieron crear {jobs} hilos, se usa uno por núcleo: {err}");
+                Jobs::default()
+            }
+        }
+    }
+
+    // Runs `op` so that rayon's parallel iterators inside it use these jobs.
+    pub fn run<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(op),
+            None => op()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use rayon::prelude::*;
+
+    // The most work items running at the same time with `jobs`.
+    fn most_at_once(jobs: &Jobs) -> usize {
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        jobs.run(|| {
+            (0..32).into_par_iter().for_each(|_| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(2));
+                running.fetch_sub(1, Ordering::SeqCst);
+            });
+        });
+
+        most.into_inner()
+    }
+
+    #[test]
+    fn one_job_runs_serially() {
+        let jobs = Jobs::new(1);
+
+        assert_eq!(jobs.run(rayon::current_num_threads), 1);
+        assert_eq!(most_at_once(&jobs), 1);
+    }
+
+    #[test]
+    fn zero_jobs_are_one() {
+        let jobs = Jobs::new(0);
+
+        assert_eq!(jobs.run(rayon::current_num_threads), 1);
+        assert_eq!(most_at_once(&jobs), 1);
+    }
+
+    #[test]
+    fn jobs_set_the_threads() {
+        assert_eq!(Jobs::new(3).run(rayon::current_num_threads), 3);
+        assert_eq!(Jobs::default().run(rayon::current_num_threads), rayon::current_num_threads());
+    }
+
+    #[test]
+    fn results_come_back_in_order() {
+        let squares: Vec<usize> = Jobs::new(4).run(|| (0..100).into_par_iter().map(|n| n * n).collect());
+
+        assert_eq!(squares, (0..100).map(|n| n * n).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,66 @@
+//! Compiles a project written in markdown down into a pdf or html file. It's made
+//! to help me write my thesis, but it's also made to be easily extensible and
+//! usable for other projects.
+//!
+//! What other tools can use, kept stable from one version to the next:
+//!
+//! ```ignore
+//! let project = thener::Project::from_config(r#"{ "path": ".", ... }"#, path::Path::new("/tesis"))?;
+//! let files = thener::MemoryFs::new().with_file("/tesis/start.md", "# Introducción");
+//! let mut session = thener::BuildSession::new(&project, &[thener::OutputFormat::Html])
+//!     .with_filesystem(Arc::new(files))
+//!     .with_jobs(Some(4))
+//!     .with_progress(|event| println!("{event:?}"));
+//! let html = session.html()?;
+//! let pdf = session.pdf()?;
+//! ```
+//!
+//! A project is read from its `project.thn` with `read_configuration`, from an
+//! archive with `read_archive`, or from its contents with `Project::from_config`.
+//! Every file of the project is read from the `FileSystem` of the session: the
+//! disk, memory, an archive, or files in memory over the ones on disk with
+//! `OverlayFs`. Markdown alone is preprocessed with `MarkdownPreprocessor`. Errors
+//! are usually `Diagnostic`s or `Diagnostics`, which `report` prints.
+
+mod bibliography;
+mod browser;
+mod diagnostics;
+mod diagrams;
+mod cross_refs;
+mod filesystem;
+mod math;
+mod md_compiler;
+mod mermaid;
+mod metadata;
+mod file_utils;
+mod front_matter;
+mod highlight;
+mod jobs;
+mod header_footer;
+mod html_generation;
+mod manifest;
+mod project_builder;
+mod pdf_exporter;
+mod server;
+mod source_map;
+mod toc;
+mod watcher;
+
+/// Closes the running browsers when the process is interrupted.
+pub use browser::close_on_interrupt;
+/// Problems found while building, and how they are printed.
+pub use diagnostics::{report, Diagnostic, Diagnostics, Severity};
+/// Where the files of a project are read from.
+pub use filesystem::{is_archive, DiskFs, FileStamp, FileSystem, MemoryFs, OverlayFs};
+/// How many diagrams are drawn and imports read at the same time.
+pub use jobs::Jobs;
+/// The `@import` resolution, usable without a project.
+pub use md_compiler::{ImportError, MarkdownPreprocessor};
+/// Reading projects and building them, all at once or by stages.
+pub use project_builder::{build_project, open_project, read_archive, read_configuration, stages_for, BuildEvent, BuildSession, BuildStage, OutputFormat, Project};
+/// The preview server of `thener serve`.
+pub use server::{serve, BuildVersion};
+/// Where each line of the preprocessed markdown came from.
+pub use source_map::{SourceLine, SourceMap};
+/// Rebuilding a project when its files change.
+pub use watcher::{watch, watch_project};
@@ -1,57 +1,167 @@
-use clap::Parser;
-use serde;
-
-mod md_compiler;
-mod file_utils;
-mod html_generation;
-mod project_builder;
-mod pdf_exporter;
-
-#[derive(serde::Serialize, serde::Deserialize)]
-#[derive(Clone, Debug)]
-enum OutputFormat {
-    PDF,
-    HTML
-}
-
-// Implement from str on output format
-impl std::str::FromStr for OutputFormat {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "pdf" => Ok(OutputFormat::PDF),
-            "html" => Ok(OutputFormat::HTML),
-            _ => Err(format!("Formato de salida no soportado: {}", s))
-        }
+use clap::{Parser, Subcommand};
+
+use thener::{BuildSession, BuildVersion, OutputFormat};
+
+// A number of jobs, at least one.
+fn parse_jobs(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("tiene que haber al menos un trabajo".to_string()),
+        Ok(jobs) => Ok(jobs),
+        Err(err) => Err(err.to_string())
     }
 }
 
+#[derive(clap::Args, Debug)]
+struct BuildArgs {
+    #[arg(value_name="project_file")]
+    project: String,
+
+    /// Formatos a generar: se puede repetir (`-f html -f pdf`) o separar con comas (`-f html,pdf`)
+    #[arg(short, long, value_name="format", default_value="pdf", value_delimiter=',')]
+    format: Vec<OutputFormat>,
+
+    /// Vuelve a generar todo, aunque no haya cambiado nada desde la última compilación
+    #[arg(long)]
+    force: bool,
+
+    /// Cuántos importes se leen y cuántos diagramas se dibujan a la vez, uno por núcleo si no se indica
+    #[arg(short, long, value_name="jobs", value_parser=parse_jobs)]
+    jobs: Option<usize>
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    #[arg(value_name="project_file")]
+    project: String,
+
+    /// Formatos a generar: se puede repetir (`-f html -f pdf`) o separar con comas (`-f html,pdf`)
+    #[arg(short, long, value_name="format", default_value="html", value_delimiter=',')]
+    format: Vec<OutputFormat>,
+
+    /// Puerto de localhost en el que se sirve el html
+    #[arg(short, long, value_name="port", default_value_t=4000)]
+    port: u16,
+
+    /// Vuelve a generar todo, aunque no haya cambiado nada desde la última compilación
+    #[arg(long)]
+    force: bool,
+
+    /// Cuántos importes se leen y cuántos diagramas se dibujan a la vez, uno por núcleo si no se indica
+    #[arg(short, long, value_name="jobs", value_parser=parse_jobs)]
+    jobs: Option<usize>
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "Compila el proyecto y lo vuelve a compilar cada vez que cambia uno de sus archivos")]
+    Watch(BuildArgs),
+
+    #[command(about = "Sirve el html compilado en localhost y recarga el navegador cuando cambia el proyecto")]
+    Serve(ServeArgs)
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version)]
 #[command(about = "Toma un projecto thener y genera un pdf o html", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(value_name="project_file")]
-    project: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    #[arg(short, long, value_name="format", default_value="pdf")]
-    format: Option<OutputFormat>
+    #[command(flatten)]
+    build: Option<BuildArgs>
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() {
     let cli = Args::parse();
 
-    let project = project_builder::read_configuration(&cli.project)?;
-    project_builder::build_project(&project)?;
+    // Chrome would otherwise keep running after a Ctrl-C.
+    if let Err(err) = thener::close_on_interrupt() {
+        println!("[ERROR] No se pudo manejar Ctrl-C: {err}");
+    }
+
+    // Printed with `Display` rather than returned from `main`, which would show the `Debug` form.
+    if let Err(err) = run(cli) {
+        thener::report(err.as_ref());
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Args) -> Result<(), Box<dyn std::error::Error>> {
+    match (cli.command, cli.build) {
+        // A change to `project.thn` ends `watch` with the project read again, which
+        // is then watched from a new session.
+        (Some(Command::Watch(args)), _) => {
+            let mut project = Some(read_watched_project(&args.project)?);
+            let mut force = args.force;
+            while let Some(current) = project {
+                let mut session = BuildSession::new(&current, &args.format)
+                    .with_force(std::mem::take(&mut force))
+                    .with_jobs(args.jobs);
+                project = thener::watch(&mut session, || {})?;
+            }
+        },
+        (Some(Command::Serve(args)), _) => {
+            let project = read_watched_project(&args.project)?;
+            let version = BuildVersion::default();
+            let served = project.html_output_path()?;
+            thener::serve(served.clone(), args.port, version.clone())?;
+
+            // The server keeps serving the first output directory. A change to it is
+            // reported once, not on every reload.
+            let mut project = Some(project);
+            let mut force = args.force;
+            let mut output = served.clone();
+            while let Some(current) = project {
+                let current_output = current.html_output_path()?;
+                if current_output != output && current_output != served {
+                    println!("[ERROR] El directorio de salida cambió, se sigue sirviendo {}", served.display());
+                }
+                output = current_output;
+                let mut session = BuildSession::new(&current, &args.format)
+                    .with_live_reload(true)
+                    .with_force(std::mem::take(&mut force))
+                    .with_jobs(args.jobs);
+                project = thener::watch(&mut session, || version.bump())?;
+            }
+        },
+        (None, Some(args)) => {
+            // `project_file` can also be an archive with the whole project.
+            let (project, filesystem) = thener::open_project(&args.project)?;
+            BuildSession::new(&project, &args.format)
+                .with_filesystem(filesystem)
+                .with_force(args.force)
+                .with_jobs(args.jobs)
+                .build()?;
+        },
+        (None, None) => unreachable!("clap requires the project file when no subcommand is given")
+    }
 
     Ok(())
 }
 
+// Watching needs the files of the project on disk.
+fn read_watched_project(project: &str) -> Result<thener::Project, Box<dyn std::error::Error>> {
+    if thener::is_archive(std::path::Path::new(project)) {
+        return Err(format!("{project} es un archivo comprimido, que solo se puede compilar, no observar").into());
+    }
+
+    thener::read_configuration(project)
+}
 
-/*
-  A general description of this library:
-  This library is a tool for compiling a project written in markdown, down into a pdf or html file.
-  It's made to help me write my thesis, but it's also made to be easily extensible and usable for other projects.
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-  The way it works:
- */
\ No newline at end of file
+    #[test]
+    fn jobs_are_at_least_one() {
+        let args = Args::try_parse_from(["thener", "project.thn", "-j", "1"]).unwrap();
+        assert_eq!(args.build.and_then(|build| build.jobs), Some(1));
+
+        let args = Args::try_parse_from(["thener", "watch", "project.thn", "-j", "4"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Watch(BuildArgs { jobs: Some(4), .. }))));
+
+        assert!(Args::try_parse_from(["thener", "project.thn", "-j", "0"]).is_err());
+        assert!(Args::try_parse_from(["thener", "serve", "project.thn", "--jobs", "0"]).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::{fs, path};
+use sha2::{Digest, Sha256};
+
+use crate::filesystem::{DiskFs, FileStamp, FileSystem};
+use crate::metadata::Metadata;
+use crate::toc::Heading;
+
+// Bumped whenever the format changes, so that old manifests are ignored.
+static VERSION: u32 = 2;
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// `None` if the file can't be read, which never matches a recorded hash.
+pub fn hash_file(filesystem: &dyn FileSystem, file: &path::Path) -> Option<String> {
+    filesystem.read(file).ok().map(|bytes| hash_bytes(&bytes))
+}
+
+// The hash of every file in `files`, empty for the ones that can't be read so
+// that they count as changed.
+pub fn hash_files(filesystem: &dyn FileSystem, files: &[path::PathBuf]) -> BTreeMap<path::PathBuf, String> {
+    files.iter()
+        .map(|file| (file.clone(), hash_file(filesystem, file).unwrap_or_default()))
+        .collect()
+}
+
+// What a stage read and wrote the last time it ran. The stage can be skipped
+// while its settings and inputs are the same and its output is still there,
+// untouched. Inputs are read from the filesystem the build reads from, and the
+// output from the disk.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct StageRecord {
+    settings: String,
+    inputs: BTreeMap<path::PathBuf, String>,
+    output: String
+}
+
+impl StageRecord {
+    pub fn new(filesystem: &dyn FileSystem, settings: &str, inputs: &[path::PathBuf], output: &path::Path) -> StageRecord {
+        StageRecord {
+            settings: settings.to_string(),
+            inputs: hash_files(filesystem, inputs),
+            output: hash_file(&DiskFs, output).unwrap_or_default()
+        }
+    }
+
+    pub fn is_fresh(&self, filesystem: &dyn FileSystem, settings: &str, output: &path::Path) -> bool {
+        self.settings == settings
+            && hash_file(&DiskFs, output).is_some_and(|hash| hash == self.output)
+            && self.inputs.iter().all(|(file, hash)| hash_file(filesystem, file).as_ref() == Some(hash))
+    }
+}
+
+// The HTML stage also keeps what the later stages and the watcher need from
+// it, so that they can go on without preprocessing again.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct HtmlRecord {
+    pub stage: StageRecord,
+    pub sources: Vec<path::PathBuf>,
+    pub templates: Vec<path::PathBuf>,
+    pub headings: Vec<Heading>,
+    pub metadata: Metadata
+}
+
+// A copied asset. While its stamp is the same it isn't read again; files
+// without one are always read and compared by their hash.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AssetRecord {
+    pub hash: String,
+    pub stamp: Option<FileStamp>
+}
+
+/* Kept in `cache/manifest.json` inside the build directory:
+
+{
+    "version": 2,
+    "html": { "stage": { "settings": "...", "inputs": { "/proyecto/start.md": "9f86d0..." }, "output": "..." }, "sources": [...], ... },
+    "assets": { "img/logo.png": { "hash": "2c26b4...", "stamp": { "size": 5120, "modified": 1714521600000000000 } } },
+    "pdf": { "settings": "...", "inputs": { ... }, "output": "..." }
+}
+
+`settings` is a hash of the configuration of the project, so that changing it
+rebuilds everything. `assets` are the copied assets, by their path inside the
+assets directory. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct Manifest {
+    version: u32,
+    pub html: Option<HtmlRecord>,
+    pub assets: BTreeMap<String, AssetRecord>,
+    pub pdf: Option<StageRecord>
+}
+
+impl Manifest {
+    // An empty manifest if there is none yet or it can't be understood.
+    pub fn load(manifest_path: &path::Path) -> Manifest {
+        fs::read_to_string(manifest_path).ok()
+            .and_then(|json| serde_json::from_str::<Manifest>(&json).ok())
+            .filter(|manifest| manifest.version == VERSION)
+            .unwrap_or_default()
+    }
+
+    pub fn save(&mut self, manifest_path: &path::Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.version = VERSION;
+        if let Some(dir) = manifest_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(manifest_path, serde_json::to_string_pretty(self)?)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    #[test]
+    fn stages_are_fresh_while_nothing_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("index.html");
+        fs::write(&output, "<h1>Inicio</h1>").unwrap();
+        let inputs = [path::PathBuf::from("/doc/start.md"), path::PathBuf::from("/doc/uno.md")];
+        let files = MemoryFs::new().with_file("/doc/start.md", "# Inicio").with_file("/doc/uno.md", "Uno");
+
+        let record = StageRecord::new(&files, "ajustes", &inputs, &output);
+        assert!(record.is_fresh(&files, "ajustes", &output));
+        assert!(!record.is_fresh(&files, "otros ajustes", &output));
+
+        let changed = MemoryFs::new().with_file("/doc/start.md", "# Inicio").with_file("/doc/uno.md", "Dos");
+        assert!(!record.is_fresh(&changed, "ajustes", &output));
+        let missing = MemoryFs::new().with_file("/doc/start.md", "# Inicio");
+        assert!(!record.is_fresh(&missing, "ajustes", &output));
+
+        fs::write(&output, "<h1>Editado</h1>").unwrap();
+        assert!(!record.is_fresh(&files, "ajustes", &output));
+        fs::remove_file(&output).unwrap();
+        assert!(!record.is_fresh(&files, "ajustes", &output));
+    }
+
+    #[test]
+    fn manifests_are_saved_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("cache/manifest.json");
+
+        let mut manifest = Manifest::default();
+        manifest.assets.insert("img/logo.png".to_string(), AssetRecord { hash: hash_bytes(b"logo"), stamp: None });
+        manifest.save(&manifest_path).unwrap();
+
+        let loaded = Manifest::load(&manifest_path);
+        assert_eq!(loaded.assets, manifest.assets);
+        assert!(loaded.html.is_none() && loaded.pdf.is_none());
+    }
+
+    #[test]
+    fn old_or_broken_manifests_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("manifest.json");
+        assert!(Manifest::load(&manifest_path).assets.is_empty());
+
+        let assets = r#"{ "img/logo.png": { "hash": "2c26b4", "stamp": null } }"#;
+        fs::write(&manifest_path, format!(r#"{{ "version": 1, "html": null, "assets": {assets}, "pdf": null }}"#)).unwrap();
+        assert!(Manifest::load(&manifest_path).assets.is_empty());
+
+        fs::write(&manifest_path, format!(r#"{{ "version": {VERSION}, "html": null, "assets": {assets}, "pdf": null }}"#)).unwrap();
+        assert_eq!(Manifest::load(&manifest_path).assets.len(), 1);
+
+        fs::write(&manifest_path, "{ no es json").unwrap();
+        assert!(Manifest::load(&manifest_path).assets.is_empty());
+    }
+}
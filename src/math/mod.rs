@@ -0,0 +1,135 @@
+use comrak::nodes::{AstNode, NodeValue};
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+
+use crate::cross_refs::{self, TargetKind};
+use crate::html_generation;
+
+// What a display equation looks like once rendered. The number is filled in by
+// `number_equations`, only for equations standing alone in their paragraph.
+pub static EQUATION_TAG_START: &str = "<span class='equation'>";
+static EQUATION_NUMBER: &str = "<span class='equation-number'></span>";
+
+// latex2mathml writes the text of each token as is, so `a < b` would end up as
+// `<mo><</mo>`. Escapes the contents of every token element.
+fn escape_tokens(mathml: &str) -> String {
+    static TOKENS: &[&str] = &["mi", "mn", "mo", "ms", "mtext"];
+
+    let mut result = String::with_capacity(mathml.len());
+    let mut rest = mathml;
+    while let Some(start) = rest.find('<') {
+        let after = &rest[start + 1..];
+        let Some(tag_end) = after.find('>') else {
+            break;
+        };
+        let name = after.split(|c: char| !c.is_ascii_alphanumeric()).next().unwrap_or("");
+        let self_closing = after[..tag_end].ends_with('/');
+
+        result.push_str(&rest[..start + 1 + tag_end + 1]);
+        rest = &after[tag_end + 1..];
+
+        if TOKENS.contains(&name) && !self_closing {
+            let close = format!("</{name}>");
+            if let Some(end) = rest.find(&close) {
+                result.push_str(&html_generation::escape_text(&rest[..end]));
+                result.push_str(&close);
+                rest = &rest[end + close.len()..];
+            }
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+// Renders `$...$` (inline) or `$$...$$` (display) math as MathML, which Chrome
+// lays out by itself, so nothing has to run before printing.
+pub fn math_to_html(latex: &str, display: bool) -> Result<String, String> {
+    let style = if display { DisplayStyle::Block } else { DisplayStyle::Inline };
+    let mathml = escape_tokens(&latex_to_mathml(latex.trim(), style).map_err(|err| err.to_string())?);
+
+    Ok(if display {
+        format!("{EQUATION_TAG_START}{mathml}{EQUATION_NUMBER}</span>")
+    } else {
+        mathml
+    })
+}
+
+// Numbers the display equations that cross-references can point to, in
+// document order, the same order `cross_refs::collect_targets` uses.
+pub fn number_equations<'a>(root: &'a AstNode<'a>) {
+    let mut equations = 0;
+    for node in root.descendants() {
+        if cross_refs::element_kind(node) != Some(TargetKind::Equation) {
+            continue;
+        }
+
+        equations += 1;
+        for child in node.children() {
+            if let NodeValue::HtmlInline(ref mut literal) = child.data.borrow_mut().value {
+                if literal.starts_with(EQUATION_TAG_START) {
+                    *literal = literal.replace(EQUATION_NUMBER, &format!("<span class='equation-number'>({equations})</span>"));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::Jobs;
+    use crate::md_compiler::{markdown_to_html, MarkdownOptions};
+    use crate::source_map::SourceMap;
+
+    fn render(markdown: &str) -> String {
+        let options = MarkdownOptions {
+            toc: None,
+            number_headings: false,
+            bibliography: None,
+            highlight: None,
+            mermaid: None,
+            diagrams: None,
+            language: None,
+            jobs: Jobs::default()
+        };
+        markdown_to_html(markdown, &SourceMap::default(), &options).ok().unwrap().html
+    }
+
+    #[test]
+    fn token_contents_are_escaped() {
+        assert_eq!(
+            escape_tokens("<math><mi>a</mi><mo><</mo><mtext>x & y</mtext><mspace width='1em'/><mn>2</mn></math>"),
+            "<math><mi>a</mi><mo>&lt;</mo><mtext>x &amp; y</mtext><mspace width='1em'/><mn>2</mn></math>"
+        );
+        assert_eq!(escape_tokens("<mrow><mo>></mo></mrow>"), "<mrow><mo>&gt;</mo></mrow>");
+    }
+
+    #[test]
+    fn display_math_is_an_equation() {
+        let inline = math_to_html("a < b", false).unwrap();
+        let display = math_to_html("a < b", true).unwrap();
+
+        assert!(inline.starts_with("<math") && inline.contains("<mo>&lt;</mo>"));
+        assert!(display.starts_with(EQUATION_TAG_START) && display.ends_with(&format!("{EQUATION_NUMBER}</span>")));
+        assert!(math_to_html("\\frac{1}{", false).is_err());
+    }
+
+    #[test]
+    fn equations_alone_in_their_paragraph_are_numbered() {
+        let html = render("$$x = 1$$\n\nTexto con $y$ en línea.\n\n$$z = 2$$\n");
+
+        assert!(html.contains("<span class='equation-number'>(1)</span>"));
+        assert!(html.contains("<span class='equation-number'>(2)</span>"));
+        assert_eq!(html.matches(EQUATION_TAG_START).count(), 2);
+        assert!(html.contains("Texto con <math"));
+    }
+
+    #[test]
+    fn dollars_that_are_not_math() {
+        let html = render("Cuesta \\$5 y \\$10.\n\nUn $ sin cerrar.\n");
+
+        assert!(!html.contains("<math"));
+        assert!(html.contains("Cuesta $5 y $10."));
+        assert!(html.contains("Un $ sin cerrar."));
+    }
+}
@@ -1,10 +1,28 @@
-use std::{path, fs};
-use defer_lite::defer;
+use std::{path, fmt};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use path_absolutize::Absolutize;
-use comrak::{parse_document, format_html, Arena, Options, ExtensionOptions, ParseOptions, RenderOptions, RenderOptionsBuilder};
-use comrak::nodes::{AstNode, NodeValue};
+use comrak::{parse_document, format_html_with_plugins, Anchorizer, Arena, Options, Plugins};
+use comrak::adapters::{HeadingAdapter, HeadingMeta};
+use comrak::nodes::{AstNode, NodeHtmlBlock, NodeValue, Sourcepos};
+use rayon::prelude::*;
 
-use crate::html_generation;
+use crate::bibliography::{self, Bibliography};
+use crate::cross_refs::{self, Captioned, Labels, TargetKind};
+use crate::diagnostics::{Diagnostic, Diagnostics};
+use crate::diagrams::{self, DiagramInfo, DiagramResult, Diagrams};
+use crate::filesystem::{DiskFs, FileSystem};
+use crate::header_footer;
+use crate::{html_generation, math};
+use crate::highlight::{HighlightConfig, Highlighter};
+use crate::jobs::Jobs;
+use crate::mermaid::Mermaid;
+use crate::source_map::{SourceLine, SourceMap};
+use crate::toc::{self, Heading, TocConfig};
 
 static IMPORT_PREFIX: &str = "@import ";
 // static EVAL_START: &str = "@>";
@@ -16,13 +34,194 @@ static TAG_COMMENT_MARKER: &str = "@//";
 // static ERROR_CLASS_MARKER: &str = "@!";
 // static QUESTION_CLASS_MARKER: &str = "@?";
 
+// Why an `@import` could not be resolved. Reported as a diagnostic pointing at
+// the `@import` line.
+#[derive(Debug)]
+pub enum ImportError {
+    // The chain starts and ends with the same file.
+    Cycle { chain: Vec<String> },
+    StackExceeded { max_import_stack: u8 },
+    Unreadable { file: String, reason: String }
+}
+
+impl std::error::Error for ImportError {}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Cycle { chain } => {
+                write!(f, "ciclo de importes: {}", chain.join(" -> "))
+            },
+            ImportError::StackExceeded { max_import_stack } => {
+                write!(f, "Stack de importes excedido ({max_import_stack} niveles)")
+            },
+            ImportError::Unreadable { file, reason } => {
+                write!(f, "No se pudo importar {file}: {reason}")
+            }
+        }
+    }
+}
+
+// A line of a file read by the preprocessor.
+#[derive(Clone)]
+enum Line {
+    // The file named by an `@import` line.
+    Import(String),
+    // Any other line, with its inline tags resolved.
+    Text(String)
+}
+
+// A file read by the preprocessor: its code and each of its lines.
+#[derive(Clone)]
+struct Source {
+    code: String,
+    lines: Vec<Line>
+}
+
+// The marker of a line that opens or closes a fenced code block and how many
+// times it is repeated: ('`', 3) for "```rust".
+fn code_fence(line: &str) -> Option<(char, usize)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+
+    let fence = &line[indent..];
+    let marker = fence.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let length = fence.len() - fence.trim_start_matches(marker).len();
+    // The info string of a backtick fence can't have backticks, that's inline code.
+    let is_inline_code = marker == '`' && fence[length..].contains('`');
+    (length >= 3 && !is_inline_code).then_some((marker, length))
+}
+
+// Whether `line` closes a fenced code block opened by `opening`.
+fn closes_fence(line: &str, opening: (char, usize)) -> bool {
+    match code_fence(line) {
+        Some((marker, length)) => {
+            marker == opening.0 && length >= opening.1 && line.trim_start().trim_start_matches(marker).trim().is_empty()
+        },
+        None => false
+    }
+}
+
+fn backtick_run(text: &str) -> usize {
+    text.len() - text.trim_start_matches('`').len()
+}
+
+// Applies `resolve` to the parts of a line outside of inline code spans, which
+// are kept as written so that `` `@ref(fig:x)` `` shows the tag itself.
+fn outside_code_spans(line: &str, resolve: impl Fn(&str) -> String) -> String {
+    let mut result = String::new();
+    let mut text_start = 0;
+    let mut position = 0;
+    while let Some(offset) = line[position..].find('`') {
+        let start = position + offset;
+        let length = backtick_run(&line[start..]);
+        let code_start = start + length;
+
+        // A span is closed by a run of as many backticks as opened it. Without
+        // one the backticks are just text.
+        let mut closing = None;
+        let mut search = code_start;
+        while let Some(offset) = line[search..].find('`') {
+            let run_start = search + offset;
+            let run = backtick_run(&line[run_start..]);
+            if run == length {
+                closing = Some(run_start + run);
+                break;
+            }
+            search = run_start + run;
+        }
+
+        match closing {
+            Some(end) => {
+                result.push_str(&resolve(&line[text_start..start]));
+                result.push_str(&line[start..end]);
+                text_start = end;
+                position = end;
+            },
+            None => position = code_start
+        }
+    }
+    result.push_str(&resolve(&line[text_start..]));
+
+    result
+}
+
+// Every imported file by its absolute path, or why it couldn't be read.
+type Sources = HashMap<path::PathBuf, Result<Source, String>>;
+
 pub struct MarkdownPreprocessor {
     max_import_stack: u8,
+    // Where the entry point and its imports are read from, the disk by default.
+    filesystem: Arc<dyn FileSystem>,
+    // What a relative entry point is relative to. Imports are relative to the file
+    // that has them.
+    base_dir: path::PathBuf,
+    // Imports are read at the same time by these jobs.
+    jobs: Jobs,
+    // Absolute paths of every file read while preprocessing, entry point included.
+    imported_files: Mutex<Vec<path::PathBuf>>,
+    // The same, along with the imports that couldn't be read.
+    referenced_files: Mutex<Vec<path::PathBuf>>,
+    diagnostics: Mutex<Diagnostics>,
+}
+
+impl Default for MarkdownPreprocessor {
+    fn default() -> Self {
+        MarkdownPreprocessor::new()
+    }
 }
 
 impl MarkdownPreprocessor {
     pub fn new() -> Self {
-        MarkdownPreprocessor { max_import_stack: 100 }
+        MarkdownPreprocessor {
+            max_import_stack: 100,
+            filesystem: Arc::new(DiskFs),
+            base_dir: std::env::current_dir().unwrap_or_default(),
+            jobs: Jobs::default(),
+            imported_files: Mutex::new(vec![]),
+            referenced_files: Mutex::new(vec![]),
+            diagnostics: Mutex::new(Diagnostics::new())
+        }
+    }
+
+    pub fn with_max_import_stack(mut self, max_import_stack: u8) -> Self {
+        self.max_import_stack = max_import_stack;
+        self
+    }
+
+    pub fn with_filesystem(mut self, filesystem: Arc<dyn FileSystem>) -> Self {
+        self.filesystem = filesystem;
+        self
+    }
+
+    pub fn with_base_dir(mut self, base_dir: &path::Path) -> Self {
+        self.base_dir = base_dir.to_path_buf();
+        self
+    }
+
+    pub fn with_jobs(mut self, jobs: Jobs) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    // The canonical path of every file preprocessed by the last call to
+    // `preprocess_markdown`, the entry point first.
+    pub fn imported_files(&self) -> Vec<path::PathBuf> {
+        self.imported_files.lock().unwrap().clone()
+    }
+
+    // Every file the last call to `preprocess_markdown` tried to read, whether it
+    // could or not, which is what a watcher needs even after a failed call.
+    pub fn referenced_files(&self) -> Vec<path::PathBuf> {
+        self.referenced_files.lock().unwrap().clone()
+    }
+
+    // Problems found by the last call to `preprocess_markdown`, including warnings
+    // when it succeeded.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diagnostics.lock().unwrap().clone()
     }
 
     fn resolve_inline_tag(&self, line: &str, tag_marker: &str, replacement: fn(&str) -> String) -> String {
@@ -51,7 +250,7 @@ impl MarkdownPreprocessor {
                 let rest_of_line = line.split_at(tag_start + expanded.len()).1;
                 start = rest_of_line
                     .find(tag_marker)
-                    .and_then(|count| Some(count + tag_start + expanded.len()));
+                    .map(|count| count + tag_start + expanded.len());
             } else {
                 break;
             }
@@ -60,13 +259,39 @@ impl MarkdownPreprocessor {
         line
     }
 
+    // Turns every `@ref(name)` into a tag that `markdown_to_html` resolves once
+    // everything is numbered.
+    fn resolve_references(&self, line: &str) -> String {
+        let mut result = String::new();
+        let mut rest = line;
+        while let Some(start) = rest.find(cross_refs::REF_START) {
+            let after = &rest[start + cross_refs::REF_START.len()..];
+            let Some(end) = after.find(cross_refs::REF_END) else {
+                break;
+            };
+
+            result.push_str(&rest[..start]);
+            result.push_str(&cross_refs::ref_tag(after[..end].trim()));
+            rest = &after[end + cross_refs::REF_END.len()..];
+        }
+        result.push_str(rest);
+
+        result
+    }
+
+    // Tags inside inline code spans are left alone.
     fn resolve_tags(&self, line: &str) -> String {
-        let with_ids = self.resolve_inline_tag(line, TAG_ID_MARKER, |tag| {
-            format!("<span id='{tag}'></span>")
-        });
+        let with_classes = outside_code_spans(line, |text| {
+            let with_refs = self.resolve_references(text);
+            let with_citations = bibliography::resolve_citation_syntax(&with_refs);
+
+            let with_ids = self.resolve_inline_tag(&with_citations, TAG_ID_MARKER, |tag| {
+                format!("<span id='{tag}'></span>")
+            });
 
-        let with_classes = self.resolve_inline_tag(&with_ids, TAG_CLASS_MARKER, |tag| {
-            format!("<span class='{tag}'></span>")
+            self.resolve_inline_tag(&with_ids, TAG_CLASS_MARKER, |tag| {
+                format!("<span class='{tag}'></span>")
+            })
         });
         
         // Find comment and ignore everything until the end of the line
@@ -83,72 +308,267 @@ impl MarkdownPreprocessor {
         // TODO: Add remaining tag handling
     }
     
-    fn preprocess_markdown_recursively(&self, file_name: &str, code: &str, import_depth: u8) -> Result<String, Box<dyn std::error::Error>> {
-        assert!(import_depth < self.max_import_stack, "Stack de importes excedido al importar {file_name}");
+    fn reference(&self, file_path: &path::Path) {
+        let mut referenced_files = self.referenced_files.lock().unwrap();
+        if !referenced_files.iter().any(|file| file == file_path) {
+            referenced_files.push(file_path.to_path_buf());
+        }
+    }
 
-        let original_dir = std::env::current_dir()?;
+    // Paths in the import stack, shown relative to the directory of the entry point.
+    fn display_import_stack(&self, import_stack: &[path::PathBuf]) -> Vec<String> {
+        let root = import_stack.first().and_then(|entry| entry.parent()).unwrap_or(path::Path::new(""));
 
-        println!("[INFO] Preprocesando {}", file_name);
-        let file_path = path::Path::new(file_name);
-        let actual_file_name = file_path.file_name().expect("Could not read file");
-        let parent_dir = match file_path.parent() {
-            Some(parent) => {
-                if parent.to_str().unwrap_or("") == "" {
-                    Ok(original_dir.clone())
-                } else {
-                    Ok(parent.to_path_buf())
+        import_stack.iter()
+            .map(|file| file.strip_prefix(root).unwrap_or(file).to_string_lossy().to_string())
+            .collect()
+    }
+
+    // Checks that `file_path` can be imported from the top of the stack, returning
+    // its canonical path.
+    fn check_import(&self, file: &str, file_path: &path::Path, import_stack: &[path::PathBuf]) -> Result<path::PathBuf, ImportError> {
+        let canonical = self.filesystem.canonicalize(file_path).map_err(|err| ImportError::Unreadable {
+            file: file.to_string(),
+            reason: err.to_string()
+        })?;
+
+        if import_stack.contains(&canonical) {
+            let start = import_stack.iter().position(|file| *file == canonical).unwrap_or(0);
+            let mut cycle = import_stack.to_vec();
+            cycle.push(canonical);
+            let mut chain = self.display_import_stack(&cycle);
+            chain.drain(..start);
+
+            return Err(ImportError::Cycle { chain });
+        }
+
+        if import_stack.len() >= self.max_import_stack as usize {
+            return Err(ImportError::StackExceeded { max_import_stack: self.max_import_stack });
+        }
+
+        Ok(canonical)
+    }
+
+    // A markdown file with its `@import` lines found and the tags of every other
+    // line already resolved. Fenced code blocks are kept as written, imports
+    // included.
+    fn parse_source(&self, code: String) -> Source {
+        let mut fence = None;
+        let lines = code.lines()
+            .map(|line| {
+                if let Some(opening) = fence {
+                    if closes_fence(line, opening) {
+                        fence = None;
+                    }
+                    return Line::Text(line.to_string());
+                }
+                if let Some(opening) = code_fence(line) {
+                    fence = Some(opening);
+                    return Line::Text(line.to_string());
                 }
-            },
-            None => Err("No se pudo leer el directorio.") // TODO: Expressive errors
-        }?;
-    
-        let mut result: Vec<String> = vec![];
-        result.push(format!("<!-- fin del archivo {} -->", actual_file_name.to_str().unwrap_or("<unknown path>")));
-        let lines = code.lines().rev();
-        std::env::set_current_dir(parent_dir)?;
-        defer! { std::env::set_current_dir(original_dir).unwrap_or(()) }
-        
-        for line in lines {
-            let without_tags = if line.is_empty() {
-                line.to_string()
-            } else {
-                self.resolve_tags(line)
-            };
 
-            if line.starts_with(IMPORT_PREFIX) {
-                let file = line[IMPORT_PREFIX.len()..].to_string();
-                let mut file_path = path::Path::new(&file).to_path_buf();
-                if file_path.is_relative() {
-                    file_path = path::Path::new(".").join(file_path).absolutize()?.to_path_buf();
+                match line.strip_prefix(IMPORT_PREFIX) {
+                    Some(file) => Line::Import(file.to_string()),
+                    None if line.is_empty() => Line::Text(String::new()),
+                    None => Line::Text(self.resolve_tags(line))
                 }
+            })
+            .collect();
 
-                file_path.set_extension("md");
+        Source { code, lines }
+    }
 
-                println!("[INFO] Importing {}", file_path.to_str().unwrap_or("<unknown path>"));
+    fn load_source(&self, file_path: &path::Path) -> Result<Source, String> {
+        self.filesystem.read_to_string(file_path)
+            .map(|code| self.parse_source(code))
+            .map_err(|err| err.to_string())
+    }
+
+    // The path of the file imported by `@import {file}` from a file in `dir`.
+    fn import_path(&self, dir: &path::Path, file: &str) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+        let mut file_path = path::Path::new(&file).to_path_buf();
+        if file_path.is_relative() {
+            file_path = file_path.absolutize_from(dir)?.to_path_buf();
+        }
+        file_path.set_extension("md");
+
+        Ok(file_path)
+    }
+
+    fn imports_of(&self, file_path: &path::Path, source: &Source) -> Vec<path::PathBuf> {
+        let dir = file_path.parent().unwrap_or(path::Path::new("/"));
+        source.lines.iter()
+            .filter_map(|line| match line {
+                Line::Import(file) => self.import_path(dir, file).ok(),
+                Line::Text(_) => None
+            })
+            .collect()
+    }
 
-                let file_contents = fs::read_to_string(&file_path)?;
-                let content = self.preprocess_markdown_recursively(
-                    file_path.to_str().unwrap_or("<unknown path>"),
-                    &file_contents,
-                    import_depth + 1
-                )?;
+    // Reads every file imported from the entry point, directly or not, a level of
+    // imports at a time with every file of the level read and parsed at once.
+    // Files that can't be read are kept with the reason, reported later at the
+    // `@import` that names them.
+    fn load_imports(&self, entry_path: &path::Path, entry: &Source) -> Sources {
+        let mut sources = Sources::new();
+        let mut level = self.imports_of(entry_path, entry);
+        while !level.is_empty() {
+            level.sort();
+            level.dedup();
 
-                for line in content.lines().rev() {
-                    result.push(line.to_string());
+            let loaded: Vec<(path::PathBuf, Result<Source, String>)> = self.jobs.run(|| {
+                level.into_par_iter()
+                    .map(|file_path| {
+                        let source = self.load_source(&file_path);
+                        (file_path, source)
+                    })
+                    .collect()
+            });
+
+            level = vec![];
+            for (file_path, source) in loaded {
+                if let Ok(source) = &source {
+                    level.extend(self.imports_of(&file_path, source).into_iter()
+                        .filter(|import| !sources.contains_key(import) && *import != file_path));
                 }
-            } else {
-                result.push(without_tags);
+                sources.insert(file_path, source);
+            }
+            level.retain(|import| !sources.contains_key(import));
+        }
+
+        sources
+    }
+
+    // `import_stack` holds the canonical path of every file being imported, from
+    // the entry point down to `file_name`. Imported files are taken from
+    // `sources`, loaded beforehand.
+    // Returns the preprocessed lines along with the source map for them.
+    fn preprocess_markdown_recursively(&self, file_name: &str, source: &Source, sources: &Sources, import_stack: &mut Vec<path::PathBuf>) -> Result<(Vec<String>, SourceMap), Box<dyn std::error::Error>> {
+        println!("[INFO] Preprocesando {}", file_name);
+        let file_path = path::Path::new(file_name);
+        let absolute_file_path = file_path.absolutize_from(&self.base_dir)?.to_path_buf();
+        let Some(actual_file_name) = absolute_file_path.file_name() else {
+            return Err(Box::new(Diagnostic::error("la ruta no es la de un archivo").in_file(&absolute_file_path)));
+        };
+        {
+            // The file being preprocessed is the last one in the stack, already
+            // canonical, which is how repeated imports are found below.
+            let canonical = import_stack.last().cloned().unwrap_or_else(|| absolute_file_path.clone());
+            let mut imported_files = self.imported_files.lock().unwrap();
+            if !imported_files.contains(&canonical) {
+                imported_files.push(canonical);
             }
         }
 
+        // Imports are relative to the file that has them.
+        let parent_dir = absolute_file_path.parent().ok_or("No se pudo leer el directorio.")?.to_path_buf();
+
+        let source_file = Rc::new(absolute_file_path.clone());
+        let source_line = |line: usize| SourceLine { file: source_file.clone(), line };
+        let mut source_map = SourceMap::default();
+
+        let mut result: Vec<String> = vec![];
         result.push(format!("<!-- Importado del archivo {} -->", actual_file_name.to_str().unwrap_or("<unknown path>")));
+        source_map.push(source_line(1));
+
+        for (line_index, (line, parsed)) in source.code.lines().zip(&source.lines).enumerate() {
+            match parsed {
+                Line::Import(file) => {
+                    let file_path = self.import_path(&parent_dir, file)?;
+                    self.reference(&file_path);
+
+                    println!("[INFO] Importing {}", file_path.to_str().unwrap_or("<unknown path>"));
+
+                    // A failed import is reported and skipped, so that every broken
+                    // import in the document shows up in a single build.
+                    let unreadable = |reason: String| ImportError::Unreadable { file: file.to_string(), reason };
+                    let checked = self.check_import(file, &file_path, import_stack).and_then(|canonical| {
+                        match sources.get(&file_path) {
+                            Some(Ok(imported)) => Ok((canonical, Cow::Borrowed(imported))),
+                            Some(Err(reason)) => Err(unreadable(reason.clone())),
+                            None => self.load_source(&file_path).map(|imported| (canonical, Cow::Owned(imported))).map_err(unreadable)
+                        }
+                    });
+
+                    let (canonical, imported) = match checked {
+                        Ok(checked) => checked,
+                        Err(err) => {
+                            self.diagnostics.lock().unwrap().push(
+                                Diagnostic::error(err.to_string())
+                                    .in_file(&absolute_file_path)
+                                    .at(line_index + 1, IMPORT_PREFIX.len() + 1)
+                                    .with_snippet(line, file.chars().count())
+                            );
+                            continue;
+                        }
+                    };
+
+                    if self.imported_files.lock().unwrap().contains(&canonical) {
+                        self.diagnostics.lock().unwrap().push(
+                            Diagnostic::warning(format!("{file} ya fue importado antes, su contenido se repetirá"))
+                                .in_file(&absolute_file_path)
+                                .at(line_index + 1, IMPORT_PREFIX.len() + 1)
+                                .with_snippet(line, file.chars().count())
+                        );
+                    }
+
+                    import_stack.push(canonical);
+                    let content = self.preprocess_markdown_recursively(
+                        file_path.to_str().unwrap_or("<unknown path>"),
+                        &imported,
+                        sources,
+                        import_stack
+                    );
+                    import_stack.pop();
+
+                    let (content, content_map) = content?;
+                    result.extend(content);
+                    source_map.extend(content_map);
+                },
+                Line::Text(text) => {
+                    result.push(text.clone());
+                    source_map.push(source_line(line_index + 1));
+                }
+            }
+        }
+
+        result.push(format!("<!-- fin del archivo {} -->", actual_file_name.to_str().unwrap_or("<unknown path>")));
+        source_map.push(source_line(source.code.lines().count().max(1)));
     
-        result.reverse();
-        Ok(result.join("\n"))
+        Ok((result, source_map))
     }
     
-    pub fn preprocess_markdown(&self, name: &str, code: &str) -> Result<String, Box<dyn std::error::Error>> {
-        self.preprocess_markdown_recursively(name, code, 0)
+    // Returns the preprocessed markdown and a map from each of its lines to the
+    // file and line they came from.
+    pub fn preprocess_markdown(&self, name: &str, code: &str) -> Result<(String, SourceMap), Box<dyn std::error::Error>> {
+        *self.diagnostics.lock().unwrap() = Diagnostics::new();
+        self.imported_files.lock().unwrap().clear();
+        self.referenced_files.lock().unwrap().clear();
+
+        let entry_path = path::Path::new(name).absolutize_from(&self.base_dir)?.to_path_buf();
+        self.reference(&entry_path);
+        let root = entry_path.parent().map(path::Path::to_path_buf).unwrap_or_default();
+        // The entry point may only exist as `code`, in which case nothing can import it.
+        let entry = match self.filesystem.canonicalize(&entry_path) {
+            Ok(entry) => entry,
+            Err(_) => entry_path.clone()
+        };
+        let mut import_stack = vec![entry];
+
+        let source = self.parse_source(code.to_string());
+        let sources = self.load_imports(&entry_path, &source);
+        let (lines, lines_map) = self.preprocess_markdown_recursively(name, &source, &sources, &mut import_stack)?;
+
+        let mut source_map = SourceMap::new(root);
+        source_map.extend(lines_map);
+        let preprocessed = lines.join("\n");
+
+        let diagnostics = self.diagnostics.lock().unwrap();
+        if diagnostics.has_errors() {
+            return Err(Box::new(diagnostics.clone()));
+        }
+
+        Ok((preprocessed, source_map))
     }
 }
 
@@ -162,46 +582,696 @@ fn iter_nodes<'a, F>(node: &'a AstNode<'a>, f: &F) -> Result<(), Box<dyn std::er
     Ok(())
 }
 
-pub fn markdown_to_html(md: &str) -> Result<String, Box<dyn std::error::Error>> {
+// Text of a node without any markup, as comrak does for heading ids.
+pub fn collect_text<'a>(node: &'a AstNode<'a>, output: &mut String) {
+    match node.data.borrow().value {
+        NodeValue::Text(ref literal) => output.push_str(literal),
+        NodeValue::Code(ref code) => output.push_str(&code.literal),
+        NodeValue::LineBreak | NodeValue::SoftBreak => output.push(' '),
+        _ => {
+            for child in node.children() {
+                collect_text(child, output);
+            }
+        }
+    }
+}
+
+// Whether the node is a paragraph containing only the given directive.
+fn is_directive<'a>(node: &'a AstNode<'a>, directive: &str) -> bool {
+    if !matches!(node.data.borrow().value, NodeValue::Paragraph) {
+        return false;
+    }
+
+    let mut text = String::new();
+    collect_text(node, &mut text);
+    text.trim() == directive
+}
+
+fn replace_with_html<'a>(node: &'a AstNode<'a>, html: String) {
+    for child in node.children().collect::<Vec<_>>() {
+        child.detach();
+    }
+
+    node.data.borrow_mut().value = NodeValue::HtmlBlock(NodeHtmlBlock { block_type: 6, literal: html });
+}
+
+// Renders headings with the ids and numbers collected beforehand, in document
+// order, so that the headings, the table of contents and the references agree.
+struct AnchoredHeadings {
+    headings: Mutex<VecDeque<Heading>>,
+    show_numbers: bool
+}
+
+impl HeadingAdapter for AnchoredHeadings {
+    fn enter(&self, output: &mut dyn Write, heading: &HeadingMeta, sourcepos: Option<Sourcepos>) -> std::io::Result<()> {
+        let anchored = self.headings.lock().unwrap().pop_front();
+
+        write!(output, "<h{}", heading.level)?;
+        if let Some(anchored) = &anchored {
+            write!(output, " id=\"{}\"", html_generation::escape_attribute(&anchored.id))?;
+        }
+        if let Some(sourcepos) = sourcepos {
+            write!(output, " data-sourcepos=\"{sourcepos}\"")?;
+        }
+        write!(output, ">")?;
+
+        match anchored {
+            Some(anchored) if self.show_numbers => write!(output, "<span class='heading-number'>{}</span> ", anchored.number),
+            _ => Ok(())
+        }
+    }
+
+    fn exit(&self, output: &mut dyn Write, heading: &HeadingMeta) -> std::io::Result<()> {
+        writeln!(output, "</h{}>", heading.level)
+    }
+}
+
+// Options for `markdown_to_html` that come from the project.
+#[derive(Default)]
+pub struct MarkdownOptions<'a> {
+    pub toc: Option<&'a TocConfig>,
+    // Whether headings are rendered with their number. They are numbered anyway,
+    // for references.
+    pub number_headings: bool,
+    pub bibliography: Option<&'a Bibliography>,
+    pub highlight: Option<&'a HighlightConfig>,
+    // Draw the `mermaid` blocks and those of the languages in `diagrams`.
+    // Without them the blocks are left as code.
+    pub mermaid: Option<&'a Mermaid<'a>>,
+    pub diagrams: Option<&'a Diagrams<'a>>,
+    // The language of the document, for the labels of figures and tables.
+    pub language: Option<&'a str>,
+    // How many diagram tools run at the same time.
+    pub jobs: Jobs
+}
+
+// Numbers a heading of `level` given the previous headings' numbers, counting
+// from `base_level`, the shallowest level in the document.
+fn heading_number(counters: &mut [usize; 7], level: u8, base_level: u8) -> String {
+    let level = level as usize;
+    counters[level] += 1;
+    for counter in counters.iter_mut().skip(level + 1) {
+        *counter = 0;
+    }
+
+    counters[base_level as usize..=level].iter()
+        .map(|counter| counter.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// The HTML for a markdown document, and what was learned while rendering it.
+pub struct RenderedMarkdown {
+    pub html: String,
+    pub headings: Vec<Heading>
+}
+
+// Replaces comrak's `data-sourcepos="line:col-line:col"`, which points into the
+// preprocessed markdown, with `data-source="file:line"` from the source map.
+fn resolve_sourcepos(html: &str, source_map: &SourceMap) -> String {
+    static SOURCEPOS: &str = " data-sourcepos=\"";
+
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(SOURCEPOS) {
+        result.push_str(&rest[..start]);
+        let value_start = start + SOURCEPOS.len();
+        let Some(value_len) = rest[value_start..].find('"') else {
+            rest = &rest[start..];
+            break;
+        };
+
+        let value = &rest[value_start..value_start + value_len];
+        let line = value.split(':').next().and_then(|line| line.parse::<usize>().ok());
+        if let Some(source) = line.and_then(|line| source_map.display(line)) {
+            result.push_str(&format!(" data-source=\"{}\"", html_generation::escape_attribute(&source)));
+        }
+
+        rest = &rest[value_start + value_len + 1..];
+    }
+    result.push_str(rest);
+
+    result
+}
+
+fn located(diagnostic: Diagnostic, line: usize, source_map: &SourceMap) -> Diagnostic {
+    match source_map.lookup(line) {
+        Some(source) => diagnostic.in_file(source.file.as_ref()).at(source.line, 1),
+        None => diagnostic
+    }
+}
+
+// A code block to be drawn as a diagram.
+struct DiagramBlock<'a> {
+    node: &'a AstNode<'a>,
+    info: DiagramInfo,
+    code: String,
+    line: usize
+}
+
+// Puts the drawn diagram in place of its block, or reports why it failed at the
+// line the tool complains about, or else at the start of the block.
+fn place_diagram(block: &DiagramBlock, result: DiagramResult, source_map: &SourceMap, diagnostics: &mut Diagnostics) {
+    match result {
+        Ok(image) => replace_with_html(block.node, diagrams::figure_html(&block.info, source_map.display(block.line), &image)),
+        Err(err) => {
+            // The code starts on the line after the fence.
+            let code_line = err.line.and_then(|number| block.code.lines().nth(number.checked_sub(1)?).map(|text| (number, text)));
+            let diagnostic = Diagnostic::error(format!("No se pudo generar el diagrama {}: {}", block.info.language, err.message));
+            let error_line = code_line.map_or(block.line, |(number, _)| block.line + number);
+            let indent = code_line.map_or(0, |(_, text)| text.chars().take_while(|c| c.is_whitespace()).count());
+            let diagnostic = match source_map.lookup(error_line) {
+                Some(source) => diagnostic.in_file(source.file.as_ref()).at(source.line, indent + 1),
+                None => diagnostic
+            };
+            diagnostics.push(match code_line {
+                Some((_, text)) => diagnostic.with_snippet(text, text.trim().chars().count().max(1)),
+                None => diagnostic
+            });
+            // Already reported, so not highlighted as code too.
+            replace_with_html(block.node, String::new());
+        }
+    }
+}
+
+// Replaces the code blocks written in a diagram language with their drawing.
+// `mermaid` ones are drawn together, so that the renderer can do it in as few
+// runs as possible; the rest one by one by their command. Every tool runs at the
+// same time, as many at once as there are jobs, and the drawings are placed once
+// they are all done, in the order of the document. When a tool can't run at all
+// it is reported once, at its first block.
+fn render_diagrams<'a>(root: &'a AstNode<'a>, markdown_options: &MarkdownOptions, source_map: &SourceMap, diagnostics: &mut Diagnostics) {
+    let mut mermaid_blocks = vec![];
+    let mut command_blocks = vec![];
+    for node in root.descendants() {
+        let (info, code) = match node.data.borrow().value {
+            NodeValue::CodeBlock(ref code) => (code.info.clone(), code.literal.clone()),
+            _ => continue
+        };
+        let language = diagrams::language(&info);
+        let is_mermaid = language == diagrams::MERMAID && markdown_options.mermaid.is_some();
+        if !is_mermaid && !markdown_options.diagrams.is_some_and(|diagrams| diagrams.handles(language)) {
+            continue;
+        }
+
+        let line = node.data.borrow().sourcepos.start.line;
+        let info = match diagrams::parse_info(&info) {
+            Ok(info) => info,
+            Err(err) => {
+                diagnostics.push(located(Diagnostic::error(format!("Bloque de diagrama inválido: {err}")), line, source_map));
+                replace_with_html(node, String::new());
+                continue;
+            }
+        };
+        let block = DiagramBlock { node, info, code, line };
+        if is_mermaid {
+            mermaid_blocks.push(block);
+        } else {
+            command_blocks.push(block);
+        }
+    }
+
+    let mermaid = markdown_options.mermaid.filter(|_| !mermaid_blocks.is_empty());
+    let renderers = markdown_options.diagrams;
+    let mermaid_codes: Vec<&str> = mermaid_blocks.iter().map(|block| block.code.as_str()).collect();
+    let commands: Vec<(&str, &str)> = command_blocks.iter()
+        .map(|block| (block.info.language.as_str(), block.code.as_str()))
+        .collect();
+    let (mermaid_results, command_results) = markdown_options.jobs.run(|| rayon::join(
+        || mermaid.map(|mermaid| mermaid.render(&mermaid_codes)),
+        || match renderers {
+            Some(renderers) => commands.par_iter().map(|(language, code)| renderers.render(language, code)).collect(),
+            None => vec![]
+        }
+    ));
+
+    if let (Some(results), Some(first)) = (mermaid_results, mermaid_blocks.first()) {
+        match results {
+            Ok(results) => {
+                for (block, result) in mermaid_blocks.iter().zip(results) {
+                    place_diagram(block, result, source_map, diagnostics);
+                }
+            },
+            Err(err) => {
+                diagnostics.push(located(
+                    Diagnostic::error(format!("No se pudieron generar los diagramas mermaid: {err}")),
+                    first.line,
+                    source_map
+                ));
+                for block in &mermaid_blocks {
+                    replace_with_html(block.node, String::new());
+                }
+            }
+        }
+    }
+
+    let mut unavailable = HashSet::new();
+    for (block, result) in command_blocks.iter().zip(command_results) {
+        if unavailable.contains(&block.info.language) {
+            replace_with_html(block.node, String::new());
+            continue;
+        }
+        match result {
+            Ok(result) => place_diagram(block, result, source_map, diagnostics),
+            Err(err) => {
+                unavailable.insert(block.info.language.clone());
+                replace_with_html(block.node, String::new());
+                diagnostics.push(located(
+                    Diagnostic::error(format!("No se pudieron generar los diagramas {}: {err}", block.info.language)),
+                    block.line,
+                    source_map
+                ));
+            }
+        }
+    }
+}
+
+// Renders the preprocessed markdown. Every heading gets a stable id and a number,
+// `@ref(name)`s are resolved and citations are rendered, followed by the list of
+// references. With a `toc` config, or when the document has an `@toc`
+// directive, a table of contents is generated from the headings.
+pub fn markdown_to_html(md: &str, source_map: &SourceMap, markdown_options: &MarkdownOptions) -> Result<RenderedMarkdown, Box<dyn std::error::Error>> {
     // The returned nodes are created in the supplied Arena, and are bound by its lifetime.
     let arena = Arena::new();
 
     let mut options = Options::default();
     options.render.unsafe_ = true;
+    options.render.sourcepos = true;
+    options.extension.table = true;
+    options.extension.math_dollars = true;
     let root = parse_document(
         &arena,
         md,
         &options);
 
+    // Diagrams, formulas and code blocks that fail to render are reported together
+    // once the walk is done.
+    let diagnostics = RefCell::new(Diagnostics::new());
+
+    render_diagrams(root, markdown_options, source_map, &mut diagnostics.borrow_mut());
+
+    // Loading the grammars is slow, so only done when there is code to highlight.
+    // Diagrams are no longer code by now.
+    let has_code = root.descendants().any(|node| {
+        matches!(node.data.borrow().value, NodeValue::CodeBlock(ref code) if code.fenced)
+    });
+    let highlighter = if has_code {
+        let default_config = HighlightConfig::default();
+        let config = markdown_options.highlight.unwrap_or(&default_config);
+        Some(Highlighter::new(config).map_err(|err| Diagnostic::error(format!("No se pudo preparar el resaltado de código: {err}")))?)
+    } else {
+        None
+    };
+
     iter_nodes(root, &|node| {
-        // Find a block code, check if the language is mermaid, and replace it with the svg.
-        let should_replace_with_svg = if let NodeValue::CodeBlock(ref code) = node.data.borrow().value {
-            code.info.starts_with("mermaid")
-        } else {
-            false
+        // Render math to MathML, reporting formulas that can't be understood.
+        let math = match node.data.borrow().value {
+            NodeValue::Math(ref math) => Some(math.clone()),
+            _ => None
         };
-
-        if should_replace_with_svg {
-            println!("[INFO] Generating graph");
-            let code = {
-                let value = &node.data.borrow().value;
-                match value {
-                    NodeValue::CodeBlock(code) => code.clone(),
-                    _ => unreachable!(),
+        if let Some(math) = math {
+            match math::math_to_html(&math.literal, math.display_math) {
+                Ok(html) => node.data.borrow_mut().value = NodeValue::HtmlInline(html),
+                Err(err) => {
+                    let line = node.ancestors()
+                        .map(|ancestor| ancestor.data.borrow().sourcepos.start.line)
+                        .find(|line| *line > 0)
+                        .unwrap_or(0);
+                    let diagnostic = Diagnostic::error(format!("No se pudo interpretar la fórmula `{}`: {err}", math.literal.trim()));
+                    diagnostics.borrow_mut().push(match source_map.lookup(line) {
+                        Some(source) => diagnostic.in_file(source.file.as_ref()).at(source.line, 1),
+                        None => diagnostic
+                    });
                 }
-            };
+            }
+            return Ok(());
+        }
 
-            let svg = html_generation::mermaid_to_svg(&code.literal)?;
-            let svg = format!("<figure class='mermaid-graph'>{}</figure>", svg);
-            node.data.borrow_mut().value = NodeValue::HtmlInline(svg.into());
-            println!("[INFO] Graph generated");
+        // Every other fenced block is highlighted by its language.
+        let fenced_code = match node.data.borrow().value {
+            NodeValue::CodeBlock(ref code) if code.fenced => Some(code.clone()),
+            _ => None
+        };
+        if let (Some(code), Some(highlighter)) = (fenced_code, &highlighter) {
+            let line = node.data.borrow().sourcepos.start.line;
+            match highlighter.code_block_html(&code.info, &code.literal) {
+                Ok(html) => {
+                    let source = source_map.display(line)
+                        .map(|source| format!(" data-source='{}'", html_generation::escape_attribute(&source)))
+                        .unwrap_or_default();
+                    node.data.borrow_mut().value = NodeValue::HtmlBlock(NodeHtmlBlock {
+                        block_type: 6,
+                        literal: format!("<div class='code-block'{source}>{html}</div>\n")
+                    });
+                },
+                Err(err) => {
+                    let diagnostic = Diagnostic::error(format!("Bloque de código inválido: {err}"));
+                    diagnostics.borrow_mut().push(match source_map.lookup(line) {
+                        Some(source) => diagnostic.in_file(source.file.as_ref()).at(source.line, 1),
+                        None => diagnostic
+                    });
+                }
+            }
         }
 
         Ok(())
     })?;
 
+    let mut diagnostics = diagnostics.into_inner();
+    math::number_equations(root);
+
+    // Collect the headings, giving them the same ids GitHub would, and find
+    // where the table of contents goes.
+    let base_level = root.descendants()
+        .filter_map(|node| match node.data.borrow().value {
+            NodeValue::Heading(ref heading) => Some(heading.level),
+            _ => None
+        })
+        .min()
+        .unwrap_or(1);
+
+    let mut anchorizer = Anchorizer::new();
+    let mut counters = [0; 7];
+    let mut headings = vec![];
+    let mut toc_nodes = vec![];
+    let mut bibliography_nodes = vec![];
+    let mut mainmatter_nodes = vec![];
+    let mut figure_list_nodes = vec![];
+    let mut table_list_nodes = vec![];
+    for node in root.descendants() {
+        if let NodeValue::Heading(ref heading) = node.data.borrow().value {
+            let mut text = String::new();
+            collect_text(node, &mut text);
+            let text = text.trim().to_string();
+            let id = anchorizer.anchorize(text.clone());
+            let number = heading_number(&mut counters, heading.level, base_level);
+            headings.push(Heading { level: heading.level, text, id, number });
+        } else if is_directive(node, toc::TOC_DIRECTIVE) {
+            toc_nodes.push(node);
+        } else if is_directive(node, bibliography::BIBLIOGRAPHY_DIRECTIVE) {
+            bibliography_nodes.push(node);
+        } else if is_directive(node, header_footer::MAINMATTER_DIRECTIVE) {
+            mainmatter_nodes.push(node);
+        } else if is_directive(node, toc::LIST_OF_FIGURES_DIRECTIVE) {
+            figure_list_nodes.push(node);
+        } else if is_directive(node, toc::LIST_OF_TABLES_DIRECTIVE) {
+            table_list_nodes.push(node);
+        }
+    }
+
+    for node in mainmatter_nodes.iter().skip(1) {
+        let diagnostic = Diagnostic::warning("`@mainmatter` aparece más de una vez, solo cuenta la primera");
+        diagnostics.push(match source_map.lookup(node.data.borrow().sourcepos.start.line) {
+            Some(source) => diagnostic.in_file(source.file.as_ref()).at(source.line, 1),
+            None => diagnostic
+        });
+    }
+
+    let labels = Labels::for_language(markdown_options.language);
+    let targets = cross_refs::collect_targets(root, &headings, source_map, &mut diagnostics);
+    cross_refs::resolve_references(root, &targets, &labels, source_map, &mut diagnostics);
+    let bibliography_html = bibliography::resolve_citations(root, markdown_options.bibliography, source_map, &mut diagnostics);
+
+    // Captions are placed once the references are resolved, since they change
+    // the blocks the numbering is based on.
+    let captioned = cross_refs::apply_captions(&arena, root, &headings, &labels);
+    let lists = [
+        (&figure_list_nodes, TargetKind::Figure, "list-of-figures", toc::LIST_OF_FIGURES_DIRECTIVE),
+        (&table_list_nodes, TargetKind::Table, "list-of-tables", toc::LIST_OF_TABLES_DIRECTIVE)
+    ];
+    let mut list_htmls = vec![];
+    for (nodes, kind, class, directive) in lists {
+        let entries: Vec<&Captioned> = captioned.iter().filter(|entry| entry.kind == kind).collect();
+        if let (Some(node), true) = (nodes.first(), entries.is_empty()) {
+            diagnostics.push(located(
+                Diagnostic::warning(format!("`{directive}` queda vacío, no hay ningún elemento con título")),
+                node.data.borrow().sourcepos.start.line,
+                source_map
+            ));
+        }
+
+        let configured_title = markdown_options.toc.and_then(|toc| match kind {
+            TargetKind::Figure => toc.figures_title(),
+            _ => toc.tables_title()
+        });
+        let default_title = if kind == TargetKind::Figure { labels.figures_title } else { labels.tables_title };
+        list_htmls.push((nodes, toc::list_html(configured_title.unwrap_or(default_title), class, &entries, &labels)));
+    }
+
+    if diagnostics.has_errors() {
+        return Err(Box::new(diagnostics));
+    }
+    crate::diagnostics::report_warnings(&diagnostics);
+
+    let toc_config = match (markdown_options.toc, toc_nodes.is_empty()) {
+        (Some(config), _) => Some(config.clone()),
+        (None, false) => Some(TocConfig::default()),
+        (None, true) => None
+    };
+    let toc_html = toc_config.as_ref().map(|config| toc::toc_html(&headings, config, markdown_options.number_headings));
+
+    for node in &toc_nodes {
+        replace_with_html(node, toc_html.clone().unwrap_or_default());
+    }
+    for node in &bibliography_nodes {
+        replace_with_html(node, bibliography_html.clone().unwrap_or_default());
+    }
+    for (index, node) in mainmatter_nodes.iter().enumerate() {
+        replace_with_html(node, if index == 0 { header_footer::mainmatter_html() } else { String::new() });
+    }
+    for (nodes, list_html) in list_htmls {
+        for node in nodes {
+            replace_with_html(node, list_html.clone());
+        }
+    }
+
+    let adapter = AnchoredHeadings {
+        headings: Mutex::new(headings.iter().cloned().collect()),
+        show_numbers: markdown_options.number_headings
+    };
+    let mut plugins = Plugins::default();
+    plugins.render.heading_adapter = Some(&adapter);
+
     let mut html = vec![];
-    format_html(root, &options, &mut html).unwrap();
+    format_html_with_plugins(root, &options, &mut html, &plugins).unwrap();
+    let mut html = resolve_sourcepos(&String::from_utf8(html).unwrap(), source_map);
+
+    // Without a directive, the table of contents goes at the start of the document.
+    if let (Some(toc_html), true) = (toc_html, toc_nodes.is_empty()) {
+        html = toc_html + &html;
+    }
+
+    // Same for the references, which go at the end.
+    if let (Some(bibliography_html), true) = (bibliography_html, bibliography_nodes.is_empty()) {
+        html.push_str(&bibliography_html);
+    }
+
+    Ok(RenderedMarkdown { html, headings })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    fn messages(preprocessor: &MarkdownPreprocessor) -> Vec<String> {
+        preprocessor.diagnostics().iter().map(|diagnostic| diagnostic.message.clone()).collect()
+    }
+
+    // The entry point is found from `base_dir` and not from the working directory
+    // of the process, also when `base_dir` goes through a link.
+    #[cfg(unix)]
+    #[test]
+    fn entry_point_importing_itself_is_a_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("proyecto");
+        let link = dir.path().join("enlace");
+        std::fs::create_dir(&project).unwrap();
+        std::fs::write(project.join("start.md"), "# Inicio\n@import start\n").unwrap();
+        std::os::unix::fs::symlink(&project, &link).unwrap();
+        assert_ne!(std::env::current_dir().unwrap(), link);
+
+        let preprocessor = MarkdownPreprocessor::new().with_base_dir(&link);
+        let code = std::fs::read_to_string(project.join("start.md")).unwrap();
+
+        assert!(preprocessor.preprocess_markdown("start.md", &code).is_err());
+        assert_eq!(messages(&preprocessor), vec!["ciclo de importes: start.md -> start.md"]);
+    }
+
+    // Files read by a previous call are not imported again by the next one.
+    #[test]
+    fn preprocessing_twice_warns_nothing() {
+        let filesystem = MemoryFs::new()
+            .with_file("/doc/start.md", "@import intro")
+            .with_file("/doc/intro.md", "Introducción");
+        let preprocessor = MarkdownPreprocessor::new()
+            .with_filesystem(Arc::new(filesystem))
+            .with_base_dir(path::Path::new("/doc"));
+
+        for _ in 0..2 {
+            let (markdown, _) = preprocessor.preprocess_markdown("start.md", "@import intro").unwrap();
+            assert!(markdown.contains("Introducción"));
+            assert_eq!(messages(&preprocessor), Vec::<String>::new());
+        }
+        assert_eq!(preprocessor.imported_files().len(), 2);
+    }
+
+    fn preprocess(code: &str) -> String {
+        let preprocessor = MarkdownPreprocessor::new()
+            .with_filesystem(Arc::new(MemoryFs::new()))
+            .with_base_dir(path::Path::new("/doc"));
+        preprocessor.preprocess_markdown("start.md", code).unwrap().0
+    }
+
+    #[test]
+    fn tags_in_inline_code_are_kept() {
+        let markdown = preprocess("Ver @ref(fig:a), no `@ref(fig:x)` ni ``@#id `@ref(b)` ``");
+
+        assert!(markdown.contains(&format!("Ver {},", cross_refs::ref_tag("fig:a"))));
+        assert!(markdown.contains("no `@ref(fig:x)` ni ``@#id `@ref(b)` ``"));
+    }
 
-    Ok(String::from_utf8(html).unwrap())
-}
\ No newline at end of file
+    #[test]
+    fn fenced_code_is_kept_as_written() {
+        let code = "```markdown\n@ref(fig:x) @#id\n@import otro\n````\n~~~\n```\n@ref(tab:y)\n~~~\n@ref(fig:z)";
+        let markdown = preprocess(code);
+
+        assert!(markdown.contains("@ref(fig:x) @#id\n@import otro\n````\n~~~\n```\n@ref(tab:y)\n~~~\n"));
+        assert!(markdown.contains(&cross_refs::ref_tag("fig:z")));
+    }
+
+    #[test]
+    fn citations_in_code_are_kept() {
+        let markdown = preprocess("Según [@knuth1984], `[@lamport1994]`\n```\n[@knuth1984, p. 3]\n```");
+
+        assert!(!markdown.contains("[@knuth1984]"));
+        assert!(markdown.contains("`[@lamport1994]`\n```\n[@knuth1984, p. 3]\n```"));
+    }
+
+    fn memory_preprocessor(files: &[(&str, &str)]) -> MarkdownPreprocessor {
+        let filesystem = files.iter().fold(MemoryFs::new(), |filesystem, (file, code)| filesystem.with_file(file, *code));
+        MarkdownPreprocessor::new()
+            .with_filesystem(Arc::new(filesystem))
+            .with_base_dir(path::Path::new("/doc"))
+    }
+
+    #[test]
+    fn imports_from_memory() {
+        let preprocessor = memory_preprocessor(&[
+            ("/doc/start.md", "# Inicio\n@import capitulos/uno"),
+            ("/doc/capitulos/uno.md", "Uno\n@import ../anexo"),
+            ("/doc/anexo.md", "Anexo")
+        ]);
+
+        let (markdown, source_map) = preprocessor.preprocess_markdown("start.md", "# Inicio\n@import capitulos/uno").unwrap();
+        let lines: Vec<&str> = markdown.lines().filter(|line| !line.starts_with("<!--")).collect();
+
+        assert_eq!(lines, vec!["# Inicio", "Uno", "Anexo"]);
+        let anexo = markdown.lines().position(|line| line == "Anexo").unwrap();
+        let source = source_map.lookup(anexo + 1).unwrap();
+        assert_eq!((source.file.as_path(), source.line), (path::Path::new("/doc/anexo.md"), 1));
+        assert_eq!(preprocessor.imported_files(), vec![
+            path::PathBuf::from("/doc/start.md"),
+            path::PathBuf::from("/doc/capitulos/uno.md"),
+            path::PathBuf::from("/doc/anexo.md")
+        ]);
+        assert_eq!(messages(&preprocessor), Vec::<String>::new());
+    }
+
+    #[test]
+    fn import_cycle_is_an_error() {
+        let preprocessor = memory_preprocessor(&[
+            ("/doc/start.md", "@import uno"),
+            ("/doc/uno.md", "@import dos"),
+            ("/doc/dos.md", "@import uno")
+        ]);
+
+        assert!(preprocessor.preprocess_markdown("start.md", "@import uno").is_err());
+        let diagnostics = preprocessor.diagnostics();
+        let cycle = diagnostics.iter().next().unwrap();
+        assert_eq!(cycle.message, "ciclo de importes: uno.md -> dos.md -> uno.md");
+        assert_eq!((cycle.file.as_deref(), cycle.line), (Some(path::Path::new("/doc/dos.md")), Some(1)));
+    }
+
+    #[test]
+    fn missing_import_is_an_error() {
+        let preprocessor = memory_preprocessor(&[("/doc/start.md", "@import falta")]);
+
+        assert!(preprocessor.preprocess_markdown("start.md", "@import falta").is_err());
+        assert!(messages(&preprocessor)[0].starts_with("No se pudo importar falta"));
+        assert_eq!(preprocessor.referenced_files(), vec![path::PathBuf::from("/doc/start.md"), path::PathBuf::from("/doc/falta.md")]);
+    }
+
+    #[test]
+    fn repeated_import_is_a_warning() {
+        let preprocessor = memory_preprocessor(&[
+            ("/doc/start.md", "@import uno\n@import uno"),
+            ("/doc/uno.md", "Uno")
+        ]);
+
+        let (markdown, _) = preprocessor.preprocess_markdown("start.md", "@import uno\n@import uno").unwrap();
+
+        assert_eq!(markdown.lines().filter(|line| *line == "Uno").count(), 2);
+        assert_eq!(messages(&preprocessor), vec!["uno ya fue importado antes, su contenido se repetirá"]);
+        assert!(!preprocessor.diagnostics().has_errors());
+    }
+
+    // Imports are compared by their canonical path, also when the project is
+    // reached through a link.
+    #[cfg(unix)]
+    #[test]
+    fn repeated_import_through_a_link_is_a_warning() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("proyecto");
+        let link = dir.path().join("enlace");
+        std::fs::create_dir(&project).unwrap();
+        std::fs::write(project.join("uno.md"), "Uno").unwrap();
+        std::os::unix::fs::symlink(&project, &link).unwrap();
+
+        let preprocessor = MarkdownPreprocessor::new().with_base_dir(&link);
+        preprocessor.preprocess_markdown("start.md", "@import uno\n@import uno").unwrap();
+
+        assert_eq!(messages(&preprocessor), vec!["uno ya fue importado antes, su contenido se repetirá"]);
+        assert_eq!(preprocessor.imported_files()[1], project.canonicalize().unwrap().join("uno.md"));
+    }
+
+    #[test]
+    fn entry_point_that_is_not_a_file_is_an_error() {
+        let preprocessor = memory_preprocessor(&[]);
+
+        let err = preprocessor.preprocess_markdown("..", "Texto").unwrap_err();
+
+        assert_eq!(err.to_string(), "error: la ruta no es la de un archivo\n --> /");
+    }
+
+    #[test]
+    fn html_points_at_the_markdown_it_came_from() {
+        let start = "# Inicio\n\n@import capitulos/uno\n\nFin";
+        let preprocessor = memory_preprocessor(&[
+            ("/doc/start.md", start),
+            ("/doc/capitulos/uno.md", "Primero\n\nSegundo")
+        ]);
+        let (markdown, source_map) = preprocessor.preprocess_markdown("start.md", start).unwrap();
+
+        let options = MarkdownOptions {
+            toc: None,
+            number_headings: false,
+            bibliography: None,
+            highlight: None,
+            mermaid: None,
+            diagrams: None,
+            language: None,
+            jobs: Jobs::default()
+        };
+        let html = markdown_to_html(&markdown, &source_map, &options).unwrap().html;
+
+        assert!(!html.contains("data-sourcepos"));
+        for element in [
+            "<h1 id=\"inicio\" data-source=\"start.md:1\">Inicio</h1>",
+            "<p data-source=\"capitulos/uno.md:1\">Primero</p>",
+            "<p data-source=\"capitulos/uno.md:3\">Segundo</p>",
+            "<p data-source=\"start.md:5\">Fin</p>"
+        ] {
+            assert!(html.contains(element), "{element}\n{html}");
+        }
+    }
+}
@@ -0,0 +1,475 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::{fs, path};
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use url::Url;
+
+use crate::browser::BrowserSession;
+use crate::diagrams::{self, DiagramError, DiagramResult};
+use crate::filesystem::{DiskFs, FileSystem};
+use crate::manifest;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MermaidRenderer {
+    #[default]
+    Mmdc,
+    Browser
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MermaidTheme {
+    Default,
+    Neutral,
+    Dark,
+    Forest,
+    Base
+}
+
+/* The `mermaid` section of `project.thn`, all of it optional:
+{
+    "mermaid": {
+        "renderer": "mmdc",
+        "command": "mmdc",
+        "batch": true,
+        "script": "./assets/js/mermaid.min.js",
+        "theme": "neutral",
+        "config": { "flowchart": { "curve": "basis" } }
+    }
+}
+
+With the "mmdc" renderer (the default) diagrams are drawn by the mermaid CLI,
+`command`, which is `mmdc` if not given and runs in the project directory. With `batch` (true by default) every
+diagram that changed is drawn in a single run instead of one run per diagram.
+With "browser" they are drawn by the Chrome that prints the PDF, loading
+mermaid.js (version 10 or later) from `script`, which is then required.
+`theme` is one of "default", "neutral", "dark", "forest" or "base", and
+`config` is passed as is as the mermaid configuration.
+
+Drawn diagrams are kept in `cache/mermaid` inside the build directory, named by
+a hash of their code, of this section and of the script or the program that
+draws them, and only drawn again when any of them changes. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MermaidConfig {
+    renderer: Option<MermaidRenderer>,
+    command: Option<String>,
+    batch: Option<bool>,
+    script: Option<String>,
+    theme: Option<MermaidTheme>,
+    config: Option<serde_json::Value>
+}
+
+impl MermaidConfig {
+    fn renderer(&self) -> MermaidRenderer {
+        self.renderer.unwrap_or_default()
+    }
+
+    fn command(&self) -> &str {
+        self.command.as_deref().unwrap_or("mmdc")
+    }
+
+    pub fn script(&self) -> Option<&str> {
+        self.script.as_deref()
+    }
+
+    // `command` when it is a file the renderer runs.
+    pub fn program_file(&self, dir: &path::Path) -> Option<path::PathBuf> {
+        match self.renderer() {
+            MermaidRenderer::Mmdc => diagrams::program_file(dir, self.command()),
+            MermaidRenderer::Browser => None
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.renderer() == MermaidRenderer::Browser && self.script.is_none() {
+            return Err("`mermaid.renderer` \"browser\" necesita la ruta de mermaid.js en `mermaid.script`".to_string());
+        }
+        if self.config.as_ref().is_some_and(|config| !config.is_object()) {
+            return Err("`mermaid.config` tiene que ser un objeto".to_string());
+        }
+
+        Ok(())
+    }
+
+    // The mermaid configuration, with the theme in it.
+    fn mermaid_config(&self) -> serde_json::Value {
+        let mut config = match &self.config {
+            Some(serde_json::Value::Object(config)) => config.clone(),
+            _ => serde_json::Map::new()
+        };
+        if let Some(theme) = self.theme {
+            config.insert("theme".to_string(), serde_json::to_value(theme).unwrap_or_default());
+        }
+
+        serde_json::Value::Object(config)
+    }
+}
+
+// The markdown file with every diagram of a batch, each in a `mermaid` block.
+fn batch_markdown(diagrams: &[&str]) -> String {
+    let mut markdown = String::new();
+    for code in diagrams {
+        // The fence has to be longer than any run of backticks in the code.
+        let longest = code.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+        let fence = "`".repeat(longest.max(2) + 1);
+        markdown.push_str(&format!("{fence}mermaid\n{}\n{fence}\n\n", code.trim_end()));
+    }
+
+    markdown
+}
+
+// Draws the mermaid diagrams of a build, going to the cache first.
+pub struct Mermaid<'a> {
+    config: &'a MermaidConfig,
+    // Resolved from `config.script`, for the browser renderer, and read from
+    // `filesystem` like the rest of the project.
+    script: Option<path::PathBuf>,
+    filesystem: Arc<dyn FileSystem>,
+    // Where `mmdc` runs, the project directory.
+    dir: path::PathBuf,
+    cache_dir: path::PathBuf,
+    browser: &'a BrowserSession,
+    // The hash of the script, or of `command` when it is a file, so that
+    // changing either draws every diagram again.
+    tool: String
+}
+
+impl<'a> Mermaid<'a> {
+    pub fn new(config: &'a MermaidConfig, script: Option<path::PathBuf>, filesystem: Arc<dyn FileSystem>, dir: &path::Path, cache_dir: &path::Path, browser: &'a BrowserSession) -> Mermaid<'a> {
+        let tool = match config.renderer() {
+            MermaidRenderer::Mmdc => config.program_file(dir).and_then(|program| manifest::hash_file(&DiskFs, &program)),
+            MermaidRenderer::Browser => script.as_ref().and_then(|script| manifest::hash_file(filesystem.as_ref(), script))
+        };
+
+        Mermaid {
+            config,
+            script,
+            filesystem,
+            dir: dir.to_path_buf(),
+            cache_dir: cache_dir.to_path_buf(),
+            browser,
+            tool: tool.unwrap_or_default()
+        }
+    }
+
+    fn cache_path(&self, code: &str) -> path::PathBuf {
+        let settings = serde_json::json!({
+            "renderer": self.config.renderer(),
+            "config": self.config.mermaid_config(),
+            "tool": self.tool
+        });
+
+        self.cache_dir.join(format!("{}.svg", diagrams::cache_name(&settings.to_string(), code)))
+    }
+
+    // The SVG of each diagram, in order, or why it couldn't be drawn. Fails as a
+    // whole when the renderer itself can't run.
+    pub fn render(&self, diagrams: &[&str]) -> Result<Vec<DiagramResult>> {
+        let mut results: Vec<Option<DiagramResult>> = diagrams.iter()
+            .map(|code| fs::read_to_string(self.cache_path(code)).ok().map(Ok))
+            .collect();
+
+        let pending: Vec<usize> = (0..diagrams.len()).filter(|index| results[*index].is_none()).collect();
+        println!("[INFO] Generating {} mermaid diagrams ({} cached)", diagrams.len(), diagrams.len() - pending.len());
+
+        if !pending.is_empty() {
+            let codes: Vec<&str> = pending.iter().map(|index| diagrams[*index]).collect();
+            let rendered = match self.config.renderer() {
+                MermaidRenderer::Mmdc => self.render_with_mmdc(&codes)?,
+                MermaidRenderer::Browser => self.render_in_browser(&codes)?
+            };
+            if rendered.len() != codes.len() {
+                anyhow::bail!("Se esperaban {} diagramas mermaid y se generaron {}", codes.len(), rendered.len());
+            }
+
+            fs::create_dir_all(&self.cache_dir).unwrap_or(());
+            for (index, result) in pending.into_iter().zip(rendered) {
+                if let Ok(svg) = &result {
+                    let cache_path = self.cache_path(diagrams[index]);
+                    fs::write(&cache_path, svg).unwrap_or_else(|_| {
+                        println!("[INFO] No se pudo guardar el diagrama en la caché: {}", cache_path.display())
+                    });
+                }
+                results[index] = Some(result);
+            }
+        }
+
+        Ok(results.into_iter()
+            .flatten()
+            .map(|result| result.map(|svg| diagrams::inline_svg(&svg).to_string()))
+            .collect())
+    }
+
+    fn mmdc(&self) -> Command {
+        let mut command = Command::new(diagrams::program_path(&self.dir, self.config.command()));
+        command.current_dir(&self.dir)
+            .arg("--backgroundColor").arg("transparent")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+
+    // Writes the mermaid configuration for `mmdc --configFile`.
+    fn write_config(&self, dir: &path::Path) -> Result<path::PathBuf> {
+        let config_path = dir.join("config.json");
+        fs::write(&config_path, self.config.mermaid_config().to_string())?;
+        Ok(config_path)
+    }
+
+    fn spawn_error(&self, err: std::io::Error) -> anyhow::Error {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => anyhow::anyhow!(
+                "No se encontró `{}`: se instala con `npm install -g @mermaid-js/mermaid-cli`, o se puede indicar otro con `mermaid.command`",
+                self.config.command()
+            ),
+            _ => anyhow::anyhow!("No se pudo ejecutar `{}`: {err}", self.config.command())
+        }
+    }
+
+    // In batches, one per job so that they run at the same time, or one by one,
+    // as many at once as there are jobs.
+    fn render_with_mmdc(&self, diagrams: &[&str]) -> Result<Vec<DiagramResult>> {
+        let dir = tempfile::tempdir()?;
+        let config_path = self.write_config(dir.path())?;
+
+        if !self.config.batch.unwrap_or(true) || diagrams.len() < 2 {
+            return self.render_each(diagrams, dir.path(), &config_path);
+        }
+
+        let batch_size = diagrams.len().div_ceil(rayon::current_num_threads());
+        let batches = diagrams.par_chunks(batch_size)
+            .enumerate()
+            .map(|(batch, codes)| match self.render_batch(batch, codes, dir.path(), &config_path)? {
+                Some(svgs) => Ok(svgs.into_iter().map(Ok).collect()),
+                None => {
+                    // Drawing them one by one tells which ones fail.
+                    println!("[INFO] Some mermaid diagrams failed, generating them one by one");
+                    self.render_each(codes, dir.path(), &config_path)
+                }
+            })
+            .collect::<Result<Vec<Vec<DiagramResult>>>>()?;
+
+        Ok(batches.into_iter().flatten().collect())
+    }
+
+    fn render_each(&self, diagrams: &[&str], dir: &path::Path, config_path: &path::Path) -> Result<Vec<DiagramResult>> {
+        diagrams.par_iter()
+            .map(|code| self.render_one(code, dir, config_path))
+            .collect()
+    }
+
+    fn render_one(&self, code: &str, dir: &path::Path, config_path: &path::Path) -> Result<DiagramResult> {
+        // Named apart from the ones being drawn at the same time, and removed when done.
+        let output_path = tempfile::Builder::new().prefix("graph-").suffix(".svg").tempfile_in(dir)?.into_temp_path();
+        let mut child = self.mmdc()
+            .arg("--input").arg("-")
+            .arg("--configFile").arg(config_path)
+            .arg("--output").arg(&output_path)
+            .spawn()
+            .map_err(|err| self.spawn_error(err))?;
+
+        let written = match child.stdin.as_mut() {
+            Some(child_stdin) => write!(child_stdin, "{}", code),
+            None => Ok(())
+        };
+        // Closes stdin so that mmdc knows the diagram is over.
+        let output = child.wait_with_output()?;
+        written?;
+        if !output.status.success() {
+            return Ok(Err(DiagramError::from_output(&String::from_utf8_lossy(&output.stderr))));
+        }
+
+        let svg = fs::read_to_string(&output_path)
+            .with_context(|| format!("`{}` no generó el diagrama", self.config.command()))?;
+
+        Ok(Ok(svg))
+    }
+
+    // Draws every diagram in a single run by passing mmdc a markdown file with all
+    // of them, `diagrams-{batch}.md`, which it turns into `diagrams-{batch}.out-1.svg`,
+    // `diagrams-{batch}.out-2.svg`... `None` if any of them failed.
+    fn render_batch(&self, batch: usize, diagrams: &[&str], dir: &path::Path, config_path: &path::Path) -> Result<Option<Vec<String>>> {
+        let input_path = dir.join(format!("diagrams-{batch}.md"));
+        let output_path = dir.join(format!("diagrams-{batch}.out.md"));
+        fs::write(&input_path, batch_markdown(diagrams))?;
+
+        let output = self.mmdc()
+            .arg("--input").arg(&input_path)
+            .arg("--configFile").arg(config_path)
+            .arg("--output").arg(&output_path)
+            .arg("--outputFormat").arg("svg")
+            .output()
+            .map_err(|err| self.spawn_error(err))?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        (1..=diagrams.len())
+            .map(|number| {
+                let svg_path = dir.join(format!("diagrams-{batch}.out-{number}.svg"));
+                fs::read_to_string(&svg_path)
+                    .with_context(|| format!("`{}` no generó el diagrama {}", self.config.command(), svg_path.display()))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    // Draws every diagram in a page of the shared Chrome that loads mermaid.js.
+    fn render_in_browser(&self, diagrams: &[&str]) -> Result<Vec<DiagramResult>> {
+        let script = self.script.as_ref()
+            .context("`mermaid.renderer` \"browser\" necesita la ruta de mermaid.js en `mermaid.script`")?;
+        if !self.filesystem.is_file(script) {
+            anyhow::bail!("No existe el script de mermaid `{}` indicado en `mermaid.script`", script.display());
+        }
+
+        // Copied next to the page, since the project may not be on disk.
+        let dir = tempfile::tempdir()?;
+        let script_contents = self.filesystem.read(script)
+            .with_context(|| format!("No se pudo leer el script de mermaid `{}`", script.display()))?;
+        fs::write(dir.path().join("mermaid.js"), script_contents)?;
+        let page_path = dir.path().join("mermaid.html");
+        fs::write(&page_path, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset='UTF-8'>\n<script src='mermaid.js'></script>\n</head>\n<body></body>\n</html>\n")?;
+        let page_url = Url::from_file_path(&page_path).map_err(|_| anyhow::anyhow!("Ruta inválida: {}", page_path.display()))?;
+
+        let tab = self.browser.new_tab()?;
+        tab.navigate_to(page_url.as_str())
+            .and_then(|tab| tab.wait_until_navigated())
+            .context("No se pudo abrir la página de mermaid.js")?;
+
+        let expression = format!(
+            r#"(async () => {{
+    if (typeof mermaid === "undefined") {{
+        return JSON.stringify({{ missing: true }});
+    }}
+    mermaid.initialize(Object.assign({{ startOnLoad: false }}, {config}));
+    const diagrams = {diagrams};
+    const results = [];
+    for (const [index, code] of diagrams.entries()) {{
+        try {{
+            const {{ svg }} = await mermaid.render("thener-mermaid-" + index, code);
+            results.push({{ svg }});
+        }} catch (err) {{
+            results.push({{ error: String((err && err.message) || err) }});
+        }}
+    }}
+    return JSON.stringify({{ results }});
+}})()"#,
+            config = self.config.mermaid_config(),
+            diagrams = serde_json::to_string(diagrams)?
+        );
+        let evaluated = tab.evaluate(&expression, true);
+        tab.close(false).unwrap_or(false);
+
+        #[derive(serde::Deserialize)]
+        struct Rendered {
+            svg: Option<String>,
+            error: Option<String>
+        }
+        #[derive(serde::Deserialize)]
+        struct Evaluated {
+            missing: Option<bool>,
+            results: Option<Vec<Rendered>>
+        }
+
+        let value = evaluated.context("Chrome no pudo ejecutar mermaid.js")?.value;
+        let evaluated: Evaluated = match value {
+            Some(serde_json::Value::String(json)) => serde_json::from_str(&json)?,
+            _ => anyhow::bail!("Chrome no devolvió los diagramas mermaid")
+        };
+        if evaluated.missing.unwrap_or(false) {
+            anyhow::bail!("No se pudo cargar mermaid.js desde `{}`", script.display());
+        }
+
+        Ok(evaluated.results.unwrap_or_default().into_iter()
+            .map(|rendered| match (rendered.svg, rendered.error) {
+                (Some(svg), _) => Ok(svg),
+                (None, error) => Err(DiagramError::from_output(&error.unwrap_or_default()))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::browser::BrowserConfig;
+    use crate::filesystem::MemoryFs;
+
+    fn config(value: serde_json::Value) -> MermaidConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    // The path of `code` in the cache of a build in `dir`.
+    fn cache_path(config: &MermaidConfig, dir: &path::Path, code: &str) -> path::PathBuf {
+        let browser = BrowserSession::new(&BrowserConfig::default());
+        let mermaid = Mermaid::new(config, None, Arc::new(MemoryFs::new()), dir, &dir.join("cache"), &browser);
+        mermaid.cache_path(code)
+    }
+
+    #[test]
+    fn the_theme_goes_into_the_configuration() {
+        let merged = config(json!({
+            "theme": "neutral",
+            "config": { "theme": "dark", "flowchart": { "curve": "basis" } }
+        })).mermaid_config();
+        assert_eq!(merged, json!({ "theme": "neutral", "flowchart": { "curve": "basis" } }));
+
+        assert_eq!(config(json!({ "theme": "forest" })).mermaid_config(), json!({ "theme": "forest" }));
+        assert_eq!(config(json!({ "config": { "fontSize": 12 } })).mermaid_config(), json!({ "fontSize": 12 }));
+        assert_eq!(MermaidConfig::default().mermaid_config(), json!({}));
+    }
+
+    #[test]
+    fn invalid_configurations() {
+        assert!(MermaidConfig::default().validate().is_ok());
+        assert!(config(json!({ "renderer": "browser", "script": "mermaid.min.js" })).validate().is_ok());
+
+        let err = config(json!({ "renderer": "browser" })).validate().unwrap_err();
+        assert!(err.contains("mermaid.script"), "{err}");
+        let err = config(json!({ "config": ["theme", "dark"] })).validate().unwrap_err();
+        assert!(err.contains("tiene que ser un objeto"), "{err}");
+
+        assert!(serde_json::from_value::<MermaidConfig>(json!({ "tema": "dark" })).is_err());
+        assert!(serde_json::from_value::<MermaidConfig>(json!({ "theme": "rosa" })).is_err());
+    }
+
+    #[test]
+    fn cached_diagrams_are_named_by_what_draws_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let default = MermaidConfig::default();
+        let first = cache_path(&default, dir.path(), "graph TD\n    A --> B");
+
+        assert_eq!(first.parent(), Some(dir.path().join("cache").as_path()));
+        assert_eq!(first.extension().and_then(|extension| extension.to_str()), Some("svg"));
+        assert_eq!(first, cache_path(&default, dir.path(), "graph TD\n    A --> B"));
+        assert_ne!(first, cache_path(&default, dir.path(), "graph TD\n    A --> C"));
+        assert_ne!(first, cache_path(&config(json!({ "theme": "dark" })), dir.path(), "graph TD\n    A --> B"));
+        // The command only counts when it is a file, by its contents.
+        assert_eq!(first, cache_path(&config(json!({ "command": "mermaid-cli" })), dir.path(), "graph TD\n    A --> B"));
+
+        let with_program = config(json!({ "command": "./bin/mmdc" }));
+        fs::create_dir(dir.path().join("bin")).unwrap();
+        fs::write(dir.path().join("bin/mmdc"), "#!/bin/sh\n").unwrap();
+        let before = cache_path(&with_program, dir.path(), "graph TD\n    A --> B");
+        assert_eq!(before, cache_path(&with_program, dir.path(), "graph TD\n    A --> B"));
+        fs::write(dir.path().join("bin/mmdc"), "#!/bin/sh\nexit 1\n").unwrap();
+        assert_ne!(before, cache_path(&with_program, dir.path(), "graph TD\n    A --> B"));
+    }
+
+    #[test]
+    fn batch_fences_are_longer_than_the_backticks_in_the_code() {
+        let markdown = batch_markdown(&["graph TD\n    A --> B\n", "graph TD\n    A[\"`**negrita**`\"] --> B[\"````\"]"]);
+
+        assert_eq!(markdown, concat!(
+            "```mermaid\ngraph TD\n    A --> B\n```\n\n",
+            "`````mermaid\ngraph TD\n    A[\"`**negrita**`\"] --> B[\"````\"]\n`````\n\n"
+        ));
+    }
+}
@@ -0,0 +1,183 @@
+use std::path;
+use serde_json::Value;
+
+use crate::diagnostics::Diagnostic;
+use crate::front_matter::Variables;
+use crate::html_generation;
+
+/* The `metadata` section of `project.thn`, every field optional:
+{
+    "metadata": {
+        "title": "Sistemas distribuidos",
+        "subtitle": "Consenso en redes particionadas",
+        "authors": [
+            { "name": "Ana Pérez", "affiliation": "Universidad Nacional", "email": "ana@un.edu" },
+            "Luis Gómez"
+        ],
+        "advisor": { "name": "Dra. Marta Ruiz", "affiliation": "Facultad de Ingeniería" },
+        "date": "2024-05-01",
+        "keywords": ["consenso", "Raft"],
+        "language": "es",
+        "abstract": "Este trabajo estudia..."
+    }
+}
+
+An author (or the advisor) is either a name or an object with `name` and,
+optionally, `affiliation` and `email`. The same keys can be written in the
+front matter of the entry point, where they replace the ones in the project. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    #[serde(default)]
+    pub authors: Vec<Author>,
+    pub advisor: Option<Author>,
+    pub date: Option<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    pub language: Option<String>,
+    #[serde(rename = "abstract")]
+    pub summary: Option<String>
+}
+
+// Keys of the front matter that are metadata rather than template variables.
+static FIELDS: &[&str] = &["title", "subtitle", "authors", "advisor", "date", "keywords", "language", "abstract"];
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(from = "AuthorEntry")]
+pub struct Author {
+    pub name: String,
+    pub affiliation: Option<String>,
+    pub email: Option<String>
+}
+
+// How an author can be written.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum AuthorEntry {
+    Name(String),
+    Full {
+        name: String,
+        affiliation: Option<String>,
+        email: Option<String>
+    }
+}
+
+impl From<AuthorEntry> for Author {
+    fn from(entry: AuthorEntry) -> Author {
+        match entry {
+            AuthorEntry::Name(name) => Author { name, affiliation: None, email: None },
+            AuthorEntry::Full { name, affiliation, email } => Author { name, affiliation, email }
+        }
+    }
+}
+
+impl Metadata {
+    // The metadata of the project with the keys in the front matter replacing
+    // their values. `file` is the entry point, for errors.
+    pub fn with_front_matter(&self, front_matter: &Variables, file: &path::Path) -> Result<Metadata, Diagnostic> {
+        let Ok(Value::Object(mut merged)) = serde_json::to_value(self) else {
+            return Ok(self.clone());
+        };
+
+        let mut overridden = false;
+        for (key, value) in front_matter.iter().filter(|(key, _)| FIELDS.contains(&key.as_str())) {
+            merged.insert(key.clone(), value.clone());
+            overridden = true;
+        }
+        if !overridden {
+            return Ok(self.clone());
+        }
+
+        serde_json::from_value(Value::Object(merged)).map_err(|err| {
+            Diagnostic::error(format!("metadatos inválidos en el front matter: {err}")).in_file(file)
+        })
+    }
+
+    pub fn author_names(&self) -> Vec<&str> {
+        self.authors.iter().map(|author| author.name.as_str()).collect()
+    }
+
+    // `<meta>` tags for the `<head>` of the generated page, one per line.
+    pub fn html_meta_tags(&self) -> String {
+        let mut tags = vec![];
+        let mut tag = |name: &str, content: &str| {
+            tags.push(format!(
+                "<meta name=\"{name}\" content=\"{}\">",
+                html_generation::escape_attribute(content)
+            ));
+        };
+
+        if let Some(title) = &self.title {
+            tag("dcterms.title", title);
+        }
+        for author in &self.authors {
+            tag("author", &author.name);
+        }
+        if let Some(summary) = &self.summary {
+            tag("description", summary.trim());
+        }
+        if !self.keywords.is_empty() {
+            tag("keywords", &self.keywords.join(", "));
+        }
+        if let Some(date) = &self.date {
+            tag("dcterms.date", date);
+        }
+        if let Some(language) = &self.language {
+            tag("dcterms.language", language);
+        }
+
+        tags.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn project_metadata() -> Metadata {
+        serde_json::from_value(json!({
+            "title": "Sistemas distribuidos",
+            "authors": ["Ana Pérez"],
+            "language": "es"
+        })).unwrap()
+    }
+
+    fn front_matter(value: Value) -> Variables {
+        let Value::Object(variables) = value else { unreachable!() };
+        variables
+    }
+
+    #[test]
+    fn front_matter_replaces_the_project_keys() {
+        let front_matter = front_matter(json!({
+            "title": "Consenso",
+            "authors": [{ "name": "Luis Gómez", "email": "luis@un.edu" }],
+            "universidad": "UNAL"
+        }));
+        let metadata = project_metadata().with_front_matter(&front_matter, path::Path::new("start.md")).unwrap();
+
+        assert_eq!(metadata.title.as_deref(), Some("Consenso"));
+        assert_eq!(metadata.author_names(), ["Luis Gómez"]);
+        assert_eq!(metadata.authors[0].email.as_deref(), Some("luis@un.edu"));
+        assert_eq!(metadata.language.as_deref(), Some("es"));
+    }
+
+    #[test]
+    fn variables_are_not_metadata() {
+        let metadata = project_metadata().with_front_matter(&front_matter(json!({ "universidad": "UNAL" })), path::Path::new("start.md")).unwrap();
+
+        assert_eq!(metadata.title.as_deref(), Some("Sistemas distribuidos"));
+        assert_eq!(metadata.author_names(), ["Ana Pérez"]);
+    }
+
+    #[test]
+    fn invalid_metadata_in_the_front_matter() {
+        let err = project_metadata().with_front_matter(&front_matter(json!({ "keywords": "uno" })), path::Path::new("start.md")).unwrap_err();
+
+        assert!(err.message.starts_with("metadatos inválidos en el front matter"), "{}", err.message);
+        assert_eq!(err.file.as_deref(), Some(path::Path::new("start.md")));
+    }
+}
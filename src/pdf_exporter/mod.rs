@@ -1,9 +1,15 @@
-use std::path::PathBuf;
-use headless_chrome::{self, types::PrintToPdfOptions};
-use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use headless_chrome::types::PrintToPdfOptions;
+use anyhow::{Context, Result};
 use std::fmt;
 use url::Url;
 
+use crate::browser::BrowserSession;
+use crate::header_footer::PageNumbering;
+use crate::metadata::Metadata;
+use crate::toc::Heading;
+
 #[derive(Debug)]
 enum Error {
     InvalidPath
@@ -30,47 +36,767 @@ impl fmt::Display for Error {
     }
 }
 
-pub fn export_to_pdf(html_path: &PathBuf, output_path: &PathBuf) -> Result<()> {
-    let browser = headless_chrome::Browser::default()?;
-    let tab = browser.new_tab()?;
-    
-    let html_url = Url::from_file_path(html_path).map_err(|_| Error::InvalidPath)?;
+/* The `pdf` section of `project.thn`, all of it optional:
+{
+    "pdf": {
+        "paper": "a4",
+        "margins": { "top": "2.5cm", "bottom": "2.5cm", "left": "3cm", "right": "2cm" },
+        "landscape": false,
+        "scale": 1.0,
+        "prefer_css_page_size": false,
+        "page_ranges": "1-5, 8",
+        "header": "./templates/encabezado.html",
+        "footer": "./templates/pie.html",
+        "page_numbers": { "cover_pages": 1, "front_matter": "roman" },
+        "outline": true
+    }
+}
+
+`paper` is "letter" (the default), "a4", "a5", "legal" or a custom size like
+`{ "width": "17cm", "height": "24cm" }`. Lengths are written in "in", "cm" or
+"mm". `margins` is either one length for every side or an object with the
+sides that are not 0. `scale` goes from 0.1 to 2. With `prefer_css_page_size`
+(on by default) an `@page { size: ... }` rule in the styles wins over `paper`.
+`page_ranges` limits the pages that are printed. The table of contents, the
+headers and the footers still number the pages as in the whole document.
+
+`header` and `footer` are templates, relative to the project, drawn in the top
+and bottom margins of every page, so those margins must be set here rather than
+in an `@page` rule. What they can show is in `header_footer::overlay_html`, and
+which pages get them in `header_footer::PageNumbering`. They are laid out for
+the size of the first page. Unless `outline` is
+false, the PDF gets bookmarks for every heading. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PdfConfig {
+    paper: Option<Paper>,
+    margins: Option<Margins>,
+    landscape: Option<bool>,
+    scale: Option<f64>,
+    prefer_css_page_size: Option<bool>,
+    page_ranges: Option<String>,
+    header: Option<String>,
+    footer: Option<String>,
+    page_numbers: Option<PageNumbering>,
+    outline: Option<bool>
+}
+
+// A length in inches, which is what Chrome takes. Written as "3cm", "25mm" or "1in".
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Length(f64);
+
+impl TryFrom<String> for Length {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Length, String> {
+        let trimmed = value.trim();
+        let unit_start = trimmed.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(unit_start);
+        let number: f64 = number.trim().parse()
+            .map_err(|_| format!("`{value}` no es una longitud válida, se escribe como \"3cm\", \"25mm\" o \"1in\""))?;
+        if number < 0.0 {
+            return Err(format!("`{value}` es una longitud negativa"));
+        }
+
+        let inches = match unit {
+            "in" => number,
+            "cm" => number / 2.54,
+            "mm" => number / 25.4,
+            "" if number == 0.0 => 0.0,
+            _ => return Err(format!("`{value}` no tiene una unidad válida, se usa \"in\", \"cm\" o \"mm\""))
+        };
+
+        Ok(Length(inches))
+    }
+}
+
+impl Length {
+    pub fn inches(&self) -> f64 {
+        self.0
+    }
+}
+
+impl From<Length> for String {
+    fn from(length: Length) -> String {
+        format!("{}in", length.0)
+    }
+}
+
+// Width and height in inches, portrait.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "PaperEntry")]
+struct Paper {
+    width: Length,
+    height: Length
+}
+
+// How the paper is written: a name or a custom size.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum PaperEntry {
+    Named(String),
+    Custom { width: String, height: String }
+}
+
+impl TryFrom<PaperEntry> for Paper {
+    type Error = String;
+
+    fn try_from(entry: PaperEntry) -> std::result::Result<Paper, String> {
+        let (width, height) = match entry {
+            PaperEntry::Named(name) => match name.to_lowercase().as_str() {
+                "letter" => (8.5, 11.0),
+                "a4" => (210.0 / 25.4, 297.0 / 25.4),
+                "a5" => (148.0 / 25.4, 210.0 / 25.4),
+                "legal" => (8.5, 14.0),
+                _ => return Err(format!("el papel `{name}` no existe, se usa \"letter\", \"a4\", \"a5\", \"legal\" o {{ \"width\": ..., \"height\": ... }}"))
+            },
+            PaperEntry::Custom { width, height } => (Length::try_from(width)?.0, Length::try_from(height)?.0)
+        };
+
+        Ok(Paper { width: Length(width), height: Length(height) })
+    }
+}
+
+impl Default for Paper {
+    fn default() -> Paper {
+        Paper { width: Length(8.5), height: Length(11.0) }
+    }
+}
+
+// The same length for every side, or one for each, 0 if missing.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(try_from = "MarginsEntry")]
+pub struct Margins {
+    pub top: Length,
+    pub bottom: Length,
+    pub left: Length,
+    pub right: Length
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum MarginsEntry {
+    All(String),
+    Sides(MarginSides)
+}
 
-    tab.navigate_to(html_url.as_str())?;
-    tab.wait_until_navigated()?;
-
-    /*
-    This is the previous code. Must use the same configurations.
-
-    let mut cmd = std::process::Command::new("wkhtmltopdf");
-    cmd.arg("--page-offset").arg("-1");
-    cmd.arg("--page-width").arg("8.5in");
-    cmd.arg("--page-height").arg("11in");
-    cmd.arg("--enable-local-file-access");
-    cmd.arg("--print-media-type");
-    cmd.arg(build_html_path);
-    cmd.arg(build_pdf_path.clone()); */
-    let pdf_data = tab
-        .print_to_pdf(Some(PrintToPdfOptions {
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MarginSides {
+    top: Option<String>,
+    bottom: Option<String>,
+    left: Option<String>,
+    right: Option<String>
+}
+
+impl TryFrom<MarginsEntry> for Margins {
+    type Error = String;
+
+    fn try_from(entry: MarginsEntry) -> std::result::Result<Margins, String> {
+        let side = |side: Option<String>| side.map(Length::try_from).unwrap_or(Ok(Length(0.0)));
+
+        Ok(match entry {
+            MarginsEntry::All(length) => {
+                let length = Length::try_from(length)?;
+                Margins { top: length, bottom: length, left: length, right: length }
+            },
+            MarginsEntry::Sides(MarginSides { top, bottom, left, right }) => Margins {
+                top: side(top)?,
+                bottom: side(bottom)?,
+                left: side(left)?,
+                right: side(right)?
+            }
+        })
+    }
+}
+
+// "1-5, 8, 11-" as Chrome takes it, or the reason it won't.
+// The ranges in `page_ranges` as first and last page, the last one missing in
+// an open range like "11-".
+fn parse_page_ranges(ranges: &str) -> std::result::Result<Vec<(u32, Option<u32>)>, String> {
+    let invalid = || format!("`{ranges}` no es un rango de páginas válido, se escribe como \"1-5, 8, 11-\"");
+    let page = |page: &str| -> std::result::Result<Option<u32>, String> {
+        match page.trim() {
+            "" => Ok(None),
+            page => page.parse::<u32>().ok().filter(|page| *page > 0).map(Some).ok_or_else(invalid)
+        }
+    };
+
+    ranges.split(',')
+        .map(|range| match range.split_once('-') {
+            Some((start, end)) => match (page(start)?, page(end)?) {
+                (Some(start), Some(end)) if start > end => Err(invalid()),
+                (None, None) => Err(invalid()),
+                (start, end) => Ok((start.unwrap_or(1), end))
+            },
+            None => page(range)?.map(|page| (page, Some(page))).ok_or_else(invalid)
+        })
+        .collect()
+}
+
+// The page of the document each of the `page_count` pages of the PDF is, since
+// `page_ranges` leaves some out. Chrome prints the pages in order and only once,
+// however the ranges are written.
+pub fn printed_pages(ranges: Option<&str>, page_count: u32) -> Vec<u32> {
+    let Some(ranges) = ranges.and_then(|ranges| parse_page_ranges(ranges).ok()) else {
+        return (1..=page_count).collect();
+    };
+
+    (1..=u32::MAX)
+        .filter(|page| ranges.iter().any(|(start, end)| start <= page && end.is_none_or(|end| *page <= end)))
+        .take(page_count as usize)
+        .collect()
+}
+
+impl PdfConfig {
+    fn paper_size(&self) -> (f64, f64) {
+        let paper = self.paper.unwrap_or_default();
+        let (width, height) = (paper.width.0, paper.height.0);
+        if self.landscape.unwrap_or(false) {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    pub fn margins(&self) -> Margins {
+        self.margins.unwrap_or_default()
+    }
+
+    pub fn header(&self) -> Option<&str> {
+        self.header.as_deref()
+    }
+
+    pub fn footer(&self) -> Option<&str> {
+        self.footer.as_deref()
+    }
+
+    pub fn outline(&self) -> bool {
+        self.outline.unwrap_or(true)
+    }
+
+    pub fn page_ranges(&self) -> Option<&str> {
+        self.page_ranges.as_deref()
+    }
+
+    pub fn page_numbers(&self) -> PageNumbering {
+        self.page_numbers.clone().unwrap_or_default()
+    }
+
+    // Checks what serde can't: that the values make sense together.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if let Some(scale) = self.scale {
+            if !(0.1..=2.0).contains(&scale) {
+                return Err(format!("`pdf.scale` debe estar entre 0.1 y 2, no {scale}"));
+            }
+        }
+
+        let (width, height) = self.paper_size();
+        if width <= 0.0 || height <= 0.0 {
+            return Err("el tamaño de `pdf.paper` no puede ser 0".to_string());
+        }
+        let margins = self.margins();
+        if margins.left.0 + margins.right.0 >= width || margins.top.0 + margins.bottom.0 >= height {
+            return Err("los márgenes de `pdf.margins` no dejan espacio en la página".to_string());
+        }
+
+        if let Some(ranges) = &self.page_ranges {
+            parse_page_ranges(ranges)?;
+        }
+
+        if self.header.is_some() && margins.top.0 == 0.0 {
+            return Err("`pdf.header` necesita un margen superior en `pdf.margins`".to_string());
+        }
+        if self.footer.is_some() && margins.bottom.0 == 0.0 {
+            return Err("`pdf.footer` necesita un margen inferior en `pdf.margins`".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn print_options(&self) -> PrintToPdfOptions {
+        let (paper_width, paper_height) = self.paper_size();
+        let margins = self.margins();
+
+        // The paper is already turned by `paper_size` in landscape, so Chrome is
+        // always told portrait.
+        PrintToPdfOptions {
             landscape: Some(false),
             display_header_footer: Some(false),
             print_background: Some(true),
-            scale: Some(1.0),
-            paper_width: Some(8.5),
-            paper_height: Some(11.0),
-            margin_top: Some(0.0),
-            margin_bottom: Some(0.0),
-            margin_left: Some(0.0),
-            margin_right: Some(0.0),
-            page_ranges: None,
+            scale: Some(self.scale.unwrap_or(1.0)),
+            paper_width: Some(paper_width),
+            paper_height: Some(paper_height),
+            margin_top: Some(margins.top.0),
+            margin_bottom: Some(margins.bottom.0),
+            margin_left: Some(margins.left.0),
+            margin_right: Some(margins.right.0),
+            page_ranges: self.page_ranges.clone(),
             ignore_invalid_page_ranges: Some(false),
             header_template: None,
             footer_template: None,
-            prefer_css_page_size: Some(true),
+            prefer_css_page_size: Some(self.prefer_css_page_size.unwrap_or(true)),
             transfer_mode: None,
-        }))?;
+        }
+    }
+}
+
+pub fn export_to_pdf(browser: &BrowserSession, html_path: &PathBuf, output_path: &PathBuf, config: &PdfConfig) -> Result<()> {
+    let tab = browser.new_tab()?;
+    
+    let html_url = Url::from_file_path(html_path).map_err(|_| Error::InvalidPath)?;
+
+    tab.navigate_to(html_url.as_str())
+        .and_then(|tab| tab.wait_until_navigated())
+        .with_context(|| format!("No se pudo abrir {}", html_path.display()))?;
+
+    let pdf_data = tab.print_to_pdf(Some(config.print_options()))?;
+    tab.close(false).unwrap_or(false);
+
+    std::fs::write(output_path, pdf_data)
+        .with_context(|| format!("No se pudo escribir {}", output_path.display()))?;
+
+    Ok(())
+}
+
+// Reads a destination array (`[page /XYZ left top zoom]`), or a dictionary
+// wrapping one in `/D`.
+fn destination_array(document: &lopdf::Document, destination: &lopdf::Object) -> Option<Vec<lopdf::Object>> {
+    let (_, destination) = document.dereference(destination).ok()?;
+    let destination = match destination.as_dict() {
+        Ok(dict) => document.dereference(dict.get(b"D").ok()?).ok()?.1,
+        Err(_) => destination
+    };
+
+    destination.as_array().ok().cloned()
+}
+
+// Walks a `/Dests` name tree, where names and destinations alternate in `/Names`
+// and subtrees hang from `/Kids`.
+fn collect_name_tree(document: &lopdf::Document, node: &lopdf::Object, result: &mut HashMap<String, Vec<lopdf::Object>>) {
+    let Some(node) = document.dereference(node).ok().and_then(|(_, node)| node.as_dict().ok()) else {
+        return;
+    };
+
+    if let Ok(names) = node.get(b"Names").and_then(|names| names.as_array()) {
+        for pair in names.chunks(2) {
+            if let [name, destination] = pair {
+                let name = name.as_str().map(|name| String::from_utf8_lossy(name).to_string());
+                if let (Ok(name), Some(destination)) = (name, destination_array(document, destination)) {
+                    result.insert(name, destination);
+                }
+            }
+        }
+    }
+
+    if let Ok(kids) = node.get(b"Kids").and_then(|kids| kids.as_array()) {
+        for kid in kids {
+            collect_name_tree(document, kid, result);
+        }
+    }
+}
+
+// The named destinations of a PDF printed by Chrome. Chrome adds one for every
+// element with an id that is the target of an internal link, named after the id.
+fn named_destinations(document: &lopdf::Document) -> Result<HashMap<String, Vec<lopdf::Object>>> {
+    let catalog = document.catalog()?;
+    let mut result = HashMap::new();
+
+    // PDF 1.1 style: a dictionary from names to destinations in the catalog.
+    if let Ok((_, dests)) = catalog.get(b"Dests").and_then(|dests| document.dereference(dests)) {
+        if let Ok(dests) = dests.as_dict() {
+            for (name, destination) in dests.iter() {
+                if let Some(destination) = destination_array(document, destination) {
+                    result.insert(String::from_utf8_lossy(name).to_string(), destination);
+                }
+            }
+        }
+    }
+
+    // PDF 1.2 style: a name tree under `/Names /Dests`.
+    let names = catalog.get(b"Names").ok()
+        .and_then(|names| document.dereference(names).ok())
+        .and_then(|(_, names)| names.as_dict().ok());
+    if let Some(dests) = names.and_then(|names| names.get(b"Dests").ok()) {
+        collect_name_tree(document, dests, &mut result);
+    }
+
+    Ok(result)
+}
+
+// Pages of the named destinations, so this tells where each linked heading landed.
+pub fn destination_pages(pdf_path: &Path) -> Result<HashMap<String, u32>> {
+    let document = lopdf::Document::load(pdf_path)
+        .with_context(|| format!("No se pudo leer {}", pdf_path.display()))?;
+    let pages: HashMap<lopdf::ObjectId, u32> = document.get_pages().into_iter()
+        .map(|(number, id)| (id, number))
+        .collect();
+
+    Ok(named_destinations(&document)?.into_iter()
+        .filter_map(|(name, destination)| {
+            let page = destination.first()?.as_reference().ok()?;
+            pages.get(&page).map(|page| (name, *page))
+        })
+        .collect())
+}
+
+// An entry of the outline, with the entries nested under it.
+struct OutlineEntry<'a> {
+    heading: &'a Heading,
+    children: Vec<OutlineEntry<'a>>
+}
+
+// Nests the headings by level. A heading that skips levels (an h3 right after an
+// h1) is nested only once, like in the table of contents.
+fn outline_tree(headings: &[Heading]) -> Vec<OutlineEntry<'_>> {
+    fn insert<'a>(entries: &mut Vec<OutlineEntry<'a>>, heading: &'a Heading) {
+        match entries.last_mut() {
+            Some(last) if last.heading.level < heading.level => insert(&mut last.children, heading),
+            _ => entries.push(OutlineEntry { heading, children: vec![] })
+        }
+    }
+
+    let mut entries = vec![];
+    for heading in headings {
+        insert(&mut entries, heading);
+    }
+
+    entries
+}
+
+// Adds the entries as siblings under `parent`, returning the first and last ids.
+// Headings that didn't land anywhere in the PDF are skipped along with their
+// children.
+fn add_outline_entries(
+    document: &mut lopdf::Document,
+    entries: &[OutlineEntry],
+    parent: lopdf::ObjectId,
+    destinations: &HashMap<String, Vec<lopdf::Object>>,
+    show_numbers: bool
+) -> Option<(lopdf::ObjectId, lopdf::ObjectId, i64)> {
+    let mut ids = vec![];
+    for entry in entries {
+        let Some(destination) = destinations.get(&entry.heading.id) else {
+            continue;
+        };
+
+        let id = document.new_object_id();
+        let title = if show_numbers {
+            format!("{} {}", entry.heading.number, entry.heading.text)
+        } else {
+            entry.heading.text.clone()
+        };
+        let mut item = lopdf::dictionary! {
+            "Title" => lopdf::text_string(&title),
+            "Parent" => parent,
+            "Dest" => destination.clone()
+        };
+
+        // Entries start closed, showing how many children they hide.
+        if let Some((first, last, count)) = add_outline_entries(document, &entry.children, id, destinations, show_numbers) {
+            item.set("First", first);
+            item.set("Last", last);
+            item.set("Count", -count);
+        }
+        document.objects.insert(id, lopdf::Object::Dictionary(item));
+        ids.push(id);
+    }
+
+    for pair in ids.windows(2) {
+        let [previous, next] = pair else {
+            continue;
+        };
+        document.get_dictionary_mut(*previous).ok()?.set("Next", *next);
+        document.get_dictionary_mut(*next).ok()?.set("Prev", *previous);
+    }
+
+    Some((*ids.first()?, *ids.last()?, ids.len() as i64))
+}
+
+// Adds bookmarks mirroring the headings, each pointing to where its heading is,
+// and asks readers to show them. Chrome prints without any.
+pub fn add_outline(pdf_path: &Path, headings: &[Heading], show_numbers: bool) -> Result<()> {
+    let mut document = lopdf::Document::load(pdf_path)
+        .with_context(|| format!("No se pudo leer {}", pdf_path.display()))?;
+    let destinations = named_destinations(&document)?;
+
+    let outlines = document.new_object_id();
+    let Some((first, last, count)) = add_outline_entries(&mut document, &outline_tree(headings), outlines, &destinations, show_numbers) else {
+        return Ok(());
+    };
+    document.objects.insert(outlines, lopdf::Object::Dictionary(lopdf::dictionary! {
+        "Type" => "Outlines",
+        "First" => first,
+        "Last" => last,
+        "Count" => count
+    }));
+
+    let catalog = document.catalog_mut()?;
+    catalog.set("Outlines", outlines);
+    catalog.set("PageMode", "UseOutlines");
+
+    document.save(pdf_path)
+        .with_context(|| format!("No se pudo escribir {}", pdf_path.display()))?;
+
+    Ok(())
+}
+
+// Fills the document properties of the PDF (`/Info`) from the metadata, and the
+// language of the document in the catalog. Chrome only sets the title, from the
+// `<title>` of the page, which the metadata title replaces.
+pub fn set_document_info(pdf_path: &Path, metadata: &Metadata) -> Result<()> {
+    let mut document = lopdf::Document::load(pdf_path)
+        .with_context(|| format!("No se pudo leer {}", pdf_path.display()))?;
 
-    std::fs::write(output_path, pdf_data)?;
+    let mut info = document.trailer.get(b"Info").ok()
+        .and_then(|info| document.dereference(info).ok())
+        .and_then(|(_, info)| info.as_dict().ok().cloned())
+        .unwrap_or_default();
+
+    let mut set = |key: &str, value: &str| {
+        if !value.is_empty() {
+            info.set(key, lopdf::text_string(value));
+        }
+    };
+    if let Some(title) = &metadata.title {
+        set("Title", title);
+    }
+    set("Author", &metadata.author_names().join(", "));
+    set("Subject", metadata.summary.as_deref().unwrap_or("").trim());
+    set("Keywords", &metadata.keywords.join(", "));
+    set("Creator", "thener");
+
+    let info_id = document.add_object(info);
+    document.trailer.set("Info", info_id);
+    if let Some(language) = &metadata.language {
+        document.catalog_mut()?.set("Lang", lopdf::text_string(language));
+    }
+
+    document.save(pdf_path)
+        .with_context(|| format!("No se pudo escribir {}", pdf_path.display()))?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// A key of a page that may be inherited from the page tree, dereferenced.
+fn inherited(document: &lopdf::Document, page_id: lopdf::ObjectId, key: &[u8]) -> Option<lopdf::Object> {
+    let mut node = document.get_dictionary(page_id).ok()?;
+    loop {
+        if let Ok(value) = node.get(key) {
+            return document.dereference(value).ok().map(|(_, value)| value.clone());
+        }
+        node = node.get(b"Parent").and_then(|parent| parent.as_reference())
+            .and_then(|parent| document.get_dictionary(parent)).ok()?;
+    }
+}
+
+fn media_box(document: &lopdf::Document, page_id: lopdf::ObjectId) -> Option<Vec<f64>> {
+    inherited(document, page_id, b"MediaBox")?.as_array().ok()?.iter()
+        .map(|value| value.as_float().map(f64::from).ok())
+        .collect()
+}
+
+// How many pages a PDF has and the size of the first one, in points. Headers and
+// footers are laid out for that size on every page, so a document that changes
+// the page size with `@page` rules gets them misplaced on the other pages.
+pub fn page_layout(pdf_path: &Path) -> Result<(u32, f64, f64)> {
+    let document = lopdf::Document::load(pdf_path)
+        .with_context(|| format!("No se pudo leer {}", pdf_path.display()))?;
+    let pages = document.get_pages();
+
+    let size = pages.values().next()
+        .and_then(|page| media_box(&document, *page))
+        .and_then(|media_box| match media_box.as_slice() {
+            [left, bottom, right, top] => Some((right - left, top - bottom)),
+            _ => None
+        })
+        .context("El PDF no tiene páginas")?;
+
+    Ok((pages.len() as u32, size.0, size.1))
+}
+
+// Draws each page of `overlay_path` over the same page of `pdf_path`. The overlay
+// pages become form XObjects, painted after the page's own content.
+pub fn stamp_pages(pdf_path: &Path, overlay_path: &Path) -> Result<()> {
+    let mut document = lopdf::Document::load(pdf_path)
+        .with_context(|| format!("No se pudo leer {}", pdf_path.display()))?;
+    let mut overlay = lopdf::Document::load(overlay_path)
+        .with_context(|| format!("No se pudo leer {}", overlay_path.display()))?;
+
+    // Move the objects of the overlay into the document, after its own.
+    overlay.renumber_objects_with(document.max_id + 1);
+    let pages = document.get_pages();
+    let mut forms = vec![];
+    for (number, overlay_page) in overlay.get_pages() {
+        let content = overlay.get_page_content(overlay_page);
+        let Some(page) = pages.get(&number) else {
+            continue;
+        };
+        if content.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        let mut form = lopdf::dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Form",
+            "BBox" => inherited(&overlay, overlay_page, b"MediaBox").unwrap_or(lopdf::Object::Null)
+        };
+        if let Some(resources) = inherited(&overlay, overlay_page, b"Resources") {
+            form.set("Resources", resources);
+        }
+        forms.push((number, *page, lopdf::Stream::new(form, content)));
+    }
+    document.max_id = document.max_id.max(overlay.max_id);
+    document.objects.extend(overlay.objects);
+
+    for (number, page, form) in forms {
+        let form = document.add_object(form);
+        let name = format!("ThenerOverlay{number}");
+
+        // The resources may be shared with other pages or inherited, so the page
+        // gets its own copy with the form added.
+        let mut resources = inherited(&document, page, b"Resources")
+            .and_then(|resources| resources.as_dict().ok().cloned())
+            .unwrap_or_default();
+        let mut xobjects = resources.get(b"XObject").ok()
+            .and_then(|xobjects| document.dereference(xobjects).ok())
+            .and_then(|(_, xobjects)| xobjects.as_dict().ok().cloned())
+            .unwrap_or_default();
+        xobjects.set(name.as_bytes(), form);
+        resources.set("XObject", xobjects);
+        document.get_dictionary_mut(page)?.set("Resources", resources);
+
+        // Whatever state the page content leaves behind is restored first.
+        let contents = document.get_page_contents(page);
+        let start = document.add_object(lopdf::Stream::new(lopdf::Dictionary::new(), b"q\n".to_vec()));
+        let end = document.add_object(lopdf::Stream::new(lopdf::Dictionary::new(), format!("\nQ\nq /{name} Do Q\n").into_bytes()));
+        let contents: Vec<lopdf::Object> = std::iter::once(start).chain(contents).chain(std::iter::once(end))
+            .map(lopdf::Object::Reference)
+            .collect();
+        document.get_dictionary_mut(page)?.set("Contents", contents);
+    }
+
+    // The catalog and page tree of the overlay are left unreferenced.
+    document.prune_objects();
+    document.compress();
+    document.save(pdf_path)
+        .with_context(|| format!("No se pudo escribir {}", pdf_path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> std::result::Result<PdfConfig, String> {
+        serde_json::from_str::<PdfConfig>(json).map_err(|err| err.to_string())
+    }
+
+    fn length(value: &str) -> std::result::Result<f64, String> {
+        Length::try_from(value.to_string()).map(|length| length.inches())
+    }
+
+    #[test]
+    fn lengths_are_read_in_inches() {
+        assert_eq!(length("1in"), Ok(1.0));
+        assert_eq!(length(" 2.54cm "), Ok(1.0));
+        assert_eq!(length("25.4 mm"), Ok(1.0));
+        assert_eq!(length("0"), Ok(0.0));
+    }
+
+    #[test]
+    fn invalid_lengths_are_errors() {
+        assert!(length("3").unwrap_err().contains("no tiene una unidad válida"));
+        assert!(length("3pt").unwrap_err().contains("no tiene una unidad válida"));
+        assert!(length("cm").unwrap_err().contains("no es una longitud válida"));
+        assert!(length("-1cm").unwrap_err().contains("es una longitud negativa"));
+    }
+
+    #[test]
+    fn papers_by_name_and_size() {
+        let paper = |json: &str| config(json).map(|config| config.paper_size());
+
+        assert_eq!(paper("{}"), Ok((8.5, 11.0)));
+        assert_eq!(paper(r#"{ "paper": "Legal" }"#), Ok((8.5, 14.0)));
+        assert_eq!(paper(r#"{ "paper": "a4", "landscape": true }"#), Ok((297.0 / 25.4, 210.0 / 25.4)));
+        assert_eq!(paper(r#"{ "paper": { "width": "5in", "height": "254mm" } }"#), Ok((5.0, 10.0)));
+        assert!(paper(r#"{ "paper": "b5" }"#).unwrap_err().contains("el papel `b5` no existe"));
+        assert!(paper(r#"{ "paper": { "width": "5in", "height": "10" } }"#).unwrap_err().contains("no tiene una unidad válida"));
+    }
+
+    #[test]
+    fn margins_for_every_side_or_some() {
+        let margins = config(r#"{ "margins": "1in" }"#).unwrap().margins();
+        assert_eq!((margins.top.inches(), margins.left.inches()), (1.0, 1.0));
+
+        let margins = config(r#"{ "margins": { "top": "2in", "left": "1in" } }"#).unwrap().margins();
+        assert_eq!(
+            [margins.top, margins.bottom, margins.left, margins.right].map(|side| side.inches()),
+            [2.0, 0.0, 1.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn page_ranges_are_parsed() {
+        assert_eq!(parse_page_ranges("1-5, 8, 11-"), Ok(vec![(1, Some(5)), (8, Some(8)), (11, None)]));
+        assert_eq!(parse_page_ranges("-3"), Ok(vec![(1, Some(3))]));
+
+        for ranges in ["", "0", "5-2", "-", "1,,2", "a-3", "1-2-3"] {
+            assert!(parse_page_ranges(ranges).is_err(), "{ranges:?}");
+        }
+    }
+
+    #[test]
+    fn configurations_that_do_not_fit_are_errors() {
+        let error = |json: &str| config(json).unwrap().validate().unwrap_err();
+
+        assert!(config(r#"{ "paper": "a4", "margins": "2cm", "scale": 0.5, "page_ranges": "1-3", "header": "h.html" }"#).unwrap().validate().is_ok());
+        assert!(error(r#"{ "scale": 3 }"#).contains("`pdf.scale` debe estar entre 0.1 y 2"));
+        assert!(error(r#"{ "paper": { "width": "0in", "height": "5in" } }"#).contains("no puede ser 0"));
+        assert!(error(r#"{ "margins": { "left": "5in", "right": "4in" } }"#).contains("no dejan espacio"));
+        assert!(error(r#"{ "page_ranges": "3-1" }"#).contains("no es un rango de páginas válido"));
+        assert!(error(r#"{ "header": "h.html" }"#).contains("necesita un margen superior"));
+        assert!(error(r#"{ "margins": { "top": "1in" }, "footer": "f.html" }"#).contains("necesita un margen inferior"));
+    }
+
+    // The tree as "A(B C) D", by the text of the headings.
+    fn outline(headings: &[(u8, &str)]) -> String {
+        fn write(entries: &[OutlineEntry]) -> String {
+            entries.iter()
+                .map(|entry| match entry.children.as_slice() {
+                    [] => entry.heading.text.clone(),
+                    children => format!("{}({})", entry.heading.text, write(children))
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+
+        let headings: Vec<Heading> = headings.iter()
+            .map(|(level, text)| Heading { level: *level, text: text.to_string(), id: text.to_lowercase(), number: String::new() })
+            .collect();
+        write(&outline_tree(&headings))
+    }
+
+    #[test]
+    fn outline_nests_by_level() {
+        assert_eq!(outline(&[(1, "A"), (2, "B"), (3, "C"), (2, "D"), (1, "E"), (2, "F")]), "A(B(C) D) E(F)");
+        assert_eq!(outline(&[]), "");
+    }
+
+    #[test]
+    fn skipped_levels_are_nested_once() {
+        assert_eq!(outline(&[(1, "A"), (3, "B"), (2, "C"), (4, "D"), (1, "E")]), "A(B C(D)) E");
+        // A document that starts deeper than it goes later.
+        assert_eq!(outline(&[(2, "A"), (3, "B"), (1, "C"), (2, "D")]), "A(B) C(D)");
+    }
+
+    #[test]
+    fn printed_pages_follow_the_ranges() {
+        assert_eq!(printed_pages(None, 3), [1, 2, 3]);
+        assert_eq!(printed_pages(Some("2-3, 6"), 3), [2, 3, 6]);
+        assert_eq!(printed_pages(Some("8, 1-2"), 3), [1, 2, 8]);
+        assert_eq!(printed_pages(Some("-2, 5-"), 4), [1, 2, 5, 6]);
+    }
+}
@@ -1,11 +1,89 @@
 use defer_lite::defer;
-use fs_extra;
-use indicatif;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::{fs, path};
 
-use markdown::{self, CompileOptions, Options, ParseOptions};
+use crate::{diagnostics, file_utils, front_matter, header_footer, manifest, md_compiler, html_generation, pdf_exporter};
+use crate::bibliography::{Bibliography, BibliographyConfig};
+use crate::browser::{BrowserConfig, BrowserSession};
+use crate::front_matter::Variables;
+use crate::highlight::HighlightConfig;
+use crate::jobs::Jobs;
+use crate::manifest::{AssetRecord, HtmlRecord, Manifest, StageRecord};
+use crate::diagrams::{self, Diagrams, DiagramsConfig};
+use crate::filesystem::{self, DiskFs, FileSystem, MemoryFs};
+use crate::header_footer::{PageLabel, PageTemplate};
+use crate::mermaid::{Mermaid, MermaidConfig};
+use crate::metadata::Metadata;
+use crate::pdf_exporter::PdfConfig;
+use crate::diagnostics::Diagnostic;
+use crate::source_map::SourceMap;
+use crate::toc::{self, Heading, TocConfig};
 
-use crate::{file_utils, md_compiler, html_generation, pdf_exporter};
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Pdf,
+    Html
+}
+
+// Implement from str on output format
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pdf" => Ok(OutputFormat::Pdf),
+            "html" => Ok(OutputFormat::Html),
+            _ => Err(format!("Formato de salida no soportado: {}", s))
+        }
+    }
+}
+
+// The stages a build can run. Every format needs the HTML stages, since the PDF
+// is printed by Chrome from the generated `index.html`; only PDF needs Chrome.
+// Declared in pipeline order, so sorting a list of stages gives the order they run in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BuildStage {
+    Preprocess,
+    CopyAssets,
+    GenerateHtml,
+    ExportPdf
+}
+
+impl OutputFormat {
+    fn stages(&self) -> &'static [BuildStage] {
+        match self {
+            OutputFormat::Html => &[BuildStage::Preprocess, BuildStage::CopyAssets, BuildStage::GenerateHtml],
+            OutputFormat::Pdf => &[BuildStage::Preprocess, BuildStage::CopyAssets, BuildStage::GenerateHtml, BuildStage::ExportPdf]
+        }
+    }
+}
+
+// What a build is doing, given to the callback of `BuildSession::with_progress`
+// as it goes. A stage is either skipped, when it is up to date, or started and
+// then finished, unless it fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildEvent {
+    Started(BuildStage),
+    Skipped(BuildStage),
+    Finished(BuildStage),
+    Done
+}
+
+// Union of the stages required by every requested format, in pipeline order.
+pub fn stages_for(formats: &[OutputFormat]) -> Vec<BuildStage> {
+    let mut stages: Vec<BuildStage> = vec![];
+    for format in formats {
+        for stage in format.stages() {
+            if !stages.contains(stage) {
+                stages.push(*stage);
+            }
+        }
+    }
+
+    stages
+}
 
 /* Contents of 'project.thn':
 {
@@ -13,9 +91,38 @@ use crate::{file_utils, md_compiler, html_generation, pdf_exporter};
     "assets": "./assets",
     "template": "./templates/main.html",
     "output": "./build",
-    "entry": "start.md"
+    "entry": "start.md",
+    "max_import_depth": 100,
+    "toc": { "title": "Índice", "depth": 3 },
+    "number_headings": true,
+    "bibliography": { "files": ["./referencias.bib"], "style": "apa" },
+    "highlight": { "theme": "InspiredGitHub", "line_numbers": false },
+    "mermaid": { "theme": "neutral" },
+    "diagrams": { "dot": { "command": "dot -Tsvg" } },
+    "variables": { "university": "Universidad Nacional" },
+    "metadata": { "title": "Sistemas distribuidos", "authors": ["Ana Pérez"], "language": "es" },
+    "pdf": { "paper": "a4", "margins": { "left": "3cm", "right": "2cm" } },
+    "browser": { "executable": "/usr/bin/chromium", "timeout": 60 }
 }
 
+`max_import_depth` is optional and limits how deeply `@import`s can be nested.
+`toc` is optional too, see `toc::TocConfig`. Headings are shown with their
+number ("2.3 Título") unless `number_headings` is false. `bibliography` is
+needed to cite, see `bibliography::BibliographyConfig`. Code blocks are
+highlighted as set in `highlight`, see `highlight::HighlightConfig`, and
+`mermaid` diagrams drawn as set in `mermaid`, see `mermaid::MermaidConfig`.
+Blocks in other diagram languages are drawn by the commands in `diagrams`, see
+`diagrams::DiagramsConfig`.
+`template` wraps the document, see `html_generation::TemplateContext`. Only its
+variable delimiters are changed, to `#{ name }#`; blocks are still `{% ... %}`
+and comments `{# ... #}`.
+`variables` are available to the template, along with the front matter of the
+entry point, which takes precedence. `metadata` describes the document, see
+`metadata::Metadata`; it is also read from the front matter, goes to the
+template as `metadata` and to the `<meta>` tags and properties of the output.
+The page setup of the PDF is in `pdf`, see `pdf_exporter::PdfConfig`, and the
+Chrome that prints it in `browser`, see `browser::BrowserConfig`.
+
 This struct is serializable from this file format (location should be "." if not provided):
 */
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -25,7 +132,18 @@ struct ReadProject {
     assets: String,
     entry: String,
     output: Option<String>,
-    template: String
+    template: String,
+    max_import_depth: Option<u8>,
+    toc: Option<TocConfig>,
+    number_headings: Option<bool>,
+    bibliography: Option<BibliographyConfig>,
+    highlight: Option<HighlightConfig>,
+    mermaid: Option<MermaidConfig>,
+    diagrams: Option<DiagramsConfig>,
+    variables: Option<Variables>,
+    metadata: Option<Metadata>,
+    pdf: Option<PdfConfig>,
+    browser: Option<BrowserConfig>
 }
 
 // This struct is the actual project, takes a ReadProject and makes it a Project with the location set to the path of the project file.
@@ -35,7 +153,23 @@ pub struct Project {
     entry: std::path::PathBuf,
     template: std::path::PathBuf,
     output: std::path::PathBuf,
-    location: std::path::PathBuf
+    location: std::path::PathBuf,
+    // The `project.thn` it was read from, if it was.
+    file: Option<std::path::PathBuf>,
+    max_import_depth: Option<u8>,
+    toc: Option<TocConfig>,
+    number_headings: bool,
+    bibliography: Option<BibliographyConfig>,
+    highlight: Option<HighlightConfig>,
+    mermaid: MermaidConfig,
+    diagrams: DiagramsConfig,
+    variables: Variables,
+    metadata: Metadata,
+    pdf: PdfConfig,
+    browser: BrowserConfig,
+    // Hash of the configuration and of the version of thener, recorded in the
+    // build manifest: changing either rebuilds everything.
+    settings: String
 }
 
 impl Project {
@@ -53,138 +187,792 @@ impl Project {
             entry: project_entry,
             template: project_template,
             output: project_output,
-            location: project_location
+            location: project_location,
+            file: None,
+            max_import_depth: read_project.max_import_depth,
+            toc: read_project.toc.clone(),
+            number_headings: read_project.number_headings.unwrap_or(true),
+            bibliography: read_project.bibliography.clone(),
+            highlight: read_project.highlight.clone(),
+            mermaid: read_project.mermaid.clone().unwrap_or_default(),
+            diagrams: read_project.diagrams.clone().unwrap_or_default(),
+            variables: read_project.variables.clone().unwrap_or_default(),
+            metadata: read_project.metadata.clone().unwrap_or_default(),
+            pdf: read_project.pdf.clone().unwrap_or_default(),
+            browser: read_project.browser.clone().unwrap_or_default(),
+            settings: manifest::hash_bytes(format!(
+                "{}\n{}",
+                env!("CARGO_PKG_VERSION"),
+                serde_json::to_string(read_project).unwrap_or_default()
+            ).as_bytes())
         }
     }
+
+    // A project from the contents of a `project.thn` that isn't on disk, with its
+    // paths relative to `location`.
+    pub fn from_config(config: &str, location: &path::Path) -> Result<Project, Box<dyn std::error::Error>> {
+        let read_project: ReadProject = serde_json::from_str(config)?;
+        validate(&read_project).map_err(|err| Diagnostic::error(format!("Configuración inválida: {err}")))?;
+        let location = file_utils::try_absolute(&location.to_string_lossy())?;
+
+        Ok(Project::from_read_project(&read_project, &location))
+    }
+
+    pub fn location(&self) -> &path::Path {
+        &self.location
+    }
+
+    pub fn config_path(&self) -> Option<&path::Path> {
+        self.file.as_deref()
+    }
+
+    // Every path of the project is relative to `path`, itself relative to the
+    // directory of `project.thn`, never to the working directory of the process.
+    fn resolve(&self, relative: &path::Path) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+        let base = self.location.join(&self.path);
+        Ok(file_utils::try_absolute_based_on_path(&base.to_string_lossy(), &relative.to_string_lossy())?.into())
+    }
+
+    // Where the contents of the project are, `path`.
+    pub fn dir(&self) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+        self.resolve(path::Path::new("."))
+    }
+
+    pub fn entry_path(&self) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+        self.resolve(&self.entry)
+    }
+
+    pub fn template_path(&self) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+        self.resolve(&self.template)
+    }
+
+    pub fn assets_path(&self) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+        self.resolve(&self.assets)
+    }
+
+    pub fn output_path(&self) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+        self.resolve(&self.output)
+    }
+
+    pub fn bibliography_paths(&self) -> Result<Vec<path::PathBuf>, Box<dyn std::error::Error>> {
+        let Some(bibliography) = &self.bibliography else {
+            return Ok(vec![]);
+        };
+
+        bibliography.files().iter()
+            .map(|file| self.resolve(path::Path::new(file)))
+            .collect()
+    }
+
+    // The mermaid.js loaded by the browser renderer, if set.
+    pub fn mermaid_script_path(&self) -> Result<Option<path::PathBuf>, Box<dyn std::error::Error>> {
+        self.mermaid.script()
+            .map(|script| self.resolve(path::Path::new(script)))
+            .transpose()
+    }
+
+    // The programs that draw diagrams, `mermaid.command` and the ones of
+    // `diagrams`, when they are files and not looked up in the `PATH`.
+    pub fn program_paths(&self) -> Result<Vec<path::PathBuf>, Box<dyn std::error::Error>> {
+        let dir = self.dir()?;
+        Ok(self.mermaid.program_file(&dir).into_iter()
+            .chain(diagrams::program_files(&self.diagrams, &dir))
+            .collect())
+    }
+
+    // The header and footer templates of the PDF, if any.
+    pub fn page_template_paths(&self) -> Result<Vec<path::PathBuf>, Box<dyn std::error::Error>> {
+        self.pdf.header().into_iter()
+            .chain(self.pdf.footer())
+            .map(|file| self.resolve(path::Path::new(file)))
+            .collect()
+    }
+
+    pub fn html_output_path(&self) -> Result<path::PathBuf, Box<dyn std::error::Error>> {
+        Ok(self.output_path()?.join("html"))
+    }
+}
+
+/*This is how the output tree will look like:
+.
+├── {project.output}
+│   ├── html
+│   │   ├── {project.assets}
+│   │   │   └── img
+│   │   │       └── logo.png
+│   │   ├── index.html (this is the compiled markdown and exported into template)
+│   │── pdf
+│   │   └── index.pdf
+│   └── cache
+│       ├── manifest.json (what the last build read and wrote, see `manifest::Manifest`)
+│       ├── mermaid (the SVG of every diagram, by the hash of its code)
+│       └── diagrams (the same for the other diagram languages)
+├── project.thn
+├── start.md
+ */
+struct BuildPaths {
+    html: path::PathBuf,
+    pdf: path::PathBuf,
+    cache: path::PathBuf
 }
 
-pub fn build_project(project: &Project) -> Result<(), Box<dyn std::error::Error>> {
-    // Change the working directory to the project's path, remembering the original one.
-    let original_dir = std::env::current_dir()?;
-    std::env::set_current_dir(&project.location)?;
-    defer! { std::env::set_current_dir(original_dir.clone()).unwrap_or(()) }
+impl BuildPaths {
+    fn from_project(project: &Project) -> Result<BuildPaths, Box<dyn std::error::Error>> {
+        Ok(BuildPaths {
+            html: project.html_output_path()?,
+            pdf: project.output_path()?.join("pdf"),
+            cache: project.output_path()?.join("cache")
+        })
+    }
+
+    fn index_html(&self) -> path::PathBuf {
+        self.html.join("index.html")
+    }
+
+    fn index_pdf(&self) -> path::PathBuf {
+        self.pdf.join("index.pdf")
+    }
+
+    // The HTML printed on the second PDF pass, with the page numbers filled in.
+    // Lives next to `index.html` so that relative asset paths still work.
+    fn print_html(&self) -> path::PathBuf {
+        self.html.join("index.print.html")
+    }
+
+    // The headers and footers of every page, printed apart and laid over the PDF.
+    fn header_footer_html(&self) -> path::PathBuf {
+        self.html.join("index.header-footer.html")
+    }
 
-    // Create the build directory if it doesn't exist.
-    println!("[INFO] Creating build directory");
-    let build_path = file_utils::try_absolute(&project.path)?;
-    if fs::File::open(&build_path).is_err() {
-        fs::create_dir_all(&build_path)?;
+    fn header_footer_pdf(&self) -> path::PathBuf {
+        self.pdf.join("index.header-footer.pdf")
     }
 
+    fn manifest(&self) -> path::PathBuf {
+        self.cache.join("manifest.json")
+    }
+
+    fn mermaid_cache(&self) -> path::PathBuf {
+        self.cache.join("mermaid")
+    }
+
+    fn diagrams_cache(&self) -> path::PathBuf {
+        self.cache.join("diagrams")
+    }
+}
+
+fn ensure_dir(dir: &path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    if fs::File::open(dir).is_err() {
+        fs::create_dir_all(dir)?;
+    }
+
+    Ok(())
+}
+
+// The markdown with every import resolved, and where each of its lines came
+// from, and the front matter of the entry point with the metadata it sets
+// merged into the project's.
+struct Preprocessed {
+    markdown: String,
+    source_map: SourceMap,
+    front_matter: Variables,
+    metadata: Metadata
+}
+
+// `sources` gets the entry point and every file it imports, or tries to, also
+// when preprocessing fails, so that they can be watched until they are fixed.
+fn preprocess(project: &Project, filesystem: &Arc<dyn FileSystem>, jobs: &Jobs, sources: &mut Vec<path::PathBuf>) -> Result<Preprocessed, Box<dyn std::error::Error>> {
     // Load the markdown file.
     println!("[INFO] Reading entry point");
-    let entry_path = path::Path::new(&project.path).join(&project.entry);
-    let entry_md = fs::read_to_string(&entry_path)?;
+    let entry_path = project.entry_path()?;
+    *sources = vec![entry_path.clone()];
+    let entry_md = filesystem.read_to_string(&entry_path).map_err(|err| {
+        Diagnostic::error(format!("No se pudo leer el punto de entrada: {err}")).in_file(&entry_path)
+    })?;
+    let (front_matter, entry_md) = front_matter::split(&entry_md, &entry_path)?;
+    let metadata = project.metadata.with_front_matter(&front_matter, &entry_path)?;
 
     // Preprocess the markdown.
     println!("[INFO] Preprocessing markdown");
-    let preprocessor = md_compiler::MarkdownPreprocessor::new();
+    let mut preprocessor = md_compiler::MarkdownPreprocessor::new()
+        .with_filesystem(filesystem.clone())
+        .with_base_dir(&project.dir()?)
+        .with_jobs(jobs.clone());
+    if let Some(max_import_depth) = project.max_import_depth {
+        preprocessor = preprocessor.with_max_import_stack(max_import_depth);
+    }
     let preprocessed = preprocessor.preprocess_markdown(
-        project.entry.to_str().ok_or("Could not read path for entry point")?,
+        entry_path.to_str().ok_or("Could not read path for entry point")?,
         &entry_md
-    )?;
-
-    /*This is how the output tree will look like:
-    .
-    ├── {project.output}
-    │   ├── html
-    │   │   ├── {project.assets}
-    │   │   │   └── img
-    │   │   │       └── logo.png
-    │   │   ├── index.html (this is the compiled markdown and exported into template)
-    │   │── pdf
-    │       └── index.pdf
-    ├── project.thn
-    ├── start.md
-     */
-
-    let html_assets_path = path::Path::new(&project.path).join(&project.output).join("html");
-    let absolute_html_assets_path = file_utils::try_absolute(&html_assets_path.to_string_lossy())?;
-
-    let pdf_assets_path = path::Path::new(&project.path).join(&project.output).join("pdf");
-    let absolute_pdf_assets_path = file_utils::try_absolute(&pdf_assets_path.to_string_lossy())?;
-    
-    // Copy all the contents of assets to the build directory. Files and directories
-    // are copied recursively.
+    );
+    *sources = preprocessor.referenced_files();
+    let (markdown, source_map) = preprocessed?;
+    diagnostics::report_warnings(&preprocessor.diagnostics());
+
+    Ok(Preprocessed { markdown, source_map, front_matter, metadata })
+}
+
+fn copy_assets(project: &Project, filesystem: &dyn FileSystem, paths: &BuildPaths, manifest: &mut Manifest, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    // Copy the assets directory into the build directory. Files that are already
+    // there as they are skipped, and files removed from the assets since the last
+    // build are removed from the copy.
     println!("[INFO] Copying assets");
-    let assets_path = path::Path::new(&project.path).join(&project.assets);
-    let absolute_assets_path = file_utils::try_absolute(&assets_path.to_string_lossy())?;
+    let absolute_assets_path = project.assets_path()?;
+    let copy_error = |err: std::io::Error| {
+        Diagnostic::error(format!("No se pudieron copiar los assets: {err}")).in_file(&absolute_assets_path)
+    };
+    let destination = paths.html.join(absolute_assets_path.file_name().unwrap_or_default());
+    if force {
+        fs::remove_dir_all(&destination).unwrap_or(());
+    }
+
+    let mut copied = std::collections::BTreeMap::new();
+    let mut unchanged = 0;
+    for file in filesystem.files_in(&absolute_assets_path).map_err(copy_error)? {
+        let relative = file.strip_prefix(&absolute_assets_path)?;
+        let name = relative.to_string_lossy().replace('\\', "/");
+        let target = destination.join(relative);
+
+        // Not modified since it was copied, and the copy is still there.
+        let stamp = filesystem.stamp(&file);
+        let copy_size = fs::metadata(&target).ok().map(|metadata| metadata.len());
+        let recorded = manifest.assets.get(&name)
+            .filter(|record| stamp.is_some() && record.stamp == stamp && copy_size == stamp.map(|stamp| stamp.size));
+        if let (false, Some(record)) = (force, recorded) {
+            unchanged += 1;
+            copied.insert(name, record.clone());
+            continue;
+        }
+
+        let contents = filesystem.read(&file).map_err(copy_error)?;
+        let hash = manifest::hash_bytes(&contents);
 
-    // Create the build assets directory if it doesn't exist.
-    if fs::File::open(&absolute_html_assets_path).is_err() {
-        fs::create_dir_all(&absolute_html_assets_path)?;
+        if !force && manifest::hash_file(&DiskFs, &target).as_ref() == Some(&hash) {
+            unchanged += 1;
+        } else {
+            // Tabbed so that it's clear it's a sub process.
+            println!("[INFO]\t Copying {name}");
+            fs::create_dir_all(target.parent().unwrap_or(&destination)).map_err(copy_error)?;
+            fs::write(&target, contents).map_err(copy_error)?;
+        }
+        copied.insert(name, AssetRecord { hash, stamp });
     }
 
-    if fs::File::open(&absolute_pdf_assets_path).is_err() {
-        fs::create_dir_all(&absolute_pdf_assets_path)?;
+    for name in manifest.assets.keys().filter(|name| !copied.contains_key(*name)) {
+        println!("[INFO]\t Removing {name}");
+        fs::remove_file(destination.join(name)).unwrap_or(());
     }
+    if unchanged > 0 {
+        println!("[INFO]\t {unchanged} unchanged assets skipped");
+    }
+    manifest.assets = copied;
 
-    fs_extra::dir::copy_with_progress(&absolute_assets_path, &absolute_html_assets_path, &fs_extra::dir::CopyOptions::new(), |x| {
-        // Show the file being copied. Tabbed so that it's clear it's a sub process.
-        print!("[INFO]\t Copying {} ({}/{} bytes)", x.file_name, x.file_bytes_copied, x.file_total_bytes);
-        print!("{}",
-            if x.file_bytes_copied < x.file_total_bytes {
-                "\r"
-            } else {
-                "\n"
-            }
-        );
+    Ok(())
+}
 
-        fs_extra::dir::TransitProcessResult::Overwrite
-    })?;
+// The page of the document, with every template file that was used and the
+// headings of the document.
+struct Page {
+    html: String,
+    templates: Vec<path::PathBuf>,
+    headings: Vec<Heading>
+}
 
+fn render_html(project: &Project, filesystem: &Arc<dyn FileSystem>, jobs: &Jobs, browser: &BrowserSession, paths: &BuildPaths, preprocessed: &Preprocessed, live_reload: bool) -> Result<Page, Box<dyn std::error::Error>> {
     // Generate the HTML from the markdown.
     println!("[INFO] Generating HTML");
-    let pure_html = md_compiler::markdown_to_html(&preprocessed)?;
-    // let mut pure_html = markdown::to_html_with_options(&preprocessed, &Options {
-    //     compile: CompileOptions {
-    //       allow_dangerous_html: true,
-    //       ..CompileOptions::default()
-    //     },
-    //     ..Options::default()
-    // }).unwrap();
+    let bibliography = match &project.bibliography {
+        Some(config) => {
+            println!("[INFO] Reading bibliography");
+            Some(Bibliography::load(filesystem.as_ref(), config, &project.bibliography_paths()?)?)
+        },
+        None => None
+    };
+    let mermaid = Mermaid::new(&project.mermaid, project.mermaid_script_path()?, filesystem.clone(), &project.dir()?, &paths.mermaid_cache(), browser);
+    let diagrams = Diagrams::new(&project.diagrams, &project.dir()?, &paths.diagrams_cache());
+    let markdown_options = md_compiler::MarkdownOptions {
+        toc: project.toc.as_ref(),
+        number_headings: project.number_headings,
+        bibliography: bibliography.as_ref(),
+        highlight: project.highlight.as_ref(),
+        mermaid: Some(&mermaid),
+        diagrams: Some(&diagrams),
+        language: preprocessed.metadata.language.as_deref(),
+        jobs: jobs.clone()
+    };
+    let rendered = md_compiler::markdown_to_html(&preprocessed.markdown, &preprocessed.source_map, &markdown_options)?;
+    if project.toc.is_some() && rendered.headings.is_empty() {
+        diagnostics::report_warnings(&Diagnostic::warning("El índice está vacío, el documento no tiene títulos").into());
+    }
 
     // Resolve the template.
     println!("[INFO] Resolving template");
-    let template_path = path::Path::new(&project.path).join(&project.template);
-    let absolute_template_path = file_utils::try_absolute(&template_path.to_string_lossy())?;
-    let wrapped_html = html_generation::resolve_template(
-        &absolute_template_path,
-        &pure_html
-    )?;
+    let mut variables = project.variables.clone();
+    variables.extend(preprocessed.front_matter.clone());
+    let context = html_generation::TemplateContext {
+        content: &rendered.html,
+        variables: &variables,
+        headings: &rendered.headings,
+        metadata: &preprocessed.metadata
+    };
+    let (wrapped_html, templates) = html_generation::resolve_template(filesystem, &project.template_path()?, &context)?;
+    let wrapped_html = html_generation::inject_meta_tags(&wrapped_html, &preprocessed.metadata);
+    let wrapped_html = if live_reload {
+        html_generation::inject_live_reload(&wrapped_html)
+    } else {
+        wrapped_html
+    };
+
+    Ok(Page { html: wrapped_html, templates, headings: rendered.headings })
+}
+
+// Returns every template file that was used and the headings of the document.
+fn generate_html(project: &Project, filesystem: &Arc<dyn FileSystem>, jobs: &Jobs, browser: &BrowserSession, paths: &BuildPaths, preprocessed: &Preprocessed, live_reload: bool) -> Result<(Vec<path::PathBuf>, Vec<Heading>), Box<dyn std::error::Error>> {
+    let page = render_html(project, filesystem, jobs, browser, paths, preprocessed, live_reload)?;
 
     // Write the HTML to the build directory.
     println!("[INFO] Writing HTML");
-    let build_html_path = path::Path::new(&absolute_html_assets_path).join("index.html");
-    fs::write(&build_html_path, &wrapped_html)?;
-    
+    fs::write(paths.index_html(), &page.html).map_err(|err| {
+        Diagnostic::error(format!("No se pudo escribir el HTML: {err}")).in_file(paths.index_html())
+    })?;
+
+    Ok((page.templates, page.headings))
+}
+
+// How many times the PDF is printed again to get the page numbers of the table
+// of contents right.
+const PAGE_NUMBER_PASSES: usize = 3;
+
+fn export_pdf(project: &Project, filesystem: &Arc<dyn FileSystem>, browser: &BrowserSession, paths: &BuildPaths, headings: &[Heading], metadata: &Metadata) -> Result<(), Box<dyn std::error::Error>> {
+    let pdf_error = |err: anyhow::Error| {
+        Diagnostic::error(format!("No se pudo generar el PDF: {err:#}")).in_file(paths.index_pdf())
+    };
+
+    // Headers, footers and bookmarks need to know where each heading and the main
+    // matter start, which Chrome only tells for linked ids.
+    let ids = headings.iter().map(|heading| heading.id.as_str())
+        .chain(std::iter::once(header_footer::MAINMATTER_ID))
+        .collect::<Vec<_>>();
+    let html = toc::with_link_targets(&fs::read_to_string(paths.index_html())?, &ids);
+    let page_templates = project.page_template_paths()?;
+
     // Generate the PDF from the HTML.
     println!("[INFO] Generating PDF");
+    fs::write(paths.print_html(), &html)?;
+    defer! { fs::remove_file(paths.print_html()).unwrap_or(()) }
+    pdf_exporter::export_to_pdf(browser, &paths.print_html(), &paths.index_pdf(), &project.pdf).map_err(pdf_error)?;
 
-    let build_pdf_path = path::Path::new(&absolute_pdf_assets_path).join("index.pdf");
+    // The table of contents and the footers show the label of the page each heading
+    // landed on, which depends on where `@mainmatter` landed and on the pages left
+    // out by `page_ranges`.
+    let page_labels = |pages: &HashMap<String, u32>| -> anyhow::Result<Vec<Option<PageLabel>>> {
+        let (page_count, _, _) = pdf_exporter::page_layout(&paths.index_pdf())?;
+        let printed = pdf_exporter::printed_pages(project.pdf.page_ranges(), page_count);
+        Ok(header_footer::page_labels(&project.pdf.page_numbers(), &printed, pages))
+    };
 
-    pdf_exporter::export_to_pdf(&build_html_path, &build_pdf_path)?;
+    // Where each heading landed is only known after printing. Fill in the page
+    // numbers of the table of contents and print again. The numbers can make its
+    // entries wrap differently and move what follows, so this goes on until they
+    // stay the same, `PAGE_NUMBER_PASSES` times at most.
+    if toc::has_page_placeholders(&html) {
+        println!("[INFO] Adding page numbers to the table of contents");
+        let mut printed_numbers = HashMap::new();
+        for _ in 0..PAGE_NUMBER_PASSES {
+            let pages = pdf_exporter::destination_pages(&paths.index_pdf()).map_err(pdf_error)?;
+            let numbers = header_footer::label_texts(&pages, &page_labels(&pages).map_err(pdf_error)?);
+            if numbers == printed_numbers {
+                break;
+            }
+
+            fs::write(paths.print_html(), toc::fill_page_numbers(&html, &numbers))?;
+            pdf_exporter::export_to_pdf(browser, &paths.print_html(), &paths.index_pdf(), &project.pdf).map_err(pdf_error)?;
+            printed_numbers = numbers;
+        }
+    }
 
-    // TODO: Fix the table of contents.
+    if !page_templates.is_empty() {
+        println!("[INFO] Adding headers and footers");
+        let pages = pdf_exporter::destination_pages(&paths.index_pdf()).map_err(pdf_error)?;
+        let labels = page_labels(&pages).map_err(pdf_error)?;
+        let (_, width, height) = pdf_exporter::page_layout(&paths.index_pdf()).map_err(pdf_error)?;
+        let layout = header_footer::PageLayout { width, height, margins: project.pdf.margins() };
+        let template = |file: Option<&str>| {
+            file.map(|file| PageTemplate::load(filesystem, &project.resolve(path::Path::new(file))?)).transpose()
+        };
+        let overlay = header_footer::overlay_html(
+            template(project.pdf.header())?.as_ref(),
+            template(project.pdf.footer())?.as_ref(),
+            &labels,
+            &layout,
+            headings,
+            &pages,
+            metadata
+        )?;
 
-    println!("[INFO] Done");
+        fs::write(paths.header_footer_html(), overlay)?;
+        defer! {
+            fs::remove_file(paths.header_footer_html()).unwrap_or(());
+            fs::remove_file(paths.header_footer_pdf()).unwrap_or(());
+        }
+        pdf_exporter::export_to_pdf(browser, &paths.header_footer_html(), &paths.header_footer_pdf(), &PdfConfig::default()).map_err(pdf_error)?;
+        pdf_exporter::stamp_pages(&paths.index_pdf(), &paths.header_footer_pdf()).map_err(pdf_error)?;
+    }
+
+    if project.pdf.outline() {
+        println!("[INFO] Adding bookmarks");
+        pdf_exporter::add_outline(&paths.index_pdf(), headings, project.number_headings).map_err(pdf_error)?;
+    }
+
+    pdf_exporter::set_document_info(&paths.index_pdf(), metadata).map_err(pdf_error)?;
 
     Ok(())
 }
 
+// With `force` everything is rebuilt, whether it changed or not.
+pub fn build_project(project: &Project, formats: &[OutputFormat], force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    BuildSession::new(project, formats).with_force(force).build()
+}
+
+// A build that remembers what it read, so it can be rerun partially. Used by
+// `build_project` for one-shot builds and kept alive by the watcher.
+pub struct BuildSession<'a> {
+    project: &'a Project,
+    stages: Vec<BuildStage>,
+    preprocessed: Option<Preprocessed>,
+    // Whether the generated page should reload itself when served by `thener serve`.
+    live_reload: bool,
+    // Absolute paths of the entry point and every imported markdown file.
+    sources: Vec<path::PathBuf>,
+    // Absolute paths of the template and the templates it extends or includes.
+    templates: Vec<path::PathBuf>,
+    // Headings and metadata of the last generated HTML, for the PDF. Known
+    // without preprocessing when the HTML was up to date.
+    headings: Vec<Heading>,
+    metadata: Option<Metadata>,
+    // Started on the first print and kept for the next builds.
+    browser: BrowserSession,
+    // Whether the next build ignores what the manifest says is up to date.
+    force: bool,
+    // Where the markdown is read from.
+    filesystem: Arc<dyn FileSystem>,
+    // How many imports are read, and how many diagrams drawn, at the same time.
+    jobs: Jobs,
+    progress: Option<Box<dyn Fn(BuildEvent) + 'a>>
+}
+
+impl<'a> BuildSession<'a> {
+    pub fn new(project: &'a Project, formats: &[OutputFormat]) -> BuildSession<'a> {
+        BuildSession {
+            project,
+            stages: stages_for(formats),
+            preprocessed: None,
+            live_reload: false,
+            sources: vec![],
+            templates: vec![],
+            headings: vec![],
+            metadata: None,
+            browser: BrowserSession::new(&project.browser),
+            force: false,
+            filesystem: Arc::new(DiskFs),
+            jobs: Jobs::default(),
+            progress: None
+        }
+    }
+
+    // Reads the entry point and its imports from `filesystem` instead of the disk.
+    pub fn with_filesystem(mut self, filesystem: Arc<dyn FileSystem>) -> BuildSession<'a> {
+        self.filesystem = filesystem;
+        self.preprocessed = None;
+        self
+    }
+
+    // One job per core unless given.
+    pub fn with_jobs(mut self, jobs: Option<usize>) -> BuildSession<'a> {
+        self.jobs = jobs.map(Jobs::new).unwrap_or_default();
+        self
+    }
+
+    pub fn with_progress(mut self, progress: impl Fn(BuildEvent) + 'a) -> BuildSession<'a> {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    fn emit(&self, event: BuildEvent) {
+        if let Some(progress) = &self.progress {
+            progress(event);
+        }
+    }
+
+    // Clears the caches and rebuilds every stage on the next build, only then.
+    pub fn with_force(mut self, force: bool) -> BuildSession<'a> {
+        self.force = force;
+        self
+    }
+
+    pub fn with_live_reload(mut self, live_reload: bool) -> BuildSession<'a> {
+        self.live_reload = live_reload;
+        self
+    }
+
+    pub fn project(&self) -> &'a Project {
+        self.project
+    }
+
+    pub fn sources(&self) -> &[path::PathBuf] {
+        &self.sources
+    }
+
+    pub fn build(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let stages = self.stages.clone();
+        self.run_stages(&stages)
+    }
+
+    // The page of the document, rendered without writing it nor copying the
+    // assets. Preprocesses first if it hasn't yet.
+    pub fn html(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        let paths = BuildPaths::from_project(self.project)?;
+        if self.preprocessed.is_none() {
+            self.preprocess()?;
+        }
+        let Some(preprocessed) = &self.preprocessed else {
+            unreachable!("the document was just preprocessed");
+        };
+
+        self.emit(BuildEvent::Started(BuildStage::GenerateHtml));
+        let page = render_html(self.project, &self.filesystem, &self.jobs, &self.browser, &paths, preprocessed, self.live_reload)?;
+        (self.templates, self.headings) = (page.templates, page.headings);
+        self.emit(BuildEvent::Finished(BuildStage::GenerateHtml));
+        self.emit(BuildEvent::Done);
+
+        Ok(page.html)
+    }
+
+    // The PDF of the document. Chrome prints it from the build directory, so every
+    // stage it needs is run as in a build: the HTML, the assets and `index.pdf`
+    // are written to the output directory and left there, and the PDF returned
+    // is read back from it.
+    pub fn pdf(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.run_stages(&stages_for(&[OutputFormat::Pdf]))?;
+        let paths = BuildPaths::from_project(self.project)?;
+
+        Ok(fs::read(paths.index_pdf())?)
+    }
+
+    fn preprocess(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.emit(BuildEvent::Started(BuildStage::Preprocess));
+        let preprocessed = preprocess(self.project, &self.filesystem, &self.jobs, &mut self.sources)?;
+        self.metadata = Some(preprocessed.metadata.clone());
+        self.preprocessed = Some(preprocessed);
+        self.emit(BuildEvent::Finished(BuildStage::Preprocess));
+
+        Ok(())
+    }
+
+    // Stages that must rerun when `changed` is modified, limited to the ones the
+    // requested formats need. Empty if the file is not part of the build.
+    pub fn stages_affected_by(&self, changed: &path::Path) -> Vec<BuildStage> {
+        // The entry point is a source even before it could be preprocessed.
+        let is_source = self.sources.iter().any(|source| source == changed)
+            || self.project.entry_path().ok().as_deref() == Some(changed);
+        let affected: &[BuildStage] = if is_source {
+            &[BuildStage::Preprocess, BuildStage::GenerateHtml, BuildStage::ExportPdf]
+        } else if self.project.template_path().ok().as_deref() == Some(changed)
+            || self.templates.iter().any(|template| template == changed)
+            || self.project.bibliography_paths().is_ok_and(|files| files.iter().any(|file| file == changed))
+            || self.project.mermaid_script_path().ok().flatten().as_deref() == Some(changed)
+            || self.project.program_paths().is_ok_and(|files| files.iter().any(|file| file == changed)) {
+            &[BuildStage::GenerateHtml, BuildStage::ExportPdf]
+        } else if self.project.assets_path().is_ok_and(|assets| changed.starts_with(assets)) {
+            &[BuildStage::CopyAssets, BuildStage::ExportPdf]
+        } else if self.project.page_template_paths().is_ok_and(|files| files.contains(&changed.to_path_buf())) {
+            &[BuildStage::ExportPdf]
+        } else {
+            &[]
+        };
+
+        self.stages.iter()
+            .filter(|stage| affected.contains(stage))
+            .copied()
+            .collect()
+    }
+
+    // Runs only the given stages. Generating the HTML and the PDF reuse the markdown
+    // and metadata from the last preprocessing, running it first if there is none yet.
+    // Stages whose inputs haven't changed since the last build, as recorded in the
+    // build manifest, are skipped; so are assets that are already copied.
+    pub fn run_stages(&mut self, stages: &[BuildStage]) -> Result<(), Box<dyn std::error::Error>> {
+        // Create the build directory if it doesn't exist.
+        println!("[INFO] Creating build directory");
+        let paths = BuildPaths::from_project(self.project)?;
+        let force = std::mem::take(&mut self.force);
+        if force {
+            println!("[INFO] Clearing the cache");
+            fs::remove_dir_all(&paths.cache).unwrap_or(());
+        }
+        ensure_dir(&paths.html)?;
+        if stages.contains(&BuildStage::ExportPdf) {
+            ensure_dir(&paths.pdf)?;
+        }
+        let mut manifest = Manifest::load(&paths.manifest());
+
+        // An HTML that is up to date skips preprocessing too, since the files it
+        // was made from are known.
+        // The programs that draw diagrams are on disk, whatever the filesystem.
+        let programs: Vec<String> = self.project.program_paths()?.iter()
+            .map(|program| manifest::hash_file(&DiskFs, program).unwrap_or_default())
+            .collect();
+        let html_settings = format!("{}:{}:{}", self.project.settings, self.live_reload, programs.join(","));
+        let fresh_html = manifest.html.clone()
+            .filter(|record| stages.contains(&BuildStage::GenerateHtml) && record.stage.is_fresh(self.filesystem.as_ref(), &html_settings, &paths.index_html()));
+        if let Some(record) = fresh_html.clone() {
+            println!("[INFO] HTML is up to date, skipping it");
+            self.emit(BuildEvent::Skipped(BuildStage::Preprocess));
+            self.emit(BuildEvent::Skipped(BuildStage::GenerateHtml));
+            self.sources = record.sources;
+            self.templates = record.templates;
+            self.headings = record.headings;
+            self.metadata = Some(record.metadata);
+        }
+
+        let needs_preprocess = fresh_html.is_none() && (
+            stages.contains(&BuildStage::Preprocess)
+                || (stages.contains(&BuildStage::GenerateHtml) && self.preprocessed.is_none())
+                || (stages.contains(&BuildStage::ExportPdf) && self.metadata.is_none())
+        );
+        if needs_preprocess {
+            self.preprocess()?;
+        }
+
+        if stages.contains(&BuildStage::CopyAssets) {
+            self.emit(BuildEvent::Started(BuildStage::CopyAssets));
+            copy_assets(self.project, self.filesystem.as_ref(), &paths, &mut manifest, force)?;
+            manifest.save(&paths.manifest())?;
+            self.emit(BuildEvent::Finished(BuildStage::CopyAssets));
+        }
+
+        if let (true, None, Some(preprocessed)) = (stages.contains(&BuildStage::GenerateHtml), &fresh_html, &self.preprocessed) {
+            self.emit(BuildEvent::Started(BuildStage::GenerateHtml));
+            (self.templates, self.headings) = generate_html(self.project, &self.filesystem, &self.jobs, &self.browser, &paths, preprocessed, self.live_reload)?;
+
+            let inputs: Vec<path::PathBuf> = self.sources.iter()
+                .chain(&self.templates)
+                .cloned()
+                .chain(self.project.bibliography_paths()?)
+                .chain(self.project.mermaid_script_path()?)
+                .collect();
+            manifest.html = Some(HtmlRecord {
+                stage: StageRecord::new(self.filesystem.as_ref(), &html_settings, &inputs, &paths.index_html()),
+                sources: self.sources.clone(),
+                templates: self.templates.clone(),
+                headings: self.headings.clone(),
+                metadata: preprocessed.metadata.clone()
+            });
+            manifest.save(&paths.manifest())?;
+            self.emit(BuildEvent::Finished(BuildStage::GenerateHtml));
+        }
+
+        if let (true, Some(metadata)) = (stages.contains(&BuildStage::ExportPdf), &self.metadata) {
+            // The PDF is printed from the HTML, with the assets it links to. Both are
+            // in the build directory, on disk: the hash of the HTML and the ones the
+            // manifest has for the copied assets go in the settings of the stage.
+            let inputs = self.project.page_template_paths()?;
+            let assets: Vec<String> = manifest.assets.iter().map(|(name, asset)| format!("{name}={}", asset.hash)).collect();
+            let pdf_settings = format!(
+                "{}:{}:{}",
+                self.project.settings,
+                manifest::hash_file(&DiskFs, &paths.index_html()).unwrap_or_default(),
+                manifest::hash_bytes(assets.join(",").as_bytes())
+            );
+            let fresh_pdf = manifest.pdf.as_ref().is_some_and(|record| record.is_fresh(self.filesystem.as_ref(), &pdf_settings, &paths.index_pdf()));
+
+            if fresh_pdf {
+                println!("[INFO] PDF is up to date, skipping it");
+                self.emit(BuildEvent::Skipped(BuildStage::ExportPdf));
+            } else {
+                self.emit(BuildEvent::Started(BuildStage::ExportPdf));
+                export_pdf(self.project, &self.filesystem, &self.browser, &paths, &self.headings, metadata)?;
+                manifest.pdf = Some(StageRecord::new(self.filesystem.as_ref(), &pdf_settings, &inputs, &paths.index_pdf()));
+                manifest.save(&paths.manifest())?;
+                self.emit(BuildEvent::Finished(BuildStage::ExportPdf));
+            }
+        }
+
+        println!("[INFO] Done");
+        self.emit(BuildEvent::Done);
+
+        Ok(())
+    }
+}
+
 pub fn read_configuration(project_path: &str) -> Result<Project, Box<dyn std::error::Error>> {
-    let project_path = file_utils::try_absolute(&project_path)?;
-    let project_parent = path::Path::new(&project_path).parent()
-        .ok_or(format!("No se pudo encontrar el directorio padre de {project_path}"))?;
+    let project_path = path::PathBuf::from(file_utils::try_absolute(project_path)?);
 
     println!("[INFO] Reading project");
-    let project_file = fs::File::open(&project_path)?;
+    let config = fs::read_to_string(&project_path)?;
+    let project = parse_configuration(&config, &project_path)?;
+
+    println!("[INFO] Building project");
+
+    Ok(project)
+}
 
-    let read_project: ReadProject = serde_json::from_reader(project_file)?;
+// A project packed in a `.zip`, `.tar`, `.tar.gz` or `.tgz` archive, with the
+// `project.thn` closest to the root of the archive. Its files are read from the
+// archive as if it was extracted next to it, in a directory named like it: the
+// build directory of `tesis.zip` ends up in `tesis/build`.
+pub fn read_archive(archive_path: &str) -> Result<(Project, Arc<dyn FileSystem>), Box<dyn std::error::Error>> {
+    let archive_path = path::PathBuf::from(file_utils::try_absolute(archive_path)?);
+    let root = archive_path.with_file_name(filesystem::archive_stem(&archive_path));
+
+    println!("[INFO] Reading archive");
+    let files = MemoryFs::from_archive(&archive_path, &root).map_err(|err| {
+        Diagnostic::error(format!("No se pudo leer el archivo comprimido: {err}")).in_file(&archive_path)
+    })?;
+    let project_path = files.files_in(&root).unwrap_or_default().into_iter()
+        .filter(|file| file.file_name().is_some_and(|name| name == "project.thn"))
+        .min_by_key(|file| file.components().count())
+        .ok_or_else(|| Diagnostic::error("El archivo comprimido no tiene un `project.thn`").in_file(&archive_path))?;
+    let project = parse_configuration(&files.read_to_string(&project_path)?, &project_path)?;
 
     println!("[INFO] Building project");
 
-    Ok(
-        Project::from_read_project(&read_project, project_parent.to_str().unwrap_or("."))
-    )
-}
\ No newline at end of file
+    Ok((project, Arc::new(files)))
+}
+
+// A project from its `project.thn` on disk or from an archive, along with where
+// its files are read from.
+pub fn open_project(project_path: &str) -> Result<(Project, Arc<dyn FileSystem>), Box<dyn std::error::Error>> {
+    if filesystem::is_archive(path::Path::new(project_path)) {
+        read_archive(project_path)
+    } else {
+        Ok((read_configuration(project_path)?, Arc::new(DiskFs)))
+    }
+}
+
+fn parse_configuration(config: &str, project_path: &path::Path) -> Result<Project, Box<dyn std::error::Error>> {
+    let project_parent = project_path.parent()
+        .ok_or(format!("No se pudo encontrar el directorio padre de {}", project_path.display()))?;
+
+    let read_project: ReadProject = serde_json::from_str(config)?;
+    validate(&read_project).map_err(|err| Diagnostic::error(format!("Configuración inválida: {err}")).in_file(project_path))?;
+
+    let mut project = Project::from_read_project(&read_project, project_parent.to_str().unwrap_or("."));
+    project.file = Some(project_path.to_path_buf());
+
+    Ok(project)
+}
+
+fn validate(read_project: &ReadProject) -> Result<(), String> {
+    if let Some(pdf) = &read_project.pdf {
+        pdf.validate()?;
+    }
+    if let Some(config) = &read_project.diagrams {
+        diagrams::validate(config)?;
+    }
+    if let Some(mermaid) = &read_project.mermaid {
+        mermaid.validate()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::{fs, path, thread};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use percent_encoding::percent_decode_str;
+use tiny_http::{Header, Response, Server};
+
+use crate::html_generation;
+
+// Incremented after every successful build. Pages served with the live reload
+// script poll it and reload when it changes.
+#[derive(Clone, Default)]
+pub struct BuildVersion(Arc<AtomicU64>);
+
+impl BuildVersion {
+    pub fn bump(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+fn content_type(file: &path::Path) -> &'static str {
+    match file.extension().and_then(|ext| ext.to_str()).unwrap_or("") {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream"
+    }
+}
+
+// Maps a request url to a file inside `root`. Returns `None` for urls that try
+// to leave it.
+fn resolve_request(root: &path::Path, url: &str) -> Option<path::PathBuf> {
+    let url_path = url.split(['?', '#']).next().unwrap_or("");
+
+    let mut file = root.to_path_buf();
+    for segment in url_path.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        if segment == ".." || segment.contains(['/', '\\']) {
+            return None;
+        }
+
+        file.push(segment.as_ref());
+    }
+
+    if file.is_dir() {
+        file.push("index.html");
+    }
+
+    Some(file)
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Cabecera HTTP inválida")
+}
+
+fn handle(request: tiny_http::Request, root: &path::Path, version: &BuildVersion) -> std::io::Result<()> {
+    if request.url() == html_generation::LIVE_RELOAD_PATH {
+        let response = Response::from_string(version.get().to_string())
+            .with_header(header("Content-Type", "text/plain"))
+            .with_header(header("Cache-Control", "no-store"));
+        return request.respond(response);
+    }
+
+    let file = resolve_request(root, request.url());
+    match file.as_ref().and_then(|file| fs::read(file).ok().map(|data| (file, data))) {
+        Some((file, data)) => {
+            let response = Response::from_data(data)
+                .with_header(header("Content-Type", content_type(file)))
+                .with_header(header("Cache-Control", "no-store"));
+            request.respond(response)
+        },
+        None => request.respond(Response::from_string("404: no encontrado").with_status_code(404))
+    }
+}
+
+// Serves the files in `root` on `localhost:port` from a background thread.
+pub fn serve(root: path::PathBuf, port: u16, version: BuildVersion) -> Result<thread::JoinHandle<()>, Box<dyn std::error::Error>> {
+    let server = Server::http(("127.0.0.1", port)).map_err(|err| format!("No se pudo abrir el puerto {port}: {err}"))?;
+    println!("[INFO] Sirviendo {} en http://localhost:{port}", root.display());
+
+    Ok(thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if let Err(err) = handle(request, &root, &version) {
+                println!("[ERROR] Error respondiendo una petición: {err}");
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("capitulos")).unwrap();
+        fs::write(dir.path().join("index.html"), "").unwrap();
+        fs::write(dir.path().join("capitulos/index.html"), "").unwrap();
+        dir
+    }
+
+    #[test]
+    fn directories_are_served_by_their_index() {
+        let dir = site();
+
+        assert_eq!(resolve_request(dir.path(), "/"), Some(dir.path().join("index.html")));
+        assert_eq!(resolve_request(dir.path(), "/capitulos/"), Some(dir.path().join("capitulos/index.html")));
+        assert_eq!(resolve_request(dir.path(), "/capitulos"), Some(dir.path().join("capitulos/index.html")));
+    }
+
+    #[test]
+    fn urls_are_decoded_without_query_nor_fragment() {
+        let dir = site();
+
+        assert_eq!(resolve_request(dir.path(), "/assets/estilo.css?v=2#arriba"), Some(dir.path().join("assets/estilo.css")));
+        assert_eq!(resolve_request(dir.path(), "/mi%20tesis.pdf"), Some(dir.path().join("mi tesis.pdf")));
+    }
+
+    #[test]
+    fn urls_cannot_leave_the_root() {
+        let dir = site();
+
+        for url in ["/../secreto", "/capitulos/../../secreto", "/%2e%2e/secreto", "/a%2F..%2F..%2Fsecreto", "/a%5C..", "/%FF"] {
+            assert_eq!(resolve_request(dir.path(), url), None, "{url}");
+        }
+    }
+}
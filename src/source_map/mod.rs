@@ -0,0 +1,92 @@
+use std::{path, rc::Rc};
+
+// Where a line of the preprocessed markdown came from. `line` is 1-based.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: Rc<path::PathBuf>,
+    pub line: usize
+}
+
+/* Maps every line of the preprocessed markdown back to the file and line it was
+read from. The import boundary comments added by the preprocessor point at the
+first and last line of the imported file.
+
+Files are stored as absolute paths and shown relative to `root`, the directory of
+the entry point. */
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    root: path::PathBuf,
+    lines: Vec<SourceLine>
+}
+
+impl SourceMap {
+    pub fn new(root: impl Into<path::PathBuf>) -> SourceMap {
+        SourceMap { root: root.into(), lines: vec![] }
+    }
+
+    pub fn push(&mut self, source: SourceLine) {
+        self.lines.push(source);
+    }
+
+    pub fn extend(&mut self, other: SourceMap) {
+        self.lines.extend(other.lines);
+    }
+
+    // Looks up a 1-based line of the preprocessed markdown.
+    pub fn lookup(&self, line: usize) -> Option<&SourceLine> {
+        line.checked_sub(1).and_then(|index| self.lines.get(index))
+    }
+
+    // `file:line` for a line of the preprocessed markdown, with `file` relative to
+    // the entry point's directory. This is what ends up in `data-source`.
+    pub fn display(&self, line: usize) -> Option<String> {
+        self.lookup(line).map(|source| {
+            let file = source.file.strip_prefix(&self.root).unwrap_or(&source.file);
+            format!("{}:{}", file.to_string_lossy(), source.line)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source_map() -> SourceMap {
+        let start = Rc::new(path::PathBuf::from("/doc/start.md"));
+        let chapter = Rc::new(path::PathBuf::from("/doc/capitulos/uno.md"));
+        let mut imported = SourceMap::default();
+        imported.push(SourceLine { file: chapter.clone(), line: 1 });
+        imported.push(SourceLine { file: chapter, line: 2 });
+
+        let mut source_map = SourceMap::new("/doc");
+        source_map.push(SourceLine { file: start.clone(), line: 1 });
+        source_map.extend(imported);
+        source_map.push(SourceLine { file: start, line: 3 });
+        source_map.push(SourceLine { file: Rc::new(path::PathBuf::from("/otro/anexo.md")), line: 7 });
+        source_map
+    }
+
+    #[test]
+    fn lines_are_looked_up_from_one() {
+        let source_map = source_map();
+
+        assert_eq!(source_map.lookup(0), None);
+        assert_eq!(source_map.lookup(3).map(|source| source.line), Some(2));
+        assert_eq!(source_map.lookup(6), None);
+    }
+
+    #[test]
+    fn files_are_shown_from_the_entry_point() {
+        let source_map = source_map();
+        let displayed: Vec<Option<String>> = (1..=6).map(|line| source_map.display(line)).collect();
+
+        assert_eq!(displayed, [
+            Some("start.md:1".to_string()),
+            Some(format!("{}:1", path::Path::new("capitulos").join("uno.md").display())),
+            Some(format!("{}:2", path::Path::new("capitulos").join("uno.md").display())),
+            Some("start.md:3".to_string()),
+            Some("/otro/anexo.md:7".to_string()),
+            None
+        ]);
+    }
+}
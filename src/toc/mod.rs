@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use crate::cross_refs::{Captioned, Labels};
+use crate::html_generation;
+
+// Placed alone in a line, marks where the table of contents goes.
+pub static TOC_DIRECTIVE: &str = "@toc";
+// Same for the lists of the captioned figures and tables.
+pub static LIST_OF_FIGURES_DIRECTIVE: &str = "@listoffigures";
+pub static LIST_OF_TABLES_DIRECTIVE: &str = "@listoftables";
+
+/* The `toc` section of `project.thn`, all of it optional:
+{
+    "toc": {
+        "title": "Índice",
+        "depth": 3,
+        "figures_title": "Índice de figuras",
+        "tables_title": "Índice de tablas"
+    }
+}
+
+With it, the table of contents is added at the start of the document unless an
+`@toc` directive places it somewhere else. The lists of figures and tables are
+only added where the `@listoffigures` and `@listoftables` directives are; their
+titles default to the language of the document. */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+pub struct TocConfig {
+    title: Option<String>,
+    depth: Option<u8>,
+    figures_title: Option<String>,
+    tables_title: Option<String>
+}
+
+impl TocConfig {
+    fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or("Índice")
+    }
+
+    fn depth(&self) -> u8 {
+        self.depth.unwrap_or(3)
+    }
+
+    pub fn figures_title(&self) -> Option<&str> {
+        self.figures_title.as_deref()
+    }
+
+    pub fn tables_title(&self) -> Option<&str> {
+        self.tables_title.as_deref()
+    }
+}
+
+// A heading of the document with the id it was rendered with and its number,
+// e.g. "2.3".
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Heading {
+    pub level: u8,
+    pub text: String,
+    pub id: String,
+    pub number: String
+}
+
+fn page_placeholder(id: &str) -> String {
+    format!("<span class='toc-page' data-target='{}'></span>", html_generation::escape_attribute(id))
+}
+
+/* Builds the table of contents as nested lists:
+
+<nav class='toc'>
+    <h1 class='toc-title'>Índice</h1>
+    <ol>
+        <li class='toc-entry toc-level-1'><a href='#intro'>Intro</a><span class='toc-page' data-target='intro'></span>
+            <ol>...</ol>
+        </li>
+    </ol>
+</nav>
+
+The `toc-page` spans are empty in the HTML output and filled with page numbers
+for the PDF. */
+pub fn toc_html(headings: &[Heading], config: &TocConfig, show_numbers: bool) -> String {
+    let headings: Vec<&Heading> = headings.iter()
+        .filter(|heading| heading.level <= config.depth())
+        .collect();
+
+    let mut html = format!("<nav class='toc'>\n<h1 class='toc-title'>{}</h1>\n", html_generation::escape_text(config.title()));
+
+    // Levels of the lists currently open. A heading that skips levels (an h3 right
+    // after an h1) is nested only once.
+    let mut open: Vec<u8> = vec![];
+    for heading in headings {
+        while open.last().is_some_and(|level| *level > heading.level) {
+            html.push_str("</li>\n</ol>\n");
+            open.pop();
+        }
+
+        if open.last().is_some_and(|level| *level == heading.level) {
+            html.push_str("</li>\n");
+        } else {
+            html.push_str("<ol>\n");
+            open.push(heading.level);
+        }
+
+        let number = if show_numbers {
+            format!("<span class='toc-number'>{}</span> ", heading.number)
+        } else {
+            String::new()
+        };
+
+        html.push_str(&format!(
+            "<li class='toc-entry toc-level-{}'><a href='#{}'>{number}{}</a>{}",
+            heading.level,
+            html_generation::escape_attribute(&heading.id),
+            html_generation::escape_text(&heading.text),
+            page_placeholder(&heading.id)
+        ));
+    }
+
+    for _ in open {
+        html.push_str("</li>\n</ol>\n");
+    }
+
+    html.push_str("</nav>\n");
+    html
+}
+
+/* Builds a list of captioned figures or tables, with the same classes as the
+table of contents:
+
+<nav class='toc list-of-figures'>
+    <h1 class='toc-title'>Índice de figuras</h1>
+    <ol>
+        <li class='toc-entry'><a href='#figure-1'><span class='toc-number'>Figura 1.</span> Arquitectura</a><span class='toc-page' data-target='figure-1'></span></li>
+    </ol>
+</nav> */
+pub fn list_html(title: &str, class: &str, entries: &[&Captioned], labels: &Labels) -> String {
+    let mut html = format!(
+        "<nav class='toc {class}'>\n<h1 class='toc-title'>{}</h1>\n<ol>\n",
+        html_generation::escape_text(title)
+    );
+    for entry in entries {
+        html.push_str(&format!(
+            "<li class='toc-entry'><a href='#{}'><span class='toc-number'>{}</span> {}</a>{}</li>\n",
+            html_generation::escape_attribute(&entry.id),
+            html_generation::escape_text(&labels.caption_label(entry.kind, &entry.number)),
+            html_generation::escape_text(&entry.text),
+            page_placeholder(&entry.id)
+        ));
+    }
+    html.push_str("</ol>\n</nav>\n");
+
+    html
+}
+
+// Chrome only adds named destinations for ids that some link points to. Invisible
+// links to every id in `ids`, placed before `</body>`, so that the PDF tells the
+// page of each of them.
+pub fn with_link_targets(html: &str, ids: &[&str]) -> String {
+    let mut links = String::from("<div class='link-targets' style='position: absolute; width: 1px; height: 1px; overflow: hidden; clip: rect(0 0 0 0);'>");
+    for id in ids {
+        links.push_str(&format!("<a href='#{}'></a>", html_generation::escape_attribute(id)));
+    }
+    links.push_str("</div>\n");
+
+    match html.rfind("</body>") {
+        Some(body_end) => format!("{}{}{}", &html[..body_end], links, &html[body_end..]),
+        None => format!("{html}{links}")
+    }
+}
+
+pub fn has_page_placeholders(html: &str) -> bool {
+    html.contains("<span class='toc-page' data-target='")
+}
+
+// Fills the `toc-page` spans with the label of the page each target landed on,
+// as the footers show it. Targets with no known page are left empty.
+pub fn fill_page_numbers(html: &str, pages: &HashMap<String, String>) -> String {
+    let mut html = html.to_string();
+    for (id, page) in pages {
+        let placeholder = page_placeholder(id);
+        let filled = placeholder.replace("></span>", &format!(">{page}</span>"));
+        html = html.replace(&placeholder, &filled);
+    }
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heading(level: u8, text: &str, id: &str, number: &str) -> Heading {
+        Heading { level, text: text.to_string(), id: id.to_string(), number: number.to_string() }
+    }
+
+    fn headings() -> Vec<Heading> {
+        vec![
+            heading(1, "Introducción", "introduccion", "1"),
+            heading(3, "Alcance", "alcance", "1.0.1"),
+            heading(2, "Método", "l'método", "1.1"),
+            heading(1, "Resultados", "resultados", "2")
+        ]
+    }
+
+    #[test]
+    fn skipped_levels_are_nested_once() {
+        let html = toc_html(&headings(), &TocConfig::default(), true);
+
+        assert_eq!(html.matches("<ol>").count(), 3);
+        assert_eq!(html.matches("<ol>").count(), html.matches("</ol>").count());
+        assert!(html.contains("<a href='#introduccion'><span class='toc-number'>1</span> Introducción</a>"));
+        assert!(html.find("alcance").unwrap() < html.find("l&#39;método").unwrap());
+    }
+
+    #[test]
+    fn depth_limits_the_entries() {
+        let config = TocConfig { depth: Some(1), ..TocConfig::default() };
+        let html = toc_html(&headings(), &config, false);
+
+        assert!(html.contains("Introducción") && html.contains("Resultados"));
+        assert!(!html.contains("Método") && !html.contains("toc-number"));
+    }
+
+    #[test]
+    fn every_list_has_page_placeholders() {
+        assert!(has_page_placeholders(&toc_html(&headings(), &TocConfig::default(), true)));
+        assert!(!has_page_placeholders(&toc_html(&[], &TocConfig::default(), true)));
+        assert!(!has_page_placeholders("<p>Sin índice</p>"));
+    }
+
+    #[test]
+    fn page_numbers_fill_their_placeholders() {
+        let html = toc_html(&headings(), &TocConfig::default(), true);
+        let pages = HashMap::from([
+            ("introduccion".to_string(), "iii".to_string()),
+            ("l'método".to_string(), "4".to_string()),
+            ("otro".to_string(), "9".to_string())
+        ]);
+        let filled = fill_page_numbers(&html, &pages);
+
+        assert!(filled.contains("<span class='toc-page' data-target='introduccion'>iii</span>"));
+        assert!(filled.contains("<span class='toc-page' data-target='l&#39;método'>4</span>"));
+        // Headings that weren't found in the PDF are left empty.
+        assert!(filled.contains("<span class='toc-page' data-target='resultados'></span>"));
+        assert!(!filled.contains(">9</span>"));
+    }
+}
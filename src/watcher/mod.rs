@@ -0,0 +1,140 @@
+use std::{path, sync::mpsc, time::Duration};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::diagnostics;
+use crate::project_builder::{read_configuration, BuildSession, BuildStage, OutputFormat, Project};
+
+// After the first event, keep collecting for this long so that an editor writing
+// several files (or one file in several chunks) triggers a single rebuild.
+const DEBOUNCE: Duration = Duration::from_millis(150);
+
+fn changed_paths(event: notify::Result<notify::Event>) -> Vec<path::PathBuf> {
+    match event {
+        Ok(event) if !matches!(event.kind, EventKind::Access(_)) => event.paths,
+        Ok(_) => vec![],
+        Err(err) => {
+            println!("[ERROR] Error observando archivos: {err}");
+            vec![]
+        }
+    }
+}
+
+// The project directory is watched recursively. Imports, the template, the
+// assets, `project.thn` or any other file the configuration points to may live
+// outside of it, so their directories are watched on their own.
+fn watch_outside_project<W: Watcher>(
+    watcher: &mut W,
+    session: &BuildSession,
+    watched: &mut Vec<path::PathBuf>
+) -> Result<(), Box<dyn std::error::Error>> {
+    let project = session.project();
+    let files: Vec<path::PathBuf> = session.sources().iter()
+        .cloned()
+        .chain(project.template_path().ok())
+        .chain(project.config_path().map(path::Path::to_path_buf))
+        .chain(project.bibliography_paths()?)
+        .chain(project.page_template_paths()?)
+        .chain(project.mermaid_script_path()?)
+        .chain(project.program_paths()?)
+        .collect();
+    let mut targets: Vec<(path::PathBuf, RecursiveMode)> = files.iter()
+        .filter_map(|file| file.parent().map(|parent| (parent.to_path_buf(), RecursiveMode::NonRecursive)))
+        .collect();
+    targets.push((project.assets_path()?, RecursiveMode::Recursive));
+
+    // The directory of an import that doesn't exist may not exist either. It is
+    // watched once it does, after the next build.
+    for (target, mode) in targets {
+        if target.starts_with(project.location()) || watched.contains(&target) || !target.is_dir() {
+            continue;
+        }
+
+        watcher.watch(&target, mode)?;
+        watched.push(target);
+    }
+
+    Ok(())
+}
+
+// Builds the project and rebuilds it every time one of its inputs changes: the
+// entry point, any imported file, the template, anything inside the assets or
+// any other file named in `project.thn`. Only the stages affected by the change
+// are rerun, and everything when `project.thn` itself changes. With `force` the
+// first build rebuilds everything.
+pub fn watch_project(project: &Project, formats: &[OutputFormat], force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reloaded = watch(&mut BuildSession::new(project, formats).with_force(force), || {})?;
+    while let Some(project) = reloaded {
+        reloaded = watch(&mut BuildSession::new(&project, formats), || {})?;
+    }
+
+    Ok(())
+}
+
+// Same as `watch_project` for an already configured session. `on_build` runs
+// after every successful build, including the first one. Returns the project
+// read again when its `project.thn` changes, for the caller to go on with a new
+// session; while it is invalid the error is shown and the current one kept.
+pub fn watch<F: FnMut()>(session: &mut BuildSession, mut on_build: F) -> Result<Option<Project>, Box<dyn std::error::Error>> {
+    match session.build() {
+        Ok(()) => on_build(),
+        Err(err) => diagnostics::report(err.as_ref())
+    }
+
+    let project = session.project();
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(project.location(), RecursiveMode::Recursive)?;
+
+    let mut watched_outside = vec![];
+    watch_outside_project(&mut watcher, session, &mut watched_outside)?;
+
+    let output_path = project.output_path()?;
+
+    println!("[INFO] Observando cambios en {}", project.location().display());
+    while let Ok(event) = receiver.recv() {
+        let mut changed = changed_paths(event);
+        while let Ok(event) = receiver.recv_timeout(DEBOUNCE) {
+            changed.extend(changed_paths(event));
+        }
+
+        // Anything written by the build itself would trigger an endless loop.
+        changed.retain(|file| !file.starts_with(&output_path));
+        changed.sort();
+        changed.dedup();
+
+        if let Some(config) = project.config_path().filter(|config| changed.iter().any(|file| file == config)) {
+            println!("[INFO] Cambio detectado en {}", config.display());
+            match read_configuration(&config.to_string_lossy()) {
+                Ok(reloaded) => return Ok(Some(reloaded)),
+                Err(err) => {
+                    diagnostics::report(err.as_ref());
+                    println!("[INFO] Se sigue usando la configuración anterior");
+                    continue;
+                }
+            }
+        }
+
+        let mut stages: Vec<BuildStage> = changed.iter()
+            .flat_map(|file| session.stages_affected_by(file))
+            .collect();
+        stages.sort();
+        stages.dedup();
+
+        if stages.is_empty() {
+            continue;
+        }
+
+        for file in &changed {
+            println!("[INFO] Cambio detectado en {}", file.display());
+        }
+
+        match session.run_stages(&stages) {
+            Ok(()) => on_build(),
+            Err(err) => diagnostics::report(err.as_ref())
+        }
+
+        watch_outside_project(&mut watcher, session, &mut watched_outside)?;
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,107 @@
+use std::cell::RefCell;
+use std::{fs, path, sync::Arc};
+
+use thener::{BuildEvent, BuildSession, BuildStage, MemoryFs, OutputFormat, Project};
+
+static CONFIG: &str = r#"{
+    "path": ".",
+    "assets": "./assets",
+    "template": "./templates/main.html",
+    "entry": "start.md",
+    "metadata": { "title": "Tesis en memoria" }
+}"#;
+
+// A project that is only in memory. Its build directory is in `dir`, on disk.
+fn project_files(dir: &path::Path) -> MemoryFs {
+    MemoryFs::new()
+        .with_file(dir.join("start.md"), "# Introducción\n\n@import capitulos/uno\n")
+        .with_file(dir.join("capitulos/uno.md"), "Texto con `@ref(fig:x)` en código.\n")
+        .with_file(dir.join("templates/main.html"), "<title>#{ metadata.title }#</title>\n<main>#{contenido}#</main>\n")
+        .with_file(dir.join("assets/styles.css"), "body { margin: 0; }")
+}
+
+fn build_events(project: &Project, files: &MemoryFs, build: impl FnOnce(&mut BuildSession)) -> Vec<BuildEvent> {
+    let events = RefCell::new(vec![]);
+    let mut session = BuildSession::new(project, &[OutputFormat::Html])
+        .with_filesystem(Arc::new(files.clone()))
+        .with_jobs(Some(2))
+        .with_progress(|event| events.borrow_mut().push(event));
+    build(&mut session);
+    drop(session);
+
+    events.into_inner()
+}
+
+#[test]
+fn renders_html_from_memory() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = Project::from_config(CONFIG, dir.path()).unwrap();
+    let files = project_files(dir.path());
+
+    let mut html = String::new();
+    let events = build_events(&project, &files, |session| html = session.html().unwrap());
+
+    assert_eq!(events, vec![
+        BuildEvent::Started(BuildStage::Preprocess),
+        BuildEvent::Finished(BuildStage::Preprocess),
+        BuildEvent::Started(BuildStage::GenerateHtml),
+        BuildEvent::Finished(BuildStage::GenerateHtml),
+        BuildEvent::Done
+    ]);
+    assert!(html.contains("<title>Tesis en memoria</title>"));
+    assert!(html.contains("Introducción</h1>"));
+    assert!(html.contains(">@ref(fig:x)</code> en código."));
+    assert!(!dir.path().join("build/html/index.html").exists());
+}
+
+#[test]
+fn builds_from_memory_and_skips_what_is_up_to_date() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = Project::from_config(CONFIG, dir.path()).unwrap();
+    let files = project_files(dir.path());
+
+    let events = build_events(&project, &files, |session| session.build().unwrap());
+
+    assert_eq!(events, vec![
+        BuildEvent::Started(BuildStage::Preprocess),
+        BuildEvent::Finished(BuildStage::Preprocess),
+        BuildEvent::Started(BuildStage::CopyAssets),
+        BuildEvent::Finished(BuildStage::CopyAssets),
+        BuildEvent::Started(BuildStage::GenerateHtml),
+        BuildEvent::Finished(BuildStage::GenerateHtml),
+        BuildEvent::Done
+    ]);
+    let html = fs::read_to_string(dir.path().join("build/html/index.html")).unwrap();
+    assert!(html.contains(">@ref(fig:x)</code> en código.</p>"));
+    assert_eq!(fs::read_to_string(dir.path().join("build/html/assets/styles.css")).unwrap(), "body { margin: 0; }");
+
+    let events = build_events(&project, &files, |session| session.build().unwrap());
+
+    assert_eq!(events, vec![
+        BuildEvent::Skipped(BuildStage::Preprocess),
+        BuildEvent::Skipped(BuildStage::GenerateHtml),
+        BuildEvent::Started(BuildStage::CopyAssets),
+        BuildEvent::Finished(BuildStage::CopyAssets),
+        BuildEvent::Done
+    ]);
+}
+
+// Assets on disk are only read again when their size or modification time change.
+#[test]
+fn copies_changed_assets_again() {
+    let dir = tempfile::tempdir().unwrap();
+    let project = Project::from_config(CONFIG, dir.path()).unwrap();
+    for (file, contents) in [("start.md", "# Uno"), ("templates/main.html", "#{contenido}#"), ("assets/a.css", "a {}"), ("assets/b.css", "b {}")] {
+        fs::create_dir_all(dir.path().join(file).parent().unwrap()).unwrap();
+        fs::write(dir.path().join(file), contents).unwrap();
+    }
+    let copy = |name: &str| fs::read_to_string(dir.path().join("build/html/assets").join(name)).ok();
+
+    BuildSession::new(&project, &[OutputFormat::Html]).build().unwrap();
+    assert_eq!((copy("a.css").as_deref(), copy("b.css").as_deref()), (Some("a {}"), Some("b {}")));
+
+    fs::write(dir.path().join("assets/a.css"), "a { color: red; }").unwrap();
+    fs::remove_file(dir.path().join("assets/b.css")).unwrap();
+    BuildSession::new(&project, &[OutputFormat::Html]).build().unwrap();
+    assert_eq!((copy("a.css").as_deref(), copy("b.css")), (Some("a { color: red; }"), None));
+}
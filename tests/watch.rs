@@ -0,0 +1,75 @@
+use std::fs;
+
+use thener::{BuildSession, BuildStage, OutputFormat};
+
+// Files outside the project named in `project.thn` are inputs of the build too.
+#[test]
+fn configured_files_affect_the_html() {
+    let dir = tempfile::tempdir().unwrap();
+    let project_dir = dir.path().join("tesis");
+    fs::create_dir(&project_dir).unwrap();
+    fs::write(project_dir.join("project.thn"), r#"{
+        "path": ".",
+        "assets": "./assets",
+        "template": "./main.html",
+        "entry": "start.md",
+        "mermaid": { "command": "../herramientas/mmdc" },
+        "diagrams": { "dot": { "command": "/opt/graphviz/dot -Tsvg" }, "d2": { "command": "d2 {input} {output}" } }
+    }"#).unwrap();
+
+    let project = thener::read_configuration(&project_dir.join("project.thn").to_string_lossy()).unwrap();
+    let session = BuildSession::new(&project, &[OutputFormat::Html]);
+
+    assert_eq!(project.config_path(), Some(project_dir.join("project.thn").as_path()));
+    assert_eq!(project.program_paths().unwrap(), vec![
+        dir.path().join("herramientas/mmdc"),
+        std::path::PathBuf::from("/opt/graphviz/dot")
+    ]);
+    assert_eq!(session.stages_affected_by(&dir.path().join("herramientas/mmdc")), vec![BuildStage::GenerateHtml]);
+    assert_eq!(session.stages_affected_by(std::path::Path::new("/opt/graphviz/dot")), vec![BuildStage::GenerateHtml]);
+    assert_eq!(session.stages_affected_by(std::path::Path::new("/usr/bin/d2")), vec![]);
+}
+
+// What the watcher does: after a failed build, fixing the entry point or creating
+// the missing import rebuilds the affected stages.
+#[test]
+fn recovers_from_a_failed_first_build() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("project.thn"), r#"{ "path": ".", "assets": "./assets", "template": "./main.html", "entry": "start.md" }"#).unwrap();
+    fs::write(dir.path().join("main.html"), "#{contenido}#").unwrap();
+    fs::create_dir(dir.path().join("assets")).unwrap();
+    fs::write(dir.path().join("assets/a.css"), "").unwrap();
+    fs::write(dir.path().join("start.md"), "# Inicio\n@import capitulos/uno\n").unwrap();
+
+    let project = thener::read_configuration(&dir.path().join("project.thn").to_string_lossy()).unwrap();
+    let mut session = BuildSession::new(&project, &[OutputFormat::Html]);
+    assert!(session.build().is_err());
+
+    let all = vec![BuildStage::Preprocess, BuildStage::GenerateHtml];
+    let missing = dir.path().join("capitulos/uno.md");
+    assert_eq!(session.stages_affected_by(&dir.path().join("start.md")), all);
+    assert_eq!(session.stages_affected_by(&missing), all);
+
+    fs::create_dir(dir.path().join("capitulos")).unwrap();
+    fs::write(&missing, "Capítulo uno").unwrap();
+    session.run_stages(&session.stages_affected_by(&missing)).unwrap();
+
+    let html = fs::read_to_string(dir.path().join("build/html/index.html")).unwrap();
+    assert!(html.contains("Capítulo uno"));
+}
+
+// An entry point with broken front matter is still watched.
+#[test]
+fn watches_an_entry_point_that_failed() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("project.thn"), r#"{ "path": ".", "assets": "./assets", "template": "./main.html", "entry": "start.md" }"#).unwrap();
+    fs::write(dir.path().join("main.html"), "#{contenido}#").unwrap();
+    fs::write(dir.path().join("start.md"), "---\ntitle: [sin cerrar\n---\n# Inicio\n").unwrap();
+
+    let project = thener::read_configuration(&dir.path().join("project.thn").to_string_lossy()).unwrap();
+    let mut session = BuildSession::new(&project, &[OutputFormat::Html]);
+    assert!(session.build().is_err());
+
+    assert_eq!(session.sources(), [dir.path().join("start.md")]);
+    assert_eq!(session.stages_affected_by(&dir.path().join("start.md")), vec![BuildStage::Preprocess, BuildStage::GenerateHtml]);
+}